@@ -30,8 +30,9 @@ pub(crate) enum Commands {
 #[derive(Args)]
 pub(crate) struct ViewArgs {
     /// Specify the path from where to read the diagnostic data.
-    /// The path must exist and it must point to a directory.
-    #[arg(short, long, value_parser(parse_path))]
+    /// The path must exist and it must point either to a directory
+    /// or to a `.tar.gz` archive, e.g. as downloaded by the `fetch` command.
+    #[arg(short, long, value_parser(parse_data_path))]
     pub(crate) path: PathBuf,
 
     /// Specify the path where the generated output will be created.
@@ -51,6 +52,12 @@ pub(crate) struct ViewArgs {
     /// Specify the end timestamp of the metrics.
     #[arg(short, long)]
     pub(crate) end: Option<DateTime<Utc>>,
+
+    /// Specify the directory where an index of the diagnostic data is kept,
+    /// which speeds up viewing the same diagnostic data again. The directory
+    /// is created if it does not exist.
+    #[arg(short, long)]
+    pub(crate) index_path: Option<PathBuf>,
 }
 
 #[derive(Args)]
@@ -128,6 +135,31 @@ fn parse_path(path: &str) -> Result<PathBuf, String> {
     Ok(path)
 }
 
+fn parse_data_path(path: &str) -> Result<PathBuf, String> {
+    const ARCHIVE_EXTENSIONS: [&str; 2] = [".tar.gz", ".tgz"];
+
+    let path = PathBuf::from(path);
+
+    if !path.exists() {
+        return Err(format!("The `{}` path does not exist.", path.display()));
+    }
+
+    let is_archive = path.is_file()
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| ARCHIVE_EXTENSIONS.iter().any(|ext| name.ends_with(ext)));
+
+    if !path.is_dir() && !is_archive {
+        return Err(format!(
+            "The `{}` path must point to a directory or to a .tar.gz archive.",
+            path.display()
+        ));
+    }
+
+    Ok(path)
+}
+
 pub(crate) trait PathExt {
     fn or_current_dir(self) -> Result<PathBuf, CliError>;
 }
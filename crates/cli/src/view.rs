@@ -1,5 +1,7 @@
 use mprobe_diagnostics::DiagnosticData;
 use mprobe_diagnostics::MetricsFilter;
+use mprobe_diagnostics::index::IndexLocation;
+use mprobe_vis::layout::MissingData;
 use mprobe_vis::layout::VisLayout;
 
 use crate::cli::PathExt;
@@ -16,12 +18,50 @@ pub(crate) fn view(args: ViewArgs) -> Result<(), CliError> {
         output_path.display()
     );
 
-    let filter = MetricsFilter::new(args.node, args.start, args.end);
-    let diagnostic_data = DiagnosticData::filter(&args.path, filter).expect("valid path");
+    let filter = MetricsFilter::new(args.node, args.start, args.end).with_trimming(true);
+    // The report is built by visiting the metrics as they are decoded, which
+    // parallel decoding would replace with decoding whole chunks ahead of time.
+    let mut diagnostic_data = DiagnosticData::filter(&args.path, filter)
+        .expect("valid path")
+        .with_recovery(true);
+
+    if let Some(index_path) = args.index_path {
+        diagnostic_data = diagnostic_data.with_index(IndexLocation::Dir(index_path));
+    }
 
     let vis = VisLayout::init(&output_path).expect("initializing data vis directory failed");
-    vis.generate_report(diagnostic_data)
+    let missing = vis
+        .generate_report(diagnostic_data)
         .expect("generating vis report failed");
+    print_missing(&missing);
 
     Ok(())
 }
+
+fn print_missing(missing: &MissingData) {
+    for error in &missing.errors {
+        eprintln!("An error occurred while reading metrics: {error}");
+    }
+
+    for file in &missing.skipped_files {
+        eprintln!("Skipped the {:?} file: {}", file.path, file.reason);
+    }
+
+    for region in &missing.skipped_regions {
+        let start = region
+            .start
+            .map_or(String::from("the start of the file"), |ts| ts.to_string());
+        let end = region
+            .end
+            .map_or(String::from("the end of the file"), |ts| ts.to_string());
+
+        eprintln!(
+            "Skipped the corrupted bytes {:?} of the {:?} file: the data captured between {start} and {end} is missing",
+            region.bytes, region.path
+        );
+    }
+
+    for error in &missing.index_errors {
+        eprintln!("An error occurred while saving the index: {error}");
+    }
+}
@@ -1,21 +1,24 @@
 use bson::Document;
+use bson::RawDocument;
 use chrono::DateTime;
 use chrono::Utc;
 
+use crate::error::KeyAccessError;
 use crate::error::MetricParseError;
 use crate::error::ValueAccessResultExt;
 
-const ID_KEY: &str = "_id";
-const DATA_TYPE_KEY: &str = "type";
+pub(crate) const ID_KEY: &str = "_id";
+pub(crate) const DATA_TYPE_KEY: &str = "type";
 const METADATA_KEY: &str = "doc";
 const METRICS_CHUNK_KEY: &str = "data";
+const DELTA_COUNTER_KEY: &str = "counter";
 
 const COMMON_KEY: &str = "common";
 const HOST_INFO_KEY: &str = "hostInfo";
 const SYSTEM_KEY: &str = "system";
 const HOSTNAME_KEY: &str = "hostname";
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(i32)]
 pub(crate) enum DocumentKind {
     Metadata = 0,
@@ -40,10 +43,15 @@ pub(crate) trait ReadDocument {
     fn kind(&self) -> Result<DocumentKind, MetricParseError>;
     fn timestamp(&self) -> Result<DateTime<Utc>, MetricParseError>;
     fn hostname(&self) -> Result<&str, MetricParseError>;
-    fn metrics_chunk(&self) -> Result<&Vec<u8>, MetricParseError>;
+    fn metrics_chunk(&self) -> Result<&[u8], MetricParseError>;
+    fn metadata(&self) -> Result<Document, MetricParseError>;
+    fn delta_counter(&self) -> Result<i64, MetricParseError>;
 }
 
-impl ReadDocument for Document {
+/// The documents are read without being deserialized, so that only
+/// the fields that are accessed are decoded and the metrics chunks,
+/// which make up most of the data, are not copied.
+impl ReadDocument for RawDocument {
     fn kind(&self) -> Result<DocumentKind, MetricParseError> {
         self.get_i32(DATA_TYPE_KEY)
             .map_value_access_err(DATA_TYPE_KEY)
@@ -86,11 +94,35 @@ impl ReadDocument for Document {
         Ok(hostname)
     }
 
-    fn metrics_chunk(&self) -> Result<&Vec<u8>, MetricParseError> {
+    fn metrics_chunk(&self) -> Result<&[u8], MetricParseError> {
         let data = self
-            .get_binary_generic(METRICS_CHUNK_KEY)
+            .get_binary(METRICS_CHUNK_KEY)
             .map_value_access_err(METRICS_CHUNK_KEY)?;
 
-        Ok(data)
+        Ok(data.bytes)
+    }
+
+    fn metadata(&self) -> Result<Document, MetricParseError> {
+        let metadata = self
+            .get_document(METADATA_KEY)
+            .map_value_access_err(METADATA_KEY)?;
+
+        Ok(Document::try_from(metadata)?)
+    }
+
+    fn delta_counter(&self) -> Result<i64, MetricParseError> {
+        // The counter is missing from the documents
+        // that contain the full periodic metadata.
+        match self.get(DELTA_COUNTER_KEY)? {
+            Some(counter) => counter
+                .as_i64()
+                .or_else(|| counter.as_i32().map(i64::from))
+                .ok_or_else(|| {
+                    MetricParseError::from(KeyAccessError::UnexpectedKeyType {
+                        key: DELTA_COUNTER_KEY.to_owned(),
+                    })
+                }),
+            None => Ok(0),
+        }
     }
 }
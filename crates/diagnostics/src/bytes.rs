@@ -1,4 +1,4 @@
-use std::io::{Error, ErrorKind, Read, Result};
+use std::io::{Error, ErrorKind, Read, Result, Write};
 
 /// Read a u8 value from a reader.
 pub(crate) fn read_u8<R: Read + ?Sized>(reader: &mut R) -> Result<u8> {
@@ -41,6 +41,25 @@ pub(crate) fn read_var_u64<R: Read + ?Sized>(reader: &mut R) -> Result<u64> {
     }
 }
 
+/// Write a u32 value to a writer in little endian.
+pub(crate) fn write_le_u32<W: Write + ?Sized>(writer: &mut W, value: u32) -> Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+/// Write a variable-byte encoded u64.
+pub(crate) fn write_var_u64<W: Write + ?Sized>(writer: &mut W, mut value: u64) -> Result<()> {
+    loop {
+        let byte = (value & 127) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+
+        writer.write_all(&[byte | 128])?;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -76,4 +95,16 @@ mod tests {
 
         read_var_u64(&mut cursor).unwrap();
     }
+
+    #[test]
+    fn write_var_u64_round_trips() {
+        for expected_value in [0, 127, 128, 255, 300, u32::MAX as u64, u64::MAX] {
+            let mut bytes = Vec::new();
+            write_var_u64(&mut bytes, expected_value).unwrap();
+
+            let actual_value = read_var_u64(&mut Cursor::new(bytes)).unwrap();
+
+            assert_eq!(actual_value, expected_value);
+        }
+    }
 }
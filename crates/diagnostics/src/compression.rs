@@ -1,17 +1,57 @@
-use std::io::{Error, ErrorKind, Read, Result};
+use std::io::{Error, ErrorKind, Read, Result, Write};
 
+use flate2::Compression;
 use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
 
 use crate::bytes;
+use crate::error::Limit;
+use crate::error::MetricParseError;
 
-pub(crate) fn decompress<R: Read + ?Sized>(reader: &mut R) -> Result<Vec<u8>> {
-    let buffer_size: usize = bytes::read_le_u32(reader)?
-        .try_into()
-        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
-    let mut decoder = ZlibDecoder::new(reader);
+/// Decompresses the data read from the `reader`, which is prefixed by
+/// its decompressed size. Neither the declared size nor the size of the
+/// decompressed data may exceed `max_size`.
+pub(crate) fn decompress<R: Read + ?Sized>(
+    reader: &mut R,
+    max_size: usize,
+) -> std::result::Result<Vec<u8>, MetricParseError> {
+    let limit = Limit::ChunkSize;
+    let buffer_size: usize = bytes::read_le_u32(reader)?.try_into()?;
+    if buffer_size > max_size {
+        return Err(MetricParseError::LimitExceeded {
+            limit,
+            max: max_size,
+            value: buffer_size,
+        });
+    }
+
+    // Reading one byte over the limit tells whether the data exceeds it.
+    let mut decoder = ZlibDecoder::new(reader).take(max_size as u64 + 1);
     let mut buffer = Vec::with_capacity(buffer_size);
 
     decoder.read_to_end(&mut buffer)?;
 
+    if buffer.len() > max_size {
+        return Err(MetricParseError::LimitExceeded {
+            limit,
+            max: max_size,
+            value: buffer.len(),
+        });
+    }
+
     Ok(buffer)
 }
+
+pub(crate) fn compress<W: Write + ?Sized>(writer: &mut W, data: &[u8]) -> Result<()> {
+    let buffer_size: u32 = data
+        .len()
+        .try_into()
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    bytes::write_le_u32(writer, buffer_size)?;
+
+    let mut encoder = ZlibEncoder::new(writer, Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()?;
+
+    Ok(())
+}
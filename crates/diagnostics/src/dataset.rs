@@ -0,0 +1,400 @@
+//! Defines a dataset of the diagnostic data captured from several processes,
+//! e.g. the nodes of a cluster, each with its diagnostic data files
+//! in a directory of its own.
+//!
+//! Unlike the [diagnostic data], which reads all the files into a single stream,
+//! the [`Dataset`] groups the files by the [`Instance`] that generated them,
+//! so that the metrics of each instance can be read on their own, or merged
+//! in timestamp order across the instances.
+//!
+//! [diagnostic data]: crate::DiagnosticData
+
+use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::DiagnosticData;
+use crate::Limits;
+use crate::MetricsFilter;
+use crate::error::MetricParseError;
+use crate::index::IndexLocation;
+use crate::metrics::MetricsChunk;
+use crate::read;
+use crate::read::MetricsIterator;
+use crate::read::ReadOptions;
+use crate::source::SharedSkipLog;
+use crate::source::SkippedFile;
+use crate::source::Source;
+use crate::source::SourceKind;
+use crate::source::sealed::HasSkipLogs;
+use crate::source::sealed::SkipLogs;
+
+/// `Instance` is a process whose diagnostic data was found in a [`Dataset`],
+/// i.e. the diagnostic data files found in the same directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instance {
+    /// Specifies the directory that holds the diagnostic data files,
+    /// relative to the archive root if they were found in an archive.
+    pub dir: PathBuf,
+
+    /// Specifies the diagnostic data files, sorted by their path.
+    pub files: Vec<PathBuf>,
+}
+
+/// `Dataset` groups the diagnostic data found under a root directory
+/// or in an archive by the [`Instance`] that generated it.
+///
+/// The instances are found when the `Dataset` is created, by identifying
+/// the diagnostic data files, while their metrics are read only once
+/// the [streams](Dataset::streams) of the instances are iterated over.
+#[derive(Debug)]
+pub struct Dataset {
+    archive: Option<PathBuf>,
+    instances: Vec<Arc<Instance>>,
+    skipped_files: Vec<SkippedFile>,
+    filter: MetricsFilter,
+    options: ReadOptions,
+}
+
+impl Dataset {
+    /// Creates a new `Dataset` out of the diagnostic data at the specified `path`.
+    ///
+    /// The `path` must be valid and point either to a directory or to a `.tar.gz`
+    /// or `.tgz` archive, in which each directory holding diagnostic data files
+    /// is an instance, e.g. `node-1/diagnostic.data`. An archive is read through
+    /// to find the instances, and once more for each instance read.
+    pub fn new(path: &Path) -> Result<Self, MetricParseError> {
+        let source = Source::path(path)?;
+        let archive = matches!(source.kind, SourceKind::Archive(..)).then(|| path.to_path_buf());
+
+        let options = ReadOptions::default();
+        let skip_log = SharedSkipLog::default();
+        let max_document_size = options.limits.max_document_size;
+        let instances = read::find_files(source, skip_log.clone(), max_document_size)?
+            .into_iter()
+            .map(|(dir, mut files)| {
+                files.sort();
+                Arc::new(Instance { dir, files })
+            })
+            .collect();
+        let skipped_files = std::mem::take(&mut skip_log.lock().files);
+
+        Ok(Self {
+            archive,
+            instances,
+            skipped_files,
+            filter: MetricsFilter::default(),
+            options,
+        })
+    }
+
+    /// Filters the diagnostic data of each instance according to
+    /// the `filter` specification.
+    pub fn with_filter(mut self, filter: MetricsFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Sets whether the diagnostic data is read in recovery mode,
+    /// as described for [DiagnosticData::with_recovery].
+    pub fn with_recovery(mut self, recover: bool) -> Self {
+        self.options.recover = recover;
+        self
+    }
+
+    /// Sets the [`Limits`] that bound the memory allocated while decoding
+    /// the diagnostic data of each instance.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Decodes the metrics chunks of each instance on a pool of `threads`,
+    /// as described for [DiagnosticData::with_parallel_decoding].
+    ///
+    /// Each stream has a pool of its own, hence merging the streams of
+    /// the instances runs as many pools at once.
+    pub fn with_parallel_decoding(mut self, threads: usize, max_in_flight: usize) -> Self {
+        self.options.decode_threads = threads;
+        self.options.max_chunks_in_flight = max_in_flight;
+        self
+    }
+
+    /// Indexes the diagnostic data files of each instance read from a directory,
+    /// as described for [DiagnosticData::with_index].
+    pub fn with_index(mut self, location: IndexLocation) -> Self {
+        self.options.index = Some(location);
+        self
+    }
+
+    /// Returns the instances found in the dataset, sorted by their directory.
+    pub fn instances(&self) -> &[Arc<Instance>] {
+        &self.instances
+    }
+
+    /// Returns the files found in the dataset that do not hold diagnostic data.
+    pub fn skipped_files(&self) -> &[SkippedFile] {
+        &self.skipped_files
+    }
+
+    /// Returns the [`DiagnosticData`] of the `instance`, filtered and read
+    /// according to the settings of this `Dataset`.
+    pub fn diagnostic_data(&self, instance: &Instance) -> Result<DiagnosticData, io::Error> {
+        let kind = match &self.archive {
+            Some(path) => {
+                SourceKind::ArchiveDir(path.clone(), File::open(path)?, instance.dir.clone())
+            }
+            None => SourceKind::Files(instance.files.clone()),
+        };
+
+        Ok(DiagnosticData {
+            source: Source::from(kind),
+            filter: self.filter.clone(),
+            options: self.options.clone(),
+        })
+    }
+
+    /// Returns an iterator over the metrics chunks of each instance,
+    /// which yields them in timestamp order for the instance.
+    pub fn streams(&self) -> Result<Vec<(Arc<Instance>, MetricsIterator)>, io::Error> {
+        self.instances
+            .iter()
+            .map(|instance| {
+                let chunks = self.diagnostic_data(instance)?.into_iter();
+                Ok((Arc::clone(instance), chunks))
+            })
+            .collect()
+    }
+
+    /// Returns an iterator that merges the [streams](Dataset::streams)
+    /// of the instances, yielding the metrics chunks of all the instances
+    /// in the order of their start timestamp.
+    pub fn merged(&self) -> Result<MergedIterator, io::Error> {
+        Ok(MergedIterator::new(self.streams()?))
+    }
+}
+
+/// An iterator that merges the metrics chunks of several instances
+/// in the order of their start timestamp. The chunks that start at the same
+/// time are yielded in the order of their instances.
+///
+/// It assumes the chunks of each instance are yielded sorted in ascending order,
+/// hence only the next chunk of each instance is held at once.
+///
+/// The errors are yielded along with the instance whose diagnostic data
+/// could not be read, which is read on afterwards as the other instances.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub struct MergedIterator {
+    streams: Vec<(Arc<Instance>, MetricsIterator)>,
+    heads: BinaryHeap<Reverse<Head>>,
+    pending: Vec<usize>,
+}
+
+/// The next metrics chunk of a stream.
+struct Head {
+    start: DateTime<Utc>,
+    stream: usize,
+    chunk: MetricsChunk,
+}
+
+impl MergedIterator {
+    fn new(streams: Vec<(Arc<Instance>, MetricsIterator)>) -> Self {
+        let pending = (0..streams.len()).rev().collect();
+
+        Self {
+            heads: BinaryHeap::with_capacity(streams.len()),
+            streams,
+            pending,
+        }
+    }
+}
+
+impl HasSkipLogs for MergedIterator {
+    fn skip_logs(&self) -> SkipLogs<'_> {
+        let skip_logs = self
+            .streams
+            .iter()
+            .flat_map(|(_, chunks)| chunks.skip_logs().0);
+        SkipLogs(skip_logs.collect())
+    }
+}
+
+impl Iterator for MergedIterator {
+    type Item = Result<(Arc<Instance>, MetricsChunk), (Arc<Instance>, MetricParseError)>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        // The streams whose chunk was yielded last, or that failed, are read
+        // ahead before picking the chunk that starts first.
+        while let Some(stream) = self.pending.pop() {
+            match self.streams[stream].1.next() {
+                Some(Ok(chunk)) => self.heads.push(Reverse(Head {
+                    start: chunk.start,
+                    stream,
+                    chunk,
+                })),
+                Some(Err(error)) => {
+                    self.pending.push(stream);
+                    let instance = Arc::clone(&self.streams[stream].0);
+                    return Some(Err((instance, error)));
+                }
+                None => {}
+            }
+        }
+
+        let Reverse(head) = self.heads.pop()?;
+        self.pending.push(head.stream);
+
+        let instance = Arc::clone(&self.streams[head.stream].0);
+        Some(Ok((instance, head.chunk)))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.start, self.stream).cmp(&(other.start, other.stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    use crate::testing;
+    use crate::testing::TempDir;
+    use crate::testing::seconds;
+    use crate::write::FtdcWriter;
+
+    use super::*;
+
+    fn write_node(dir: &Path, host: &str, start: i64) {
+        fs::create_dir_all(dir).unwrap();
+
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(5);
+        let metadata = testing::metadata(host);
+        writer.write_metadata(seconds(start), &metadata).unwrap();
+
+        for sample in 0..12 {
+            let ts = seconds(start + sample * 3);
+            let sample = testing::sample(ts, host, "8.0.4", sample);
+            writer.write_sample(ts, &sample).unwrap();
+        }
+
+        let name = "metrics.2024-11-05T10-00-00Z-00000";
+        fs::write(dir.join(name), writer.finish().unwrap()).unwrap();
+    }
+
+    fn assert_dataset(dataset: &Dataset, node_1: &Path, node_2: &Path) {
+        let dirs = dataset
+            .instances()
+            .iter()
+            .map(|instance| instance.dir.as_path())
+            .collect::<Vec<_>>();
+        assert_eq!(dirs, [node_1, node_2]);
+        assert_eq!(dataset.skipped_files().len(), 1);
+
+        for (instance, chunks) in dataset.streams().unwrap() {
+            let chunks = chunks.collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(chunks.len(), 3);
+            assert!(
+                chunks
+                    .iter()
+                    .all(|chunk| instance.dir.ends_with(&chunk.metadata.host))
+            );
+        }
+
+        let merged = dataset
+            .merged()
+            .unwrap()
+            .map(|chunk| chunk.map(|(instance, chunk)| (instance.dir.clone(), chunk.start)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = [
+            (node_1, 0),
+            (node_2, 1),
+            (node_1, 15),
+            (node_2, 16),
+            (node_1, 30),
+            (node_2, 31),
+        ]
+        .map(|(dir, start)| (dir.to_path_buf(), seconds(start)));
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn dataset_merges_instances_in_timestamp_order() {
+        let root = TempDir::new("dataset");
+        let data = root.join("data");
+        write_node(&data.join("node-1"), "node-1", 0);
+        write_node(&data.join("node-2"), "node-2", 1);
+        fs::write(data.join("README"), "not diagnostic data").unwrap();
+
+        let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        archive.append_dir_all("data", &data).unwrap();
+        let archive_path = root.join("data.tar.gz");
+        fs::write(
+            &archive_path,
+            archive.into_inner().unwrap().finish().unwrap(),
+        )
+        .unwrap();
+
+        let dataset = Dataset::new(&data).unwrap();
+        assert_dataset(&dataset, &data.join("node-1"), &data.join("node-2"));
+
+        let dataset = Dataset::new(&archive_path).unwrap();
+        let archive_root = Path::new("data");
+        assert_dataset(
+            &dataset,
+            &archive_root.join("node-1"),
+            &archive_root.join("node-2"),
+        );
+    }
+
+    #[test]
+    fn merged_yields_errors_with_their_instance() {
+        let root = TempDir::new("merged");
+        write_node(&root.join("node-1"), "node-1", 0);
+        write_node(&root.join("node-2"), "node-2", 1);
+
+        let dataset = Dataset::new(&root).unwrap();
+        fs::remove_dir_all(root.join("node-1")).unwrap();
+
+        let mut errors = Vec::new();
+        let mut chunks = Vec::new();
+        for chunk in dataset.merged().unwrap() {
+            match chunk {
+                Ok((instance, chunk)) => chunks.push((instance.dir.clone(), chunk.start)),
+                Err((instance, _)) => errors.push(instance.dir.clone()),
+            }
+        }
+
+        assert_eq!(errors, [root.join("node-1")]);
+        let node_2 = [1, 16, 31].map(|start| (root.join("node-2"), seconds(start)));
+        assert_eq!(chunks, node_2);
+    }
+}
@@ -7,11 +7,15 @@ use std::fmt::Display;
 use std::fmt::Formatter;
 use std::io;
 use std::num::TryFromIntError;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use bson::error::Error as BsonError;
 use bson::error::ErrorKind as BsonErrorKind;
 use bson::error::ValueAccessErrorKind;
+use chrono::DateTime;
+use chrono::Utc;
 
 /// The error type for parsing diagnostic metrics.
 ///
@@ -44,6 +48,75 @@ pub enum MetricParseError {
     /// A [TryFromIntError] encountered while converting integer values from [i32] to
     /// [usize].
     IntConversion(TryFromIntError),
+
+    /// A value read from the diagnostic data exceeds the [limit](crate::Limits)
+    /// set for it, e.g. because the data is corrupted.
+    LimitExceeded {
+        /// The limit that was exceeded.
+        limit: Limit,
+
+        /// The maximum value allowed by the limit.
+        max: usize,
+
+        /// The value read from the diagnostic data. When the value is not known
+        /// upfront, it is the amount read before the decoding was stopped.
+        value: usize,
+    },
+
+    /// A [MetricParseError] along with the [ErrorContext] in which it occurred,
+    /// e.g. the file and the document that could not be parsed.
+    WithContext {
+        /// The error that occurred.
+        error: Box<MetricParseError>,
+
+        /// The context in which the error occurred.
+        context: ErrorContext,
+    },
+}
+
+impl MetricParseError {
+    /// Returns the context in which the error occurred, if it is known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            MetricParseError::WithContext { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Returns the error that occurred, without its context.
+    pub fn without_context(&self) -> &MetricParseError {
+        match self {
+            MetricParseError::WithContext { error, .. } => error,
+            error => error,
+        }
+    }
+
+    /// Attaches the `context` to the error. The context known so far
+    /// is kept and only its missing parts are filled in.
+    pub(crate) fn with_context(self, context: ErrorContext) -> MetricParseError {
+        match self {
+            MetricParseError::WithContext {
+                error,
+                context: known,
+            } => MetricParseError::WithContext {
+                error,
+                context: known.or(context),
+            },
+            error => {
+                let context = match &error {
+                    MetricParseError::MetricTimestampNotFound { name } => {
+                        context.with_metric(Arc::clone(name))
+                    }
+                    _ => context,
+                };
+
+                MetricParseError::WithContext {
+                    error: Box::new(error),
+                    context,
+                }
+            }
+        }
+    }
 }
 
 impl Display for MetricParseError {
@@ -73,6 +146,11 @@ impl Display for MetricParseError {
             MetricParseError::UnknownDocumentKind(value) => {
                 write!(f, "{parse_error} unknonw document type: {value}")
             }
+            MetricParseError::LimitExceeded { limit, max, value } => write!(
+                f,
+                "{parse_error} the {limit} of {value} exceeds the limit of {max}"
+            ),
+            MetricParseError::WithContext { error, context } => write!(f, "{error} ({context})"),
         }
     }
 }
@@ -87,6 +165,8 @@ impl Error for MetricParseError {
             MetricParseError::MetricTimestampNotFound { .. } => None,
             MetricParseError::IntConversion(error) => Some(error),
             MetricParseError::UnknownDocumentKind(_) => None,
+            MetricParseError::LimitExceeded { .. } => None,
+            MetricParseError::WithContext { error, .. } => error.source(),
         }
     }
 }
@@ -115,6 +195,245 @@ impl From<KeyAccessError> for MetricParseError {
     }
 }
 
+/// `Limit` specifies which of the [limits](crate::Limits) put on the decoded
+/// diagnostic data was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// The size of a BSON document.
+    DocumentSize,
+
+    /// The decompressed size of a metrics chunk.
+    ChunkSize,
+
+    /// The amount of metrics in a metrics chunk.
+    MetricsPerChunk,
+
+    /// The amount of samples in a metrics chunk.
+    SamplesPerChunk,
+
+    /// The size of the documents held to read a file in reverse order.
+    ReversedSize,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::DocumentSize => write!(f, "document size"),
+            Limit::ChunkSize => write!(f, "decompressed chunk size"),
+            Limit::MetricsPerChunk => write!(f, "amount of metrics per chunk"),
+            Limit::SamplesPerChunk => write!(f, "amount of samples per chunk"),
+            Limit::ReversedSize => write!(f, "size of the documents read in reverse"),
+        }
+    }
+}
+
+/// `ErrorContext` specifies where in the diagnostic data a [MetricParseError] occurred.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    path: Option<PathBuf>,
+    offset: Option<u64>,
+    timestamp: Option<DateTime<Utc>>,
+    metric: Option<Arc<str>>,
+}
+
+impl ErrorContext {
+    pub(crate) fn new(path: &Path) -> ErrorContext {
+        let path = (!path.as_os_str().is_empty()).then(|| path.to_path_buf());
+
+        Self {
+            path,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn with_offset(mut self, offset: u64) -> ErrorContext {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub(crate) fn with_timestamp(mut self, timestamp: Option<DateTime<Utc>>) -> ErrorContext {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub(crate) fn with_metric(mut self, metric: Arc<str>) -> ErrorContext {
+        self.metric = Some(metric);
+        self
+    }
+
+    /// Fills in the parts of this context that are missing from the `other` context.
+    fn or(self, other: ErrorContext) -> ErrorContext {
+        Self {
+            path: self.path.or(other.path),
+            offset: self.offset.or(other.offset),
+            timestamp: self.timestamp.or(other.timestamp),
+            metric: self.metric.or(other.metric),
+        }
+    }
+
+    /// Returns the path of the file in which the error occurred.
+    /// The path is not known for the diagnostic data read from a buffer or a reader.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the byte offset in the file of the document in which the error occurred.
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// Returns the `_id` timestamp of the document in which the error occurred.
+    /// For a metrics chunk, it is the timestamp of its first sample.
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.timestamp
+    }
+
+    /// Returns the path of the metric for which the error occurred.
+    pub fn metric(&self) -> Option<&str> {
+        self.metric.as_deref()
+    }
+}
+
+impl Display for ErrorContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+
+        if let Some(path) = &self.path {
+            parts.push(format!("file: {}", path.display()));
+        }
+        if let Some(offset) = self.offset {
+            parts.push(format!("offset: {offset}"));
+        }
+        if let Some(timestamp) = self.timestamp {
+            parts.push(format!("document: {timestamp}"));
+        }
+        if let Some(metric) = &self.metric {
+            parts.push(format!("metric: {metric}"));
+        }
+
+        if parts.is_empty() {
+            return write!(f, "unknown location");
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// The error type for writing diagnostic metrics.
+#[derive(Debug, Clone)]
+pub enum MetricWriteError {
+    /// A [std::io::Error] encountered while writing diagnostic metrics.
+    Io(Arc<io::Error>),
+
+    /// A [bson::error::Error] encountered while serializing BSON documents.
+    BsonSerialization(BsonError),
+
+    /// The metrics of a chunk do not have the same amount of measurements,
+    /// hence they cannot be written as samples.
+    MeasurementCountMismatch {
+        /// Metric name
+        name: Arc<str>,
+    },
+}
+
+impl Display for MetricWriteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let write_error = "metric write error:";
+
+        match self {
+            MetricWriteError::Io(error) => write!(f, "{write_error} I/O error: {error}"),
+            MetricWriteError::BsonSerialization(error) => {
+                write!(f, "{write_error} BSON serialization error: {error}")
+            }
+            MetricWriteError::MeasurementCountMismatch { name } => write!(
+                f,
+                "{write_error} the {name} metric does not have the same amount of measurements as the other metrics in the chunk",
+            ),
+        }
+    }
+}
+
+impl Error for MetricWriteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MetricWriteError::Io(error) => Some(error),
+            MetricWriteError::BsonSerialization(error) => Some(error),
+            MetricWriteError::MeasurementCountMismatch { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for MetricWriteError {
+    fn from(error: io::Error) -> Self {
+        MetricWriteError::Io(Arc::new(error))
+    }
+}
+
+impl From<BsonError> for MetricWriteError {
+    fn from(error: BsonError) -> Self {
+        MetricWriteError::BsonSerialization(error)
+    }
+}
+
+/// The error type for saving the [index](crate::index) of the diagnostic data
+/// files of a directory. The files are still read, yet they are read in full,
+/// and indexed anew, the next time.
+#[derive(Debug, Clone)]
+pub struct IndexSaveError {
+    /// Path of the index that could not be saved.
+    pub path: PathBuf,
+
+    /// The [std::io::Error] encountered while saving the index.
+    pub error: Arc<io::Error>,
+}
+
+impl Display for IndexSaveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "index save error: cannot save the {:?} index: {}",
+            self.path, self.error
+        )
+    }
+}
+
+impl Error for IndexSaveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// The error type for parsing [metric paths](crate::metrics::MetricPath).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricPathParseError {
+    /// The path ends with an escape character, which escapes nothing.
+    TrailingEscape,
+
+    /// An escape character is followed by a character that cannot be escaped.
+    InvalidEscape {
+        /// The character that follows the escape character.
+        character: char,
+    },
+}
+
+impl Display for MetricPathParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let parse_error = "metric path parse error:";
+
+        match self {
+            MetricPathParseError::TrailingEscape => {
+                write!(f, "{parse_error} the path ends with an escape character")
+            }
+            MetricPathParseError::InvalidEscape { character } => write!(
+                f,
+                "{parse_error} the {character:?} character cannot be escaped, only '.' and '\\' can"
+            ),
+        }
+    }
+}
+
+impl Error for MetricPathParseError {}
+
 /// The error type for accessing BSON fields.
 #[derive(Debug, Clone)]
 pub enum KeyAccessError {
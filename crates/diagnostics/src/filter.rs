@@ -1,13 +1,20 @@
-use std::rc::Rc;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::iter;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use bson::Document;
 use chrono::DateTime;
-use chrono::Duration;
 use chrono::Utc;
 
 use crate::bson::DocumentKind;
 use crate::bson::ReadDocument;
 use crate::error::MetricParseError;
+use crate::read::FileDocument;
 
 #[derive(Debug, Default)]
 pub(crate) struct TimeWindow {
@@ -20,15 +27,14 @@ impl TimeWindow {
         Self { start, end }
     }
 
-    pub(crate) fn includes_with_margin(&self, timestamp: &DateTime<Utc>, margin: Duration) -> bool {
-        match (self.start, self.end) {
-            (None, None) => true,
-            (Some(start), None) => timestamp.ge(&(start - margin)),
-            (None, Some(end)) => timestamp.le(&(end + margin)),
-            (Some(start), Some(end)) => {
-                timestamp.ge(&(start - margin)) && timestamp.le(&(end + margin))
-            }
-        }
+    /// Returns whether the `timestamp` falls before the start of the time window.
+    pub(crate) fn is_before(&self, timestamp: &DateTime<Utc>) -> bool {
+        self.start.is_some_and(|start| *timestamp < start)
+    }
+
+    /// Returns whether the `timestamp` falls after the end of the time window.
+    pub(crate) fn is_after(&self, timestamp: &DateTime<Utc>) -> bool {
+        self.end.is_some_and(|end| *timestamp > end)
     }
 
     pub(crate) fn overlaps(&self, start: &DateTime<Utc>, end: &DateTime<Utc>) -> bool {
@@ -41,54 +47,177 @@ impl TimeWindow {
     }
 }
 
+impl RangeBounds<DateTime<Utc>> for TimeWindow {
+    fn start_bound(&self) -> Bound<&DateTime<Utc>> {
+        self.start
+            .as_ref()
+            .map_or(Bound::Unbounded, Bound::Included)
+    }
+
+    fn end_bound(&self) -> Bound<&DateTime<Utc>> {
+        self.end.as_ref().map_or(Bound::Unbounded, Bound::Included)
+    }
+}
+
+/// Filters the metrics chunks by their time range.
+///
+/// A metrics chunk holds the samples from its `_id` timestamp up until
+/// the `_id` timestamp of the next chunk in the same file. Hence the chunks
+/// that start after the time window are dropped right away, while the last
+/// chunk that starts before the time window is held back until the next
+/// document of its file shows whether it reaches into the time window.
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 pub(crate) struct TimeWindowFilter<I> {
     iter: I,
-    time_window: Rc<TimeWindow>,
-    time_margin: Duration,
+    time_window: Arc<TimeWindow>,
+    pending: Option<FileDocument>,
+    ready: VecDeque<FileDocument>,
 }
 
 impl<I> TimeWindowFilter<I>
 where
-    I: Iterator<Item = Result<Document, MetricParseError>>,
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
 {
-    pub fn new(iter: I, time_window: Rc<TimeWindow>) -> Self {
+    pub fn new(iter: I, time_window: Arc<TimeWindow>) -> Self {
         Self {
             iter,
             time_window,
-            time_margin: Duration::hours(2),
+            pending: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, doc: FileDocument) -> Result<(), MetricParseError> {
+        // The last chunk of a file is released once the file is read,
+        // since the next file may start after the time window does.
+        if let Some(pending) = self
+            .pending
+            .take_if(|pending| !Arc::ptr_eq(&pending.source, &doc.source))
+        {
+            self.ready.push_back(pending);
         }
+
+        // The other documents are always yielded, since the metadata documents
+        // describe the metrics chunks that follow them.
+        if doc.kind()? != DocumentKind::MetricsChunk {
+            self.ready.push_back(doc);
+            return Ok(());
+        }
+
+        let timestamp = doc.timestamp()?;
+        if self.time_window.is_after(&timestamp) {
+            return Ok(());
+        }
+
+        // The pending chunk, if any, ends before this one starts.
+        if self.time_window.is_before(&timestamp) {
+            self.pending = Some(doc);
+            return Ok(());
+        }
+
+        self.ready.extend(self.pending.take());
+        self.ready.push_back(doc);
+
+        Ok(())
     }
 }
 
 impl<I> Iterator for TimeWindowFilter<I>
 where
-    I: Iterator<Item = Result<Document, MetricParseError>>,
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
 {
-    type Item = Result<Document, MetricParseError>;
+    type Item = Result<FileDocument, MetricParseError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            match self.iter.next()? {
-                Ok(doc) => match doc.timestamp() {
-                    Ok(ts) => {
-                        if self.time_window.includes_with_margin(&ts, self.time_margin) {
-                            return Some(Ok(doc));
-                        }
+            if let Some(doc) = self.ready.pop_front() {
+                return Some(Ok(doc));
+            }
+
+            match self.iter.next() {
+                Some(Ok(doc)) => {
+                    if let Err(err) = self.push(doc) {
+                        return Some(Err(err));
                     }
+                }
+                Some(Err(err)) => return Some(Err(err)),
+                None => return self.pending.take().map(Ok),
+            }
+        }
+    }
+}
+
+/// Filters the metrics chunks by their time range when they are read
+/// from the newest to the oldest.
+///
+/// The chunks that start after the time window are dropped, while only
+/// the newest chunk of each directory that starts before the time window
+/// may reach into it, since the chunks that follow end before it starts.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub(crate) struct ReverseTimeWindowFilter<I> {
+    iter: I,
+    time_window: Arc<TimeWindow>,
+    reached: HashSet<PathBuf>,
+}
+
+impl<I> ReverseTimeWindowFilter<I> {
+    pub fn new(iter: I, time_window: Arc<TimeWindow>) -> Self {
+        Self {
+            iter,
+            time_window,
+            reached: HashSet::new(),
+        }
+    }
+
+    fn is_within(&mut self, doc: &FileDocument) -> Result<bool, MetricParseError> {
+        if doc.kind()? != DocumentKind::MetricsChunk {
+            return Ok(true);
+        }
+
+        let timestamp = doc.timestamp()?;
+        if self.time_window.is_after(&timestamp) {
+            return Ok(false);
+        }
+
+        if self.time_window.is_before(&timestamp) {
+            return Ok(self.reached.insert(doc.source.dir().to_path_buf()));
+        }
+
+        Ok(true)
+    }
+}
+
+impl<I> Iterator for ReverseTimeWindowFilter<I>
+where
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
+{
+    type Item = Result<FileDocument, MetricParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next()? {
+                Ok(doc) => match self.is_within(&doc) {
+                    Ok(true) => return Some(Ok(doc)),
+                    Ok(false) => continue,
                     Err(err) => return Some(Err(err)),
                 },
-                item => return Some(item),
+                Err(err) => return Some(Err(err)),
             }
         }
     }
 }
 
+/// Filters the documents by the hostname found in the metadata document.
+///
+/// Since every node writes its files into a separate directory, the result
+/// of the last metadata document is tracked per directory. That way the
+/// interim files, which do not contain metadata documents, are attributed
+/// to the right node.
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 pub(crate) struct HostnameFilter<I> {
     iter: I,
     hostname: Option<String>,
-    are_host_metrics: bool,
+    are_host_metrics: HashMap<PathBuf, bool>,
 }
 
 impl<I> HostnameFilter<I> {
@@ -96,16 +225,16 @@ impl<I> HostnameFilter<I> {
         Self {
             iter,
             hostname,
-            are_host_metrics: false,
+            are_host_metrics: HashMap::new(),
         }
     }
 }
 
 impl<I> Iterator for HostnameFilter<I>
 where
-    I: Iterator<Item = Result<Document, MetricParseError>>,
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
 {
-    type Item = Result<Document, MetricParseError>;
+    type Item = Result<FileDocument, MetricParseError>;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
@@ -114,17 +243,25 @@ where
                 Ok(doc) => match doc.kind() {
                     Ok(DocumentKind::Metadata) => match doc.hostname() {
                         Ok(hostname) => {
-                            self.are_host_metrics =
+                            let are_host_metrics =
                                 self.hostname.as_ref().is_none_or(|hn| hn == hostname);
+                            self.are_host_metrics
+                                .insert(doc.source.dir().to_path_buf(), are_host_metrics);
 
-                            if self.are_host_metrics {
+                            if are_host_metrics {
                                 return Some(Ok(doc));
                             }
                         }
                         Err(err) => return Some(Err(err)),
                     },
                     Ok(_) => {
-                        if self.are_host_metrics {
+                        let are_host_metrics = self
+                            .are_host_metrics
+                            .get(doc.source.dir())
+                            .copied()
+                            .unwrap_or(self.hostname.is_none());
+
+                        if are_host_metrics {
                             return Some(Ok(doc));
                         }
                     }
@@ -135,3 +272,261 @@ where
         }
     }
 }
+
+/// `MetricSelector` selects the metrics of the chunks by their path,
+/// i.e. their groups, using include and exclude glob patterns.
+///
+/// A metric is selected if it matches any of the include patterns,
+/// or there are none, and it does not match any of the exclude patterns.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct MetricSelector {
+    include: Vec<MetricPattern>,
+    exclude: Vec<MetricPattern>,
+}
+
+impl MetricSelector {
+    pub(crate) fn include(&mut self, pattern: &str) {
+        self.include.push(MetricPattern::new(pattern));
+    }
+
+    pub(crate) fn exclude(&mut self, pattern: &str) {
+        self.exclude.push(MetricPattern::new(pattern));
+    }
+
+    pub(crate) fn selects<S: AsRef<str>>(&self, groups: &[S]) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(groups)))
+            && !self.exclude.iter().any(|p| p.matches(groups))
+    }
+}
+
+/// A glob pattern on the path of a metric, made of `.` separated segments,
+/// each matched against a group of the metric.
+///
+/// Within a segment, `*` matches any sequence of characters,
+/// while a `**` segment matches any number of groups.
+#[derive(Debug, Clone)]
+struct MetricPattern {
+    segments: Vec<String>,
+}
+
+impl MetricPattern {
+    const SEGMENT_DELIMITER: char = '.';
+    const ANY_GROUPS: &str = "**";
+    const ANY_CHARS: u8 = b'*';
+
+    fn new(pattern: &str) -> Self {
+        let segments = pattern
+            .split(Self::SEGMENT_DELIMITER)
+            .map(String::from)
+            .collect();
+
+        Self { segments }
+    }
+
+    fn matches<S: AsRef<str>>(&self, groups: &[S]) -> bool {
+        Self::matches_segments(&self.segments, groups)
+    }
+
+    fn matches_segments<S: AsRef<str>>(segments: &[String], groups: &[S]) -> bool {
+        match segments.split_first() {
+            None => groups.is_empty(),
+            Some((segment, rest)) if segment == Self::ANY_GROUPS => {
+                (0..=groups.len()).any(|skip| Self::matches_segments(rest, &groups[skip..]))
+            }
+            Some((segment, rest)) => match groups.split_first() {
+                Some((group, groups)) => {
+                    Self::matches_segment(segment, group.as_ref())
+                        && Self::matches_segments(rest, groups)
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// Matches the `group` against the `segment` glob, backtracking
+    /// to the last `*` whenever the characters do not match.
+    fn matches_segment(segment: &str, group: &str) -> bool {
+        let (segment, group) = (segment.as_bytes(), group.as_bytes());
+        let (mut sidx, mut gidx) = (0, 0);
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while gidx < group.len() {
+            match segment.get(sidx) {
+                Some(&Self::ANY_CHARS) => {
+                    backtrack = Some((sidx, gidx));
+                    sidx += 1;
+                }
+                Some(c) if *c == group[gidx] => {
+                    sidx += 1;
+                    gidx += 1;
+                }
+                _ => match backtrack {
+                    Some((star, matched)) => {
+                        backtrack = Some((star, matched + 1));
+                        sidx = star + 1;
+                        gidx = matched + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+
+        segment[sidx..].iter().all(|c| *c == Self::ANY_CHARS)
+    }
+}
+
+/// Matches the `host` of a process, i.e. `hostname:port`, against the `selected`
+/// host, which may leave out the port to match the processes on all the ports.
+pub(crate) fn matches_host(host: &str, selected: &str) -> bool {
+    if selected.contains(':') {
+        return host.eq_ignore_ascii_case(selected);
+    }
+
+    let hostname = host.rsplit_once(':').map_or(host, |(hostname, _)| hostname);
+    hostname.eq_ignore_ascii_case(selected)
+}
+
+/// Compares the `version` against the `bound` only up to the components of
+/// the `bound`, e.g. `7.0.12` is equal to `7.0` and greater than `7.0.2`.
+/// The suffixes of the components, such as `-rc1`, are ignored.
+pub(crate) fn compare_version(version: &str, bound: &str) -> Ordering {
+    let components = |version: &str| {
+        version
+            .split('.')
+            .map(|c| {
+                let digits = c.len() - c.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                c[..digits].parse::<u64>().unwrap_or(0)
+            })
+            .collect::<Vec<u64>>()
+    };
+
+    let bound = components(bound);
+    let version = components(version);
+
+    version
+        .into_iter()
+        .chain(iter::repeat(0))
+        .take(bound.len())
+        .cmp(bound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selects(include: &[&str], exclude: &[&str], path: &str) -> bool {
+        let mut selector = MetricSelector::default();
+        include.iter().for_each(|p| selector.include(p));
+        exclude.iter().for_each(|p| selector.exclude(p));
+
+        selector.selects(&path.split(' ').collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn metric_selector_matches_glob_patterns() {
+        let cases = [
+            (vec![], vec![], "serverStatus opcounters insert", true),
+            (
+                vec!["serverStatus.opcounters.*"],
+                vec![],
+                "serverStatus opcounters insert",
+                true,
+            ),
+            (
+                vec!["serverStatus.opcounters.*"],
+                vec![],
+                "serverStatus opcounters",
+                false,
+            ),
+            (
+                vec!["serverStatus.opcounters.*"],
+                vec![],
+                "serverStatus opcountersRepl insert",
+                false,
+            ),
+            (
+                vec!["serverStatus.op*.*"],
+                vec![],
+                "serverStatus opcountersRepl insert",
+                true,
+            ),
+            (
+                vec!["serverStatus.**"],
+                vec![],
+                "serverStatus wiredTiger cache bytes",
+                true,
+            ),
+            (
+                vec!["**.insert"],
+                vec![],
+                "serverStatus opcounters insert",
+                true,
+            ),
+            (
+                vec!["**.insert"],
+                vec![],
+                "serverStatus opcounters query",
+                false,
+            ),
+            (
+                vec!["*Status.*.*ert"],
+                vec![],
+                "serverStatus opcounters insert",
+                true,
+            ),
+            (
+                vec!["serverStatus.**"],
+                vec!["**.wiredTiger.**"],
+                "serverStatus wiredTiger cache",
+                false,
+            ),
+            (
+                vec![],
+                vec!["systemMetrics.**"],
+                "serverStatus uptime",
+                true,
+            ),
+            (
+                vec![],
+                vec!["systemMetrics.**"],
+                "systemMetrics cpu user_ms",
+                false,
+            ),
+        ];
+
+        for (include, exclude, path, expected) in cases {
+            assert_eq!(
+                selects(&include, &exclude, path),
+                expected,
+                "{include:?} / {exclude:?} on {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn matches_host_with_and_without_port() {
+        assert!(matches_host("node-1:27017", "node-1:27017"));
+        assert!(matches_host("node-1:27017", "NODE-1"));
+        assert!(!matches_host("node-1:27017", "node-1:27018"));
+        assert!(!matches_host("node-10:27017", "node-1"));
+    }
+
+    #[test]
+    fn compare_version_up_to_bound_components() {
+        let cases = [
+            ("7.0.12", "7.0", Ordering::Equal),
+            ("7.0.12", "7.0.2", Ordering::Greater),
+            ("6.0.19", "7", Ordering::Less),
+            ("8.0.0-rc1", "8.0.0", Ordering::Equal),
+            ("8", "8.0.1", Ordering::Less),
+        ];
+
+        for (version, bound, expected) in cases {
+            assert_eq!(
+                compare_version(version, bound),
+                expected,
+                "{version} / {bound}"
+            );
+        }
+    }
+}
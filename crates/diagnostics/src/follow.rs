@@ -0,0 +1,531 @@
+//! Defines how the diagnostic data is followed while mongod writes it.
+//!
+//! mongod appends the metrics chunks to the last rotated file as they fill up
+//! and starts a new rotated file every so often, while it rewrites
+//! the `metrics.interim` file with the samples of the chunk being filled
+//! every time it takes a sample. The [`FollowIterator`] polls the directory
+//! for these changes, reading only the documents appended to the rotated
+//! files since the last poll and the interim file whenever it is rewritten.
+//!
+//! The samples already yielded, e.g. from the interim file, are removed
+//! from the chunks read later, so that every sample is yielded only once.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use crate::MetricsFilter;
+use crate::bson::DocumentKind;
+use crate::bson::ReadDocument;
+use crate::error::ErrorContext;
+use crate::error::MetricParseError;
+use crate::filter::HostnameFilter;
+use crate::filter::TimeWindow;
+use crate::index;
+use crate::index::FileStamp;
+use crate::metrics::MetricsChunk;
+use crate::read;
+use crate::read::BsonReader;
+use crate::read::FileDocument;
+use crate::read::FileInfo;
+use crate::read::Identified;
+use crate::read::ReadOptions;
+use crate::read::TraverseDir;
+use crate::source::SharedSkipLog;
+use crate::source::SkipReason;
+use crate::source::Source;
+use crate::source::SourceKind;
+use crate::source::sealed::HasSkipLogs;
+use crate::source::sealed::SkipLogs;
+
+/// An iterator that follows the diagnostic data files of a directory tree
+/// while mongod writes them, and yields the [`MetricsChunk`]s as they are written.
+///
+/// The iterator blocks until the next metrics chunk is written. It ends once
+/// a metrics chunk starts after the end of the time window, if any, or once
+/// it is [stopped](StopHandle::stop).
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub struct FollowIterator {
+    chunks: Box<dyn Iterator<Item = Result<MetricsChunk, MetricParseError>> + Send>,
+    skip_log: SharedSkipLog,
+    stop: StopHandle,
+}
+
+impl FollowIterator {
+    pub(crate) fn new(
+        source: Source,
+        filter: MetricsFilter,
+        options: ReadOptions,
+        poll_interval: Duration,
+    ) -> Result<Self, io::Error> {
+        let root = match source.kind {
+            SourceKind::Dir(path, _) => path,
+            SourceKind::Files(paths) => match paths.first().and_then(|path| path.parent()) {
+                Some(dir) => dir.to_path_buf(),
+                None => return Err(not_followable()),
+            },
+            _ => return Err(not_followable()),
+        };
+
+        let time_window = Arc::new(TimeWindow::new(filter.start, filter.end));
+        let skip_log = SharedSkipLog::default();
+        let stop = StopHandle::default();
+
+        let reader = FollowReader {
+            root,
+            poll_interval,
+            time_window: time_window.clone(),
+            max_document_size: options.limits.max_document_size,
+            skip_log: skip_log.clone(),
+            stop: stop.clone(),
+            files: HashMap::new(),
+            ignored: HashSet::new(),
+            ready: VecDeque::new(),
+            polled: false,
+            finished: false,
+        };
+        let documents = HostnameFilter::new(reader, filter.hostname.clone());
+
+        // Reading ahead would hold back the chunks already written
+        // until the next ones are, hence they are decoded one at a time.
+        let options = ReadOptions {
+            decode_threads: 1,
+            ..options
+        };
+        let chunks = read::decode_chunks(documents, filter, time_window, &options, true);
+
+        Ok(Self {
+            chunks,
+            skip_log,
+            stop,
+        })
+    }
+
+    /// Returns a [`StopHandle`] that stops this iterator, e.g. from another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+}
+
+impl HasSkipLogs for FollowIterator {
+    fn skip_logs(&self) -> SkipLogs<'_> {
+        SkipLogs(vec![&self.skip_log])
+    }
+}
+
+impl Iterator for FollowIterator {
+    type Item = Result<MetricsChunk, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chunks.next()
+    }
+}
+
+/// `StopHandle` stops a [`FollowIterator`] from waiting for
+/// the diagnostic data to be written.
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    /// Stops the iterator, which ends once it is done with the documents
+    /// read so far, within one poll interval at most.
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the iterator has been stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+fn not_followable() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "only the diagnostic data in a directory can be followed",
+    )
+}
+
+/// An iterator that polls a directory tree for the documents written into
+/// the diagnostic data files since the last poll, blocking in between.
+///
+/// The documents of the rotated files are read from where the last poll
+/// left off, up to the last document written in full, while the interim file
+/// is read anew whenever it changes. The files are read in the order
+/// they were written, with the interim files last.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+struct FollowReader {
+    root: PathBuf,
+    poll_interval: Duration,
+    time_window: Arc<TimeWindow>,
+    max_document_size: usize,
+    skip_log: SharedSkipLog,
+    stop: StopHandle,
+    files: HashMap<PathBuf, FollowedFile>,
+    ignored: HashSet<PathBuf>,
+    ready: VecDeque<Result<FileDocument, MetricParseError>>,
+    polled: bool,
+    finished: bool,
+}
+
+/// A diagnostic data file followed by a [`FollowReader`].
+struct FollowedFile {
+    info: Arc<FileInfo>,
+
+    /// The offset of the first document of a rotated file that is yet to be read.
+    offset: u64,
+
+    /// The stamp of an interim file when it was last read in full.
+    stamp: Option<FileStamp>,
+}
+
+impl FollowReader {
+    fn poll(&mut self) {
+        let root_dir = match fs::read_dir(&self.root) {
+            Ok(root_dir) => root_dir,
+            Err(error) => {
+                let context = ErrorContext::new(&self.root);
+                self.ready
+                    .push_back(Err(MetricParseError::from(error).with_context(context)));
+                self.finished = true;
+                return;
+            }
+        };
+
+        for path in TraverseDir::new(root_dir) {
+            match path {
+                Ok(path) => self.identify(path),
+                Err(error) => self.ready.push_back(Err(error)),
+            }
+        }
+
+        let mut files = self.files.values_mut().collect::<Vec<_>>();
+        files.sort_by_key(|file| file.info.order());
+
+        // The files that fail to be read or decoded are reported once and
+        // no longer followed, as when they fail to be identified, while
+        // the ones removed by mongod are followed again if they reappear.
+        let mut done = Vec::new();
+        for file in files {
+            let path = file.info.path().to_path_buf();
+            match file.read(self.max_document_size, &mut self.ready) {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.ignored.insert(path.clone());
+                    done.push(path);
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => done.push(path),
+                Err(error) => {
+                    let context = ErrorContext::new(&path);
+                    self.ready
+                        .push_back(Err(MetricParseError::from(error).with_context(context)));
+                    self.ignored.insert(path.clone());
+                    done.push(path);
+                }
+            }
+        }
+
+        for path in done {
+            self.files.remove(&path);
+        }
+
+        // Nothing written from now on falls within the time window.
+        self.finished = self.ready.iter().any(|document| {
+            document.as_ref().is_ok_and(|document| {
+                document.kind().ok() == Some(DocumentKind::MetricsChunk)
+                    && document
+                        .timestamp()
+                        .is_ok_and(|ts| self.time_window.is_after(&ts))
+            })
+        });
+    }
+
+    /// Starts following the file at `path` if it holds diagnostic data.
+    fn identify(&mut self, path: PathBuf) {
+        if index::is_index_file(&path)
+            || self.files.contains_key(&path)
+            || self.ignored.contains(&path)
+        {
+            return;
+        }
+
+        let context = ErrorContext::new(&path);
+        let identified = File::open(&path).and_then(|file| {
+            let mut reader = BufReader::new(file);
+            FileInfo::identify(
+                path.clone(),
+                &mut reader,
+                &mut Vec::new(),
+                self.max_document_size,
+            )
+        });
+
+        match identified {
+            Ok(Identified::Diagnostic(info)) => {
+                let file = FollowedFile {
+                    info: Arc::new(info),
+                    offset: 0,
+                    stamp: None,
+                };
+                self.files.insert(path, file);
+            }
+            // mongod may have just created the file.
+            Ok(Identified::Skipped(file)) if file.reason == SkipReason::Empty => {}
+            Ok(Identified::Skipped(file)) => {
+                self.ignored.insert(path);
+                self.skip_log.lock().files.push(file);
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => {
+                self.ignored.insert(path);
+                self.ready
+                    .push_back(Err(MetricParseError::from(error).with_context(context)));
+            }
+        }
+    }
+}
+
+impl FollowedFile {
+    /// Reads the documents written in full into the file since it was last read.
+    /// Returns whether the file can still be followed, i.e. it did not fail.
+    fn read(
+        &mut self,
+        max_document_size: usize,
+        ready: &mut VecDeque<Result<FileDocument, MetricParseError>>,
+    ) -> Result<bool, io::Error> {
+        let path = self.info.path();
+        let stamp = FileStamp::of(path)?;
+
+        let offset = if self.info.is_interim() {
+            if self.stamp == Some(stamp) {
+                return Ok(true);
+            }
+            0
+        } else {
+            self.offset
+        };
+
+        let mut file = File::open(path)?;
+        if file.metadata()?.len() <= offset {
+            return Ok(true);
+        }
+
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_to_end(&mut data)?;
+
+        let mut position = 0;
+        let mut documents = BsonReader::new(data.as_slice(), max_document_size);
+        while is_written(&data[position..]) {
+            match documents.next() {
+                Some((at, Ok(document))) => {
+                    position = at as usize + document.as_bytes().len();
+                    ready.push_back(Ok(FileDocument {
+                        document,
+                        source: Arc::clone(&self.info),
+                        offset: offset + at,
+                    }));
+                }
+                Some((at, Err(error))) => {
+                    let context = ErrorContext::new(path).with_offset(offset + at);
+                    ready.push_back(Err(error.with_context(context)));
+                    return Ok(false);
+                }
+                None => break,
+            }
+        }
+
+        if self.info.is_interim() {
+            // The interim file may be in the middle of being rewritten.
+            if position == data.len() {
+                self.stamp = Some(stamp);
+            }
+        } else {
+            self.offset = offset + position as u64;
+        }
+
+        Ok(true)
+    }
+}
+
+/// Returns whether the document at the start of `data` has been written in full.
+/// The documents with an invalid length are deemed written, so that they fail.
+fn is_written(data: &[u8]) -> bool {
+    let Some(length) = data.first_chunk::<4>() else {
+        return false;
+    };
+
+    usize::try_from(i32::from_le_bytes(*length)).map_or(true, |length| data.len() >= length)
+}
+
+impl Iterator for FollowReader {
+    type Item = Result<FileDocument, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(document) = self.ready.pop_front() {
+                return Some(document);
+            }
+
+            if self.finished || self.stop.is_stopped() {
+                return None;
+            }
+
+            if self.polled {
+                thread::sleep(self.poll_interval);
+                if self.stop.is_stopped() {
+                    return None;
+                }
+            }
+
+            self.polled = true;
+            self.poll();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use chrono::DateTime;
+    use chrono::Utc;
+
+    use crate::DiagnosticData;
+    use crate::Limits;
+    use crate::testing;
+    use crate::testing::TempDir;
+    use crate::testing::seconds;
+    use crate::write::FtdcWriter;
+
+    use super::*;
+
+    /// Writes the samples taken over the `secs` and splits them into documents.
+    fn documents(metadata: bool, secs: std::ops::Range<i64>) -> Vec<Vec<u8>> {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(5);
+        if metadata {
+            let metadata = testing::metadata("node-1");
+            writer.write_metadata(seconds(0), &metadata).unwrap();
+        }
+        testing::write_samples(&mut writer, "node-1:27017", secs);
+
+        let data = writer.finish().unwrap();
+        let mut documents = Vec::new();
+        let mut position = 0;
+        while position < data.len() {
+            let length = i32::from_le_bytes(data[position..position + 4].try_into().unwrap());
+            let end = position + length as usize;
+            documents.push(data[position..end].to_vec());
+            position = end;
+        }
+
+        documents
+    }
+
+    fn append(path: &std::path::Path, data: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    fn timestamps<I>(chunks: I) -> Vec<DateTime<Utc>>
+    where
+        I: Iterator<Item = Result<MetricsChunk, MetricParseError>>,
+    {
+        chunks
+            .flat_map(|chunk| {
+                let chunk = chunk.unwrap();
+                let uptime = chunk
+                    .into_metrics()
+                    .into_iter()
+                    .find(|metric| metric.name.as_ref() == "serverStatus uptime")
+                    .unwrap();
+                uptime.measurements.into_iter().map(|m| m.timestamp)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn follow_yields_samples_once_as_they_are_written() {
+        let dir = TempDir::new("follow");
+        let rotated = dir.join("metrics.2024-11-05T10-00-00Z-00000");
+        let interim = dir.join("metrics.interim");
+
+        // The metadata document followed by the chunks of 0-4, 5-9 and 10-14.
+        let chunks = documents(true, 0..15);
+        fs::write(&rotated, [&chunks[0][..], &chunks[1][..]].concat()).unwrap();
+        fs::write(&interim, &documents(false, 5..8)[0]).unwrap();
+
+        let mut follow = DiagnosticData::new(&dir)
+            .unwrap()
+            .follow(std::time::Duration::from_millis(10))
+            .unwrap();
+        let mut expected = (0..8).map(seconds).collect::<Vec<_>>();
+        assert_eq!(timestamps(follow.by_ref().take(2)), expected);
+
+        // The last chunk is only half written, while its first samples
+        // are already in the interim file.
+        let (written, rest) = chunks[3].split_at(chunks[3].len() / 2);
+        append(&rotated, &[&chunks[2][..], written].concat());
+        fs::write(&interim, &documents(false, 10..12)[0]).unwrap();
+        expected = (8..12).map(seconds).collect();
+        assert_eq!(timestamps(follow.by_ref().take(2)), expected);
+
+        append(&rotated, rest);
+        expected = (12..15).map(seconds).collect();
+        assert_eq!(timestamps(follow.by_ref().take(1)), expected);
+
+        follow.stop_handle().stop();
+        assert!(follow.next().is_none());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn files_failing_to_be_read_are_reported_once() {
+        let dir = TempDir::new("follow-error");
+        let rotated = dir.join("metrics.2024-11-05T10-00-00Z-00000");
+        fs::write(&rotated, documents(true, 0..5).concat()).unwrap();
+
+        let mut reader = FollowReader {
+            root: dir.to_path_buf(),
+            poll_interval: Duration::from_millis(10),
+            time_window: Arc::new(TimeWindow::default()),
+            max_document_size: Limits::default().max_document_size,
+            skip_log: SharedSkipLog::default(),
+            stop: StopHandle::default(),
+            files: HashMap::new(),
+            ignored: HashSet::new(),
+            ready: VecDeque::new(),
+            polled: false,
+            finished: false,
+        };
+        reader.poll();
+        assert_eq!(reader.ready.drain(..).filter(Result::is_ok).count(), 2);
+
+        // The file turns into a symbolic link to itself, which fails every read.
+        fs::remove_file(&rotated).unwrap();
+        std::os::unix::fs::symlink(&rotated, &rotated).unwrap();
+
+        reader.poll();
+        assert_eq!(reader.ready.drain(..).filter(Result::is_err).count(), 1);
+        assert!(reader.ignored.contains(&rotated));
+
+        reader.poll();
+        assert!(reader.ready.is_empty());
+    }
+}
@@ -0,0 +1,569 @@
+//! Defines an on-disk index of the diagnostic data files.
+//!
+//! The index records the byte offset, the kind and the timestamp of every
+//! document in the diagnostic data files, along with the host name of the
+//! metadata documents. With the index, the files are identified without being
+//! opened and only the documents that fall within the time window, and belong
+//! to the selected host, are read, which saves rescanning the same diagnostic
+//! data each time it is queried.
+//!
+//! The index of each file is kept for as long as the size and the modification
+//! time of the file stay the same, otherwise the file is read in full and
+//! indexed anew. The files are indexed as a by-product of reading them, hence
+//! only the files that have been read at least once are indexed.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::hash::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+use std::time::UNIX_EPOCH;
+
+use bson::Bson;
+use bson::Document;
+use bson::doc;
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::bson::DocumentKind;
+use crate::error::IndexSaveError;
+use crate::filter::TimeWindow;
+
+/// `IndexLocation` specifies where the index of the diagnostic data is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexLocation {
+    /// Stores the index in the directory of the diagnostic data files,
+    /// alongside them.
+    Beside,
+
+    /// Stores the index in the given cache directory, which is created if
+    /// missing, e.g. when the diagnostic data is on a read-only file system.
+    Dir(PathBuf),
+}
+
+impl IndexLocation {
+    /// The name of the index stored alongside the diagnostic data files.
+    const FILE_NAME: &str = ".mprobe-index";
+    const EXTENSION: &str = "mprobe-index";
+
+    /// Returns the path of the index of the diagnostic data files in `dir`.
+    fn index_path(&self, dir: &Path) -> PathBuf {
+        match self {
+            IndexLocation::Beside => dir.join(Self::FILE_NAME),
+            IndexLocation::Dir(cache_dir) => {
+                let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+                let mut hasher = DefaultHasher::new();
+                dir.hash(&mut hasher);
+
+                let name = format!("{:016x}.{}", hasher.finish(), Self::EXTENSION);
+                cache_dir.join(name)
+            }
+        }
+    }
+}
+
+/// Returns whether the file at `path` is an index stored alongside
+/// the diagnostic data files, or one being written.
+pub(crate) fn is_index_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(IndexLocation::FILE_NAME))
+}
+
+/// `FileStamp` identifies the content of a file by its size
+/// and its modification time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    size: u64,
+    modified: i64,
+}
+
+impl FileStamp {
+    pub(crate) fn of(path: &Path) -> Result<FileStamp, io::Error> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|modified| i64::try_from(modified.as_nanos()).ok())
+            .unwrap_or_default();
+
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+/// A document of a diagnostic data file, as recorded in the index.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct IndexedDocument {
+    pub(crate) offset: u64,
+    pub(crate) kind: DocumentKind,
+    pub(crate) timestamp: DateTime<Utc>,
+
+    /// The host name of a metadata document.
+    pub(crate) hostname: Option<String>,
+}
+
+/// The index of a single diagnostic data file.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FileIndex {
+    stamp: FileStamp,
+    documents: Vec<IndexedDocument>,
+}
+
+impl FileIndex {
+    /// Creates the index of a file with the `stamp` out of its `documents`,
+    /// returning `None` if the file has no documents.
+    pub(crate) fn new(stamp: FileStamp, documents: Vec<IndexedDocument>) -> Option<FileIndex> {
+        (!documents.is_empty()).then_some(Self { stamp, documents })
+    }
+
+    /// Returns the timestamp of the first document of the file and whether
+    /// it is an interim file, i.e. it starts with a metrics chunk.
+    pub(crate) fn start(&self) -> (DateTime<Utc>, bool) {
+        let first = &self.documents[0];
+        (first.timestamp, first.kind == DocumentKind::MetricsChunk)
+    }
+
+    /// Returns the offsets of the documents to read for the `time_window`
+    /// and the `hostname`, in the order they are stored in the file.
+    ///
+    /// All the documents other than the metrics chunks are read, since they
+    /// describe the chunks that follow them. The metrics chunks are selected
+    /// the same way they are filtered by the time window and the host name
+    /// once read, so the same chunks are yielded as if the file was read in full.
+    pub(crate) fn select(&self, time_window: &TimeWindow, hostname: Option<&str>) -> Vec<u64> {
+        let mut offsets = Vec::with_capacity(self.documents.len());
+        let mut pending = None;
+        let mut is_host = true;
+
+        for document in &self.documents {
+            if document.kind != DocumentKind::MetricsChunk {
+                if let Some(host) = &document.hostname {
+                    is_host = hostname.is_none_or(|hostname| hostname == host);
+                }
+
+                offsets.push(document.offset);
+                continue;
+            }
+
+            let timestamp = &document.timestamp;
+            if !is_host || time_window.is_after(timestamp) {
+                continue;
+            }
+
+            // The last chunk that starts before the time window may reach into it.
+            if time_window.is_before(timestamp) {
+                pending = Some(document.offset);
+                continue;
+            }
+
+            offsets.extend(pending.take());
+            offsets.push(document.offset);
+        }
+
+        offsets.extend(pending);
+        offsets.sort_unstable();
+        offsets
+    }
+}
+
+/// The index shared between the stages of the pipeline that read the files.
+#[derive(Debug, Clone)]
+pub(crate) struct SharedIndex(Arc<Mutex<IndexStore>>);
+
+#[derive(Debug)]
+struct IndexStore {
+    location: IndexLocation,
+    dirs: HashMap<PathBuf, DirIndex>,
+}
+
+impl IndexStore {
+    /// Returns the index of the files in `dir`, loading it on first access.
+    fn dir_index(&mut self, dir: &Path) -> &mut DirIndex {
+        let location = &self.location;
+        self.dirs.entry(dir.to_path_buf()).or_insert_with(|| {
+            SharedIndex::load(&location.index_path(dir), dir).unwrap_or_default()
+        })
+    }
+}
+
+/// The index of the diagnostic data files in a single directory.
+#[derive(Debug, Default)]
+struct DirIndex {
+    files: HashMap<OsString, Arc<FileIndex>>,
+    changed: bool,
+}
+
+impl SharedIndex {
+    const VERSION: i32 = 1;
+
+    const VERSION_KEY: &str = "version";
+    const DIR_KEY: &str = "dir";
+    const FILES_KEY: &str = "files";
+    const SIZE_KEY: &str = "size";
+    const MODIFIED_KEY: &str = "modified";
+    const DOCUMENTS_KEY: &str = "documents";
+    const OFFSET_KEY: &str = "offset";
+    const KIND_KEY: &str = "kind";
+    const TIMESTAMP_KEY: &str = "timestamp";
+    const HOSTNAME_KEY: &str = "hostname";
+
+    pub(crate) fn new(location: IndexLocation) -> Self {
+        Self(Arc::new(Mutex::new(IndexStore {
+            location,
+            dirs: HashMap::new(),
+        })))
+    }
+
+    /// Locks the index. The index is only ever updated with whole entries,
+    /// so it is still consistent even if a thread panicked holding it.
+    fn lock(&self) -> MutexGuard<'_, IndexStore> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the index of the file at `path`, if the file was indexed
+    /// and it has not changed since.
+    pub(crate) fn lookup(&self, path: &Path) -> Option<Arc<FileIndex>> {
+        let (dir, name) = (path.parent()?, path.file_name()?);
+        let stamp = FileStamp::of(path).ok()?;
+
+        let mut store = self.lock();
+        store
+            .dir_index(dir)
+            .files
+            .get(name)
+            .filter(|index| index.stamp == stamp)
+            .cloned()
+    }
+
+    /// Records the `index` of the file at `path`.
+    pub(crate) fn insert(&self, path: &Path, index: FileIndex) {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return;
+        };
+
+        let mut store = self.lock();
+        let dir_index = store.dir_index(dir);
+        dir_index.files.insert(name.to_owned(), Arc::new(index));
+        dir_index.changed = true;
+    }
+
+    /// Writes the indexes of the directories that changed to disk.
+    /// The entries of the files that no longer exist are dropped.
+    ///
+    /// Returns the errors of the indexes that could not be written,
+    /// which are written again on the next save.
+    pub(crate) fn save(&self) -> Vec<IndexSaveError> {
+        let mut store = self.lock();
+        let location = store.location.clone();
+        let mut errors = Vec::new();
+
+        for (dir, dir_index) in store.dirs.iter_mut().filter(|(_, d)| d.changed) {
+            dir_index.files.retain(|name, _| dir.join(name).exists());
+
+            let path = location.index_path(dir);
+            match Self::write(&path, dir, dir_index) {
+                Ok(()) => dir_index.changed = false,
+                Err(error) => errors.push(IndexSaveError {
+                    path,
+                    error: Arc::new(error),
+                }),
+            }
+        }
+
+        errors
+    }
+
+    fn write(path: &Path, dir: &Path, dir_index: &DirIndex) -> Result<(), io::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // The index is replaced at once, so that a concurrent reader
+        // never sees it partially written.
+        let temp_path = path.with_file_name(format!(
+            "{}.tmp",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        let data = Self::to_document(dir, dir_index)
+            .to_vec()
+            .map_err(io::Error::other)?;
+        fs::write(&temp_path, data)?;
+        fs::rename(&temp_path, path)
+    }
+
+    fn to_document(dir: &Path, dir_index: &DirIndex) -> Document {
+        let mut files = Document::new();
+
+        for (name, index) in &dir_index.files {
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+
+            let documents = index
+                .documents
+                .iter()
+                .map(|document| {
+                    let mut entry = doc! {
+                        Self::OFFSET_KEY: document.offset as i64,
+                        Self::KIND_KEY: document.kind as i32,
+                        Self::TIMESTAMP_KEY: bson::DateTime::from_chrono(document.timestamp),
+                    };
+                    if let Some(hostname) = &document.hostname {
+                        entry.insert(Self::HOSTNAME_KEY, hostname);
+                    }
+
+                    Bson::Document(entry)
+                })
+                .collect::<Vec<_>>();
+
+            files.insert(
+                name,
+                doc! {
+                    Self::SIZE_KEY: index.stamp.size as i64,
+                    Self::MODIFIED_KEY: index.stamp.modified,
+                    Self::DOCUMENTS_KEY: documents,
+                },
+            );
+        }
+
+        doc! {
+            Self::VERSION_KEY: Self::VERSION,
+            Self::DIR_KEY: dir.to_string_lossy().as_ref(),
+            Self::FILES_KEY: files,
+        }
+    }
+
+    /// Loads the index of the files in `dir` stored at `path`. An index that
+    /// cannot be read, or that was written by another version, is discarded.
+    fn load(path: &Path, dir: &Path) -> Option<DirIndex> {
+        let data = fs::read(path).ok()?;
+        let document = Document::from_reader(data.as_slice()).ok()?;
+
+        let version = document.get_i32(Self::VERSION_KEY).ok()?;
+        let indexed_dir = document.get_str(Self::DIR_KEY).ok()?;
+        if version != Self::VERSION || indexed_dir != dir.to_string_lossy() {
+            return None;
+        }
+
+        let mut files = HashMap::new();
+        for (name, index) in document.get_document(Self::FILES_KEY).ok()? {
+            let index = Self::parse_file_index(index.as_document()?)?;
+            files.insert(OsString::from(name), Arc::new(index));
+        }
+
+        Some(DirIndex {
+            files,
+            changed: false,
+        })
+    }
+
+    fn parse_file_index(index: &Document) -> Option<FileIndex> {
+        let stamp = FileStamp {
+            size: index.get_i64(Self::SIZE_KEY).ok()?.try_into().ok()?,
+            modified: index.get_i64(Self::MODIFIED_KEY).ok()?,
+        };
+
+        let documents = index
+            .get_array(Self::DOCUMENTS_KEY)
+            .ok()?
+            .iter()
+            .map(|document| {
+                let document = document.as_document()?;
+
+                Some(IndexedDocument {
+                    offset: document.get_i64(Self::OFFSET_KEY).ok()?.try_into().ok()?,
+                    kind: DocumentKind::try_from(document.get_i32(Self::KIND_KEY).ok()?).ok()?,
+                    timestamp: document.get_datetime(Self::TIMESTAMP_KEY).ok()?.to_chrono(),
+                    hostname: document.get_str(Self::HOSTNAME_KEY).ok().map(str::to_owned),
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        FileIndex::new(stamp, documents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::DiagnosticData;
+    use crate::MetricsFilter;
+    use crate::metrics::MetricsChunk;
+    use crate::source::SkipReport;
+    use crate::testing;
+    use crate::testing::TempDir;
+    use crate::testing::seconds;
+    use crate::write::FtdcWriter;
+
+    fn document(offset: u64, kind: DocumentKind, secs: i64) -> IndexedDocument {
+        IndexedDocument {
+            offset,
+            kind,
+            timestamp: seconds(secs),
+            hostname: None,
+        }
+    }
+
+    fn metadata(offset: u64, secs: i64, hostname: &str) -> IndexedDocument {
+        IndexedDocument {
+            hostname: Some(hostname.to_owned()),
+            ..document(offset, DocumentKind::Metadata, secs)
+        }
+    }
+
+    #[test]
+    fn select_reads_chunks_within_time_window_of_selected_host() {
+        let stamp = FileStamp {
+            size: 0,
+            modified: 0,
+        };
+        let chunk = DocumentKind::MetricsChunk;
+        let documents = vec![
+            metadata(0, 0, "node-1"),
+            document(10, chunk, 0),
+            document(20, chunk, 10),
+            document(30, DocumentKind::PeriodicMetadata, 15),
+            document(40, chunk, 20),
+            document(50, chunk, 30),
+            document(60, chunk, 40),
+            metadata(70, 45, "node-2"),
+            document(80, chunk, 50),
+        ];
+        let index = FileIndex::new(stamp, documents).unwrap();
+
+        let window = TimeWindow::new(Some(seconds(15)), Some(seconds(30)));
+        assert_eq!(index.select(&window, None), vec![0, 20, 30, 40, 50, 70]);
+
+        let window = TimeWindow::new(Some(seconds(45)), None);
+        assert_eq!(index.select(&window, None), vec![0, 30, 60, 70, 80]);
+        assert_eq!(index.select(&window, Some("node-1")), vec![0, 30, 60, 70]);
+
+        let window = TimeWindow::default();
+        assert_eq!(index.select(&window, Some("node-2")), vec![0, 30, 70, 80]);
+    }
+
+    fn write_file(path: &Path, range: std::ops::Range<i64>) {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(5);
+        let metadata = testing::metadata("node-1");
+        writer
+            .write_metadata(seconds(range.start), &metadata)
+            .unwrap();
+        testing::write_samples(&mut writer, "node-1:27017", range);
+
+        fs::write(path, writer.finish().unwrap()).unwrap();
+    }
+
+    fn read(dir: &Path, index: Option<IndexLocation>, start: i64, end: i64) -> Vec<MetricsChunk> {
+        let filter = MetricsFilter::new(None, Some(seconds(start)), Some(seconds(end)));
+        let mut data = DiagnosticData::filter(dir, filter).unwrap();
+        if let Some(index) = index {
+            data = data.with_index(index);
+        }
+
+        data.into_iter().collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    fn starts(chunks: &[MetricsChunk]) -> Vec<DateTime<Utc>> {
+        chunks.iter().map(|chunk| chunk.start).collect()
+    }
+
+    #[test]
+    fn indexed_files_are_read_from_the_time_window() {
+        let root = TempDir::new("index");
+        let dir = root.join("diagnostic.data");
+        let cache = root.join("cache");
+        fs::create_dir_all(&dir).unwrap();
+
+        let first = dir.join("metrics.2024-11-05T10-00-00Z-00000");
+        let second = dir.join("metrics.2024-11-05T10-01-00Z-00000");
+        write_file(&first, 0..60);
+        write_file(&second, 60..120);
+
+        let location = IndexLocation::Dir(cache.clone());
+        let expected = read(&dir, None, 0, 200);
+        assert_eq!(
+            read(&dir, Some(location.clone()), 0, 200).len(),
+            expected.len()
+        );
+
+        // The files were indexed while read in full.
+        let index = SharedIndex::new(location.clone());
+        let file_index = index.lookup(&first).unwrap();
+        assert_eq!(file_index.start(), (seconds(0), false));
+        assert_eq!(file_index.documents.len(), 13);
+
+        let window = TimeWindow::new(Some(seconds(22)), Some(seconds(31)));
+        assert_eq!(file_index.select(&window, None).len(), 4);
+
+        let expected = read(&dir, None, 22, 31);
+        let actual = read(&dir, Some(location.clone()), 22, 31);
+        assert_eq!(starts(&actual), starts(&expected));
+        assert_eq!(starts(&actual), vec![seconds(20), seconds(25), seconds(30)]);
+
+        // A file that changed is indexed anew.
+        write_file(&second, 60..90);
+        assert!(SharedIndex::new(location.clone()).lookup(&second).is_none());
+        assert_eq!(read(&dir, Some(location.clone()), 80, 200).len(), 2);
+        assert!(SharedIndex::new(location).lookup(&second).is_some());
+
+        // The index stored alongside the files is not reported as skipped.
+        read(&dir, Some(IndexLocation::Beside), 0, 200);
+        assert!(dir.join(IndexLocation::FILE_NAME).exists());
+        let mut chunks = DiagnosticData::new(&dir).unwrap().into_iter();
+        chunks.by_ref().for_each(drop);
+        assert!(chunks.skipped_files().is_empty());
+    }
+
+    #[test]
+    fn index_is_saved_when_reading_stops_early_and_save_errors_are_recorded() {
+        let root = TempDir::new("index-save");
+        let dir = root.join("diagnostic.data");
+        fs::create_dir_all(&dir).unwrap();
+
+        let first = dir.join("metrics.2024-11-05T10-00-00Z-00000");
+        let second = dir.join("metrics.2024-11-05T10-01-00Z-00000");
+        write_file(&first, 0..60);
+        write_file(&second, 60..120);
+
+        // The first file is read through, yet the second one is not.
+        let location = IndexLocation::Dir(root.join("cache"));
+        let chunks = DiagnosticData::new(&dir)
+            .unwrap()
+            .with_index(location.clone())
+            .into_iter()
+            .take(13)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(chunks.last().unwrap().start, seconds(60));
+
+        let index = SharedIndex::new(location);
+        assert!(index.lookup(&first).is_some());
+        assert!(index.lookup(&second).is_none());
+
+        // The cache directory cannot be created over a file.
+        let blocked = root.join("blocked");
+        fs::write(&blocked, b"").unwrap();
+        let mut chunks = DiagnosticData::new(&dir)
+            .unwrap()
+            .with_index(IndexLocation::Dir(blocked.clone()))
+            .into_iter();
+        assert_eq!(chunks.by_ref().filter_map(Result::ok).count(), 24);
+
+        let errors = chunks.index_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].path.starts_with(&blocked));
+    }
+}
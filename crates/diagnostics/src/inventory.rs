@@ -0,0 +1,342 @@
+//! Defines an inventory of the diagnostic data, which lists the processes
+//! it was captured from along with the time ranges it covers.
+//!
+//! Unlike iterating over the [diagnostic data], building the [`Inventory`]
+//! reads only the metadata documents and the timestamps of the metrics chunks,
+//! while the chunks themselves are left compressed. Only the last chunk
+//! of each process is decoded, for the details reported in its samples.
+//!
+//! [diagnostic data]: crate::DiagnosticData
+
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use chrono::DateTime;
+use chrono::TimeDelta;
+use chrono::Utc;
+
+use crate::Limits;
+use crate::bson::DocumentKind;
+use crate::bson::ReadDocument;
+use crate::error::IndexSaveError;
+use crate::error::MetricParseError;
+use crate::metadata::Metadata;
+use crate::metadata::ProcessInfo;
+use crate::metrics;
+use crate::metrics::columnar::ColumnarChunk;
+use crate::metrics::raw::EncodedSamples;
+use crate::read::FileDocument;
+use crate::source::SkipLog;
+use crate::source::SkippedFile;
+use crate::source::SkippedRegion;
+
+/// `Inventory` lists the processes whose diagnostic data was found in a source.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    /// The processes the diagnostic data was captured from, sorted by
+    /// the directory holding their diagnostic data.
+    pub entries: Vec<InventoryEntry>,
+
+    /// The files of the source that do not hold diagnostic data.
+    pub skipped_files: Vec<SkippedFile>,
+
+    /// The regions of the files that could not be decoded in recovery mode.
+    pub skipped_regions: Vec<SkippedRegion>,
+
+    /// The errors encountered while reading the diagnostic data, which leave
+    /// the affected entries incomplete rather than failing the inventory.
+    pub errors: Vec<MetricParseError>,
+
+    /// The errors of saving the index of the files, if they are indexed.
+    pub index_errors: Vec<IndexSaveError>,
+}
+
+/// `InventoryEntry` describes the diagnostic data captured from a single process,
+/// i.e. the diagnostic data files found in the same directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InventoryEntry {
+    /// Specifies the directory that holds the diagnostic data files.
+    pub dir: PathBuf,
+
+    /// Specifies the host name, as reported by the `hostInfo` command.
+    pub hostname: Option<String>,
+
+    /// Specifies the `hostname:port` the process listens on,
+    /// as reported in `serverStatus.host`.
+    pub host: Option<String>,
+
+    /// Specifies the process, e.g. mongod or mongos.
+    pub process: Option<String>,
+
+    /// Specifies the database version of the process.
+    pub version: Option<String>,
+
+    /// Specifies the name of the replica set the process is a member of, if any.
+    pub replica_set: Option<String>,
+
+    /// Specifies the diagnostic data files, in the order they were read.
+    pub files: Vec<PathBuf>,
+
+    /// Specifies the timestamp of the first sample.
+    pub first_sample: Option<DateTime<Utc>>,
+
+    /// Specifies the timestamp of the last sample.
+    pub last_sample: Option<DateTime<Utc>>,
+
+    /// Specifies the amount of metrics chunks. The chunks of the interim file
+    /// are counted as well, although some of their samples may have already
+    /// been written into the rotated files.
+    pub chunk_count: usize,
+
+    /// Specifies the approximate interval between the samples,
+    /// as measured in the last metrics chunk.
+    pub sample_interval: Option<TimeDelta>,
+}
+
+impl InventoryEntry {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            hostname: None,
+            host: None,
+            process: None,
+            version: None,
+            replica_set: None,
+            files: Vec::new(),
+            first_sample: None,
+            last_sample: None,
+            chunk_count: 0,
+            sample_interval: None,
+        }
+    }
+}
+
+/// The state of an [`InventoryEntry`] while its documents are being read.
+struct PendingEntry {
+    entry: InventoryEntry,
+    process_info: Option<ProcessInfo>,
+    last_chunk: Option<(DateTime<Utc>, FileDocument)>,
+}
+
+/// `InventoryBuilder` builds the [`Inventory`] out of the documents read
+/// from the diagnostic data files.
+pub(crate) struct InventoryBuilder {
+    limits: Limits,
+    entries: BTreeMap<PathBuf, PendingEntry>,
+    errors: Vec<MetricParseError>,
+}
+
+impl InventoryBuilder {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            entries: BTreeMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Adds the `document` to the entry of the directory it was read from.
+    pub(crate) fn add(&mut self, document: FileDocument) -> Result<(), MetricParseError> {
+        let dir = document.source.dir();
+        let pending = self
+            .entries
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| PendingEntry {
+                entry: InventoryEntry::new(dir.to_path_buf()),
+                process_info: None,
+                last_chunk: None,
+            });
+
+        let entry = &mut pending.entry;
+        let path = document.source.path();
+        if entry.files.last().is_none_or(|last| last != path) {
+            entry.files.push(path.to_path_buf());
+        }
+
+        match document.kind()? {
+            DocumentKind::Metadata => {
+                let process_info = ProcessInfo::from_metadata_document(&document.metadata()?);
+                if let Some(host_info) = &process_info.host_info {
+                    entry.hostname = Some(host_info.hostname.clone());
+                }
+                pending.process_info = Some(process_info);
+            }
+            DocumentKind::MetricsChunk => {
+                let timestamp = document.timestamp()?;
+                entry.chunk_count += 1;
+                entry.first_sample =
+                    Some(entry.first_sample.map_or(timestamp, |t| t.min(timestamp)));
+
+                if pending
+                    .last_chunk
+                    .as_ref()
+                    .is_none_or(|(last, _)| *last <= timestamp)
+                {
+                    pending.last_chunk = Some((timestamp, document));
+                }
+            }
+            DocumentKind::PeriodicMetadata => {}
+        }
+
+        Ok(())
+    }
+
+    /// Records an `error` encountered while reading the documents.
+    pub(crate) fn error(&mut self, error: MetricParseError) {
+        self.errors.push(error);
+    }
+
+    /// Builds the inventory, decoding the last metrics chunk of each entry.
+    pub(crate) fn build(mut self, skip_log: SkipLog) -> Inventory {
+        let mut entries = Vec::with_capacity(self.entries.len());
+
+        for (_, pending) in std::mem::take(&mut self.entries) {
+            let PendingEntry {
+                mut entry,
+                process_info,
+                last_chunk,
+            } = pending;
+
+            if let Some((_, document)) = last_chunk
+                && let Err(err) = self.describe(&mut entry, &document, process_info.as_ref())
+            {
+                self.errors.push(err.with_context(document.context()));
+            }
+
+            entries.push(entry);
+        }
+
+        Inventory {
+            entries,
+            skipped_files: skip_log.files,
+            skipped_regions: skip_log.regions,
+            errors: self.errors,
+            index_errors: skip_log.index_errors,
+        }
+    }
+
+    /// Completes the `entry` with the details read from its last metrics chunk.
+    fn describe(
+        &self,
+        entry: &mut InventoryEntry,
+        document: &FileDocument,
+        process_info: Option<&ProcessInfo>,
+    ) -> Result<(), MetricParseError> {
+        let select = |metadata: Metadata| match process_info {
+            Some(process_info) => Some(metadata.with_process_info(process_info)),
+            None => Some(metadata),
+        };
+        let Some(samples) = EncodedSamples::from_reader(
+            &mut Cursor::new(document.metrics_chunk()?),
+            &self.limits,
+            select,
+        )?
+        else {
+            return Ok(());
+        };
+
+        let metadata = &samples.metadata;
+        entry.host = Some(metadata.host.clone());
+        entry.process = Some(metadata.process.clone());
+        entry.version = Some(metadata.version.clone());
+        entry.replica_set = metadata.replica_set.clone();
+
+        let timestamps = samples
+            .parse()?
+            .into_iter()
+            .find(|metric| metric.groups == [ColumnarChunk::START_TIMESTAMP_METRIC_NAME])
+            .map(|metric| metric.values)
+            .ok_or_else(|| MetricParseError::MetricTimestampNotFound {
+                name: ColumnarChunk::START_TIMESTAMP_METRIC_NAME.into(),
+            })?;
+
+        let (Some(first), Some(last)) = (timestamps.first(), timestamps.last()) else {
+            return Ok(());
+        };
+        let first = metrics::timestamp_from_millis(*first);
+        let last = metrics::timestamp_from_millis(*last);
+
+        entry.last_sample = Some(last);
+        if let Ok(intervals) = i32::try_from(timestamps.len() - 1)
+            && intervals > 0
+        {
+            entry.sample_interval = Some((last - first) / intervals);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bson::doc;
+
+    use crate::DiagnosticData;
+    use crate::testing;
+    use crate::testing::TempDir;
+    use crate::testing::seconds;
+    use crate::write::FtdcWriter;
+
+    use super::*;
+
+    fn write_node(dir: &std::path::Path, host: &str, version: &str, secs: &[i64], interval: i64) {
+        fs::create_dir_all(dir).unwrap();
+        let hostname = host.split(':').next().unwrap();
+
+        for (idx, start) in secs.iter().enumerate() {
+            let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(5);
+            let metadata = doc! {
+                "buildInfo": { "version": version },
+                "hostInfo": { "system": { "hostname": hostname } },
+            };
+            writer.write_metadata(seconds(*start), &metadata).unwrap();
+
+            for sample in 0..12 {
+                let ts = seconds(start + sample * interval);
+                let sample = testing::sample(ts, host, version, sample);
+                writer.write_sample(ts, &sample).unwrap();
+            }
+
+            let name = format!("metrics.2024-11-05T10-00-00Z-{idx:05}");
+            fs::write(dir.join(name), writer.finish().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn inventory_lists_processes_with_their_time_ranges() {
+        let root = TempDir::new("inventory");
+        write_node(&root.join("node-1"), "node-1:27017", "7.0.12", &[0, 100], 1);
+        write_node(&root.join("node-2"), "node-2:27018", "8.0.4", &[10], 2);
+        fs::write(root.join("README"), "not diagnostic data").unwrap();
+
+        let inventory = DiagnosticData::new(&root).unwrap().inventory();
+
+        assert!(inventory.errors.is_empty(), "{:?}", inventory.errors);
+        assert_eq!(inventory.skipped_files.len(), 1);
+        assert_eq!(inventory.entries.len(), 2);
+
+        let node_1 = &inventory.entries[0];
+        assert_eq!(node_1.dir, root.join("node-1"));
+        assert_eq!(node_1.hostname.as_deref(), Some("node-1"));
+        assert_eq!(node_1.host.as_deref(), Some("node-1:27017"));
+        assert_eq!(node_1.process.as_deref(), Some("mongod"));
+        assert_eq!(node_1.version.as_deref(), Some("7.0.12"));
+        assert_eq!(node_1.files.len(), 2);
+        assert_eq!(node_1.first_sample, Some(seconds(0)));
+        assert_eq!(node_1.last_sample, Some(seconds(111)));
+        assert_eq!(node_1.chunk_count, 6);
+        assert_eq!(node_1.sample_interval, Some(TimeDelta::seconds(1)));
+
+        let node_2 = &inventory.entries[1];
+        assert_eq!(node_2.host.as_deref(), Some("node-2:27018"));
+        assert_eq!(node_2.version.as_deref(), Some("8.0.4"));
+        assert_eq!(node_2.files.len(), 1);
+        assert_eq!(node_2.first_sample, Some(seconds(10)));
+        assert_eq!(node_2.last_sample, Some(seconds(32)));
+        assert_eq!(node_2.chunk_count, 3);
+        assert_eq!(node_2.sample_interval, Some(TimeDelta::seconds(2)));
+    }
+}
@@ -1,3 +1,16 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::thread::JoinHandle;
+
 /// Iterator adapters
 pub(crate) trait IteratorExt {
     /// Creates a fallible iterator that uses a fallible clojure to determine
@@ -22,6 +35,21 @@ pub(crate) trait IteratorExt {
     {
         TryFlatten::new(self)
     }
+
+    /// Creates an iterator that maps the elements with `f` on a pool of `threads`,
+    /// yet yields them in the order of the underlying iterator. At most
+    /// `max_in_flight` elements are taken from the underlying iterator
+    /// ahead of the element to be yielded next.
+    #[inline]
+    fn parallel_map<R, F>(self, threads: usize, max_in_flight: usize, f: F) -> ParallelMap<Self, R>
+    where
+        Self: Iterator + Sized,
+        Self::Item: Send + 'static,
+        R: Send + 'static,
+        F: Fn(Self::Item) -> R + Send + Sync + 'static,
+    {
+        ParallelMap::new(self, threads, max_in_flight, f)
+    }
 }
 
 impl<I: Iterator> IteratorExt for I {}
@@ -108,6 +136,138 @@ where
     }
 }
 
+/// The result of mapping an element on a worker thread, or the payload
+/// of the panic that occurred while mapping it.
+type WorkResult<R> = (usize, Result<R, Box<dyn Any + Send>>);
+
+/// An iterator that maps the elements of `iter` on a pool of worker threads
+/// and yields them in their original order.
+///
+/// This `struct` is created by the [`IteratorExt::parallel_map`] method on [`IteratorExt`].
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub(crate) struct ParallelMap<I: Iterator, R> {
+    iter: I,
+    jobs: Option<SyncSender<(usize, I::Item)>>,
+    results: Receiver<WorkResult<R>>,
+    finished: BTreeMap<usize, R>,
+    submitted: usize,
+    yielded: usize,
+    max_in_flight: usize,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<I, R> ParallelMap<I, R>
+where
+    I: Iterator,
+    I::Item: Send + 'static,
+    R: Send + 'static,
+{
+    pub(in crate::iter) fn new<F>(iter: I, threads: usize, max_in_flight: usize, f: F) -> Self
+    where
+        F: Fn(I::Item) -> R + Send + Sync + 'static,
+    {
+        let threads = threads.max(1);
+        let max_in_flight = max_in_flight.max(threads);
+
+        let (jobs, job_receiver) = mpsc::sync_channel(max_in_flight);
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let f = Arc::new(f);
+
+        let workers = (0..threads)
+            .map(|_| {
+                let jobs = Arc::clone(&job_receiver);
+                let results = result_sender.clone();
+                let f = Arc::clone(&f);
+                thread::spawn(move || Self::work(&jobs, &results, &*f))
+            })
+            .collect();
+
+        Self {
+            iter,
+            jobs: Some(jobs),
+            results,
+            finished: BTreeMap::new(),
+            submitted: 0,
+            yielded: 0,
+            max_in_flight,
+            workers,
+        }
+    }
+
+    fn work<F>(jobs: &Mutex<Receiver<(usize, I::Item)>>, results: &Sender<WorkResult<R>>, f: &F)
+    where
+        F: Fn(I::Item) -> R,
+    {
+        loop {
+            // The lock is released as soon as a job is received,
+            // so that the other workers can receive the next ones.
+            let job = jobs.lock().map(|jobs| jobs.recv());
+            let Ok(Ok((idx, item))) = job else {
+                return;
+            };
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(item)));
+            if results.send((idx, result)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl<I, R> Iterator for ParallelMap<I, R>
+where
+    I: Iterator,
+{
+    type Item = R;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.submitted - self.yielded < self.max_in_flight {
+            let Some(jobs) = self.jobs.as_ref() else {
+                break;
+            };
+
+            match self.iter.next() {
+                Some(item) => {
+                    jobs.send((self.submitted, item))
+                        .expect("the workers to run while jobs are submitted");
+                    self.submitted += 1;
+                }
+                None => self.jobs = None,
+            }
+        }
+
+        while self.yielded < self.submitted {
+            if let Some(result) = self.finished.remove(&self.yielded) {
+                self.yielded += 1;
+                return Some(result);
+            }
+
+            match self.results.recv() {
+                Ok((idx, Ok(result))) => {
+                    self.finished.insert(idx, result);
+                }
+                Ok((_, Err(payload))) => panic::resume_unwind(payload),
+                Err(_) => return None,
+            }
+        }
+
+        None
+    }
+}
+
+impl<I: Iterator, R> Drop for ParallelMap<I, R> {
+    fn drop(&mut self) {
+        // Closing the jobs channel stops the workers once they finish
+        // the jobs at hand.
+        self.jobs = None;
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -137,4 +297,43 @@ mod tests {
 
         assert_eq!(flattened, expected);
     }
+
+    #[test]
+    fn parallel_map_must_keep_order() {
+        let expected: Vec<u64> = (0..200).map(|n| n * n).collect();
+
+        let mapped: Vec<u64> = (0..200u64)
+            .parallel_map(4, 8, |n| {
+                // Delay the earlier elements, so they finish out of order.
+                thread::sleep(std::time::Duration::from_micros(200 - n));
+                n * n
+            })
+            .collect();
+
+        assert_eq!(mapped, expected);
+    }
+
+    #[test]
+    fn parallel_map_must_bound_elements_in_flight() {
+        let taken = Arc::new(Mutex::new(0usize));
+        let source = {
+            let taken = Arc::clone(&taken);
+            (0..100).inspect(move |_| *taken.lock().unwrap() += 1)
+        };
+
+        let mut mapped = source.parallel_map(2, 5, |n| n);
+
+        assert_eq!(mapped.next(), Some(0));
+        assert_eq!(*taken.lock().unwrap(), 5);
+        assert_eq!(mapped.by_ref().take(10).count(), 10);
+        assert_eq!(*taken.lock().unwrap(), 15);
+    }
+
+    #[test]
+    #[should_panic(expected = "mapping failed")]
+    fn parallel_map_must_propagate_panics() {
+        let _ = (0..10)
+            .parallel_map(2, 4, |n| if n == 3 { panic!("mapping failed") } else { n })
+            .count();
+    }
 }
@@ -30,7 +30,7 @@
 //!     let diagnostic_data = DiagnosticData::new(&path)?;
 //!
 //!     for chunk in diagnostic_data {
-//!         for metric in chunk?.metrics {
+//!         for metric in chunk?.into_metrics() {
 //!             println!("{}", metric.name);
 //!         }
 //!     }
@@ -39,6 +39,15 @@
 //! }
 //! ```
 //!
+//! A single metric, or all the metrics of a section, can be looked up in a chunk
+//! by its [path](crate::metrics::MetricPath), e.g. `serverStatus.connections.current`,
+//! through the [get](crate::metrics::MetricsChunk::get) and
+//! [starting_with](crate::metrics::MetricsChunk::starting_with) functions.
+//!
+//! Consumers that stream the metrics elsewhere, e.g. into files, can instead have
+//! a [MetricsVisitor] driven with the metrics as they are decoded, through the
+//! [DiagnosticData::visit] function, which avoids building the chunks altogether.
+//!
 //! # Filter the diagnostic data
 //!
 //! Since the [DiagnosticData] implements [IntoIterator], one could use
@@ -65,6 +74,129 @@
 //! let diagnostic_data = DiagnosticData::filter(&path, filter).expect("valid path");
 //! ```
 //!
+//! When several processes run on the same host, their diagnostic data can be
+//! told apart with [InstanceSelector]s, which match the processes by their
+//! `host:port`, process type, replica set, or version.
+//!
+//! The metrics of the chunks can be narrowed down as well, by including
+//! and excluding glob patterns on the metric paths, which saves the time
+//! and memory of building the metrics that are not needed.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use mprobe_diagnostics::{DiagnosticData, MetricsFilter};
+//!
+//! let path = Path::new("/path/to/diagnostic/data");
+//!
+//! let filter = MetricsFilter::default()
+//!     .include_metrics("serverStatus.opcounters.*")
+//!     .include_metrics("serverStatus.connections.*");
+//! let diagnostic_data = DiagnosticData::filter(&path, filter).expect("valid path");
+//! ```
+//!
+//! # Read the diagnostic data from other sources
+//!
+//! Besides a directory, the diagnostic data can be read from a `.tar.gz` archive,
+//! a single file, a byte buffer, or any [reader](std::io::Read), using one of
+//! the [DiagnosticData] constructors. The same filters apply to all of them.
+//!
+//! ```no_run
+//! use std::io;
+//! use std::path::Path;
+//! use mprobe_diagnostics::DiagnosticData;
+//!
+//! let path = Path::new("/path/to/diagnostic.data/metrics.2024-11-05T10-00-00Z-00000");
+//! let diagnostic_data = DiagnosticData::from_file(&path).expect("valid path");
+//!
+//! let diagnostic_data = DiagnosticData::from_reader(io::stdin());
+//! ```
+//!
+//! # Follow the diagnostic data
+//!
+//! The diagnostic data of a running mongod can be followed through
+//! the [DiagnosticData::follow] function, which polls its `diagnostic.data`
+//! directory and yields the metrics chunks as they are written.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use std::time::Duration;
+//! use mprobe_diagnostics::DiagnosticData;
+//!
+//! let path = Path::new("/var/lib/mongodb/diagnostic.data");
+//! let chunks = DiagnosticData::new(&path)
+//!     .and_then(|diagnostic_data| diagnostic_data.follow(Duration::from_secs(1)))
+//!     .expect("valid path");
+//!
+//! for chunk in chunks {
+//!     println!("{:?}", chunk.map(|chunk| chunk.end));
+//! }
+//! ```
+//!
+//! # Read the newest diagnostic data
+//!
+//! Investigations usually start from the most recent metrics. The metrics chunks
+//! can be read from the newest to the oldest through the
+//! [DiagnosticData::newest_first] function, while the [DiagnosticData::latest]
+//! function selects the metrics of the last minutes before the newest sample,
+//! rather than before the current time.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use std::time::Duration;
+//! use mprobe_diagnostics::DiagnosticData;
+//! use mprobe_diagnostics::error::MetricParseError;
+//!
+//! fn main() -> Result<(), MetricParseError> {
+//!     let path = Path::new("/path/to/diagnostic/data");
+//!     let diagnostic_data = DiagnosticData::new(&path)?.latest(Duration::from_secs(30 * 60))?;
+//!
+//!     for chunk in diagnostic_data.newest_first() {
+//!         println!("{}", chunk?.start);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+//!
+//! # Read the diagnostic data of several processes
+//!
+//! The diagnostic data of a cluster usually holds a directory for each node.
+//! A [Dataset](crate::dataset::Dataset) groups the diagnostic data files by
+//! the directory that holds them, and reads the metrics of each node on its own,
+//! or merged in timestamp order across the nodes.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use mprobe_diagnostics::dataset::Dataset;
+//! use mprobe_diagnostics::error::MetricParseError;
+//!
+//! fn main() -> Result<(), MetricParseError> {
+//!     let path = Path::new("/path/to/cluster/diagnostic/data");
+//!     let dataset = Dataset::new(&path)?;
+//!
+//!     for chunk in dataset.merged()? {
+//!         match chunk {
+//!             Ok((instance, chunk)) => println!("{:?}: {}", instance.dir, chunk.start),
+//!             Err((instance, error)) => eprintln!("{:?}: {error}", instance.dir),
+//!         }
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The diagnostic data files in a directory or an archive are identified
+//! by their content rather than by their name, so renamed files are read as well.
+//! The other files are skipped and can be listed through the `skipped_files`
+//! function of the [SkipReport](crate::source::SkipReport) implemented by
+//! the iterator, once it has been consumed.
+//!
+//! # Write the diagnostic data
+//!
+//! The [FtdcWriter](crate::write::FtdcWriter) writes metadata documents, samples,
+//! or the metric chunks read from other diagnostic data in the format generated
+//! by mongod, which can be read back as any other diagnostic data file.
+//!
 
 #![warn(missing_docs)]
 
@@ -73,54 +205,289 @@ mod bytes;
 mod compression;
 mod filter;
 mod iter;
+mod number;
 mod read;
+#[cfg(test)]
+mod testing;
 
+pub mod dataset;
 pub mod error;
+pub mod follow;
+pub mod index;
+pub mod inventory;
 pub mod metadata;
 pub mod metrics;
+pub mod source;
+pub mod visit;
+pub mod write;
 
-use std::fs;
-use std::fs::ReadDir;
 use std::io;
+use std::io::Read;
 use std::path::Path;
+use std::time::Duration;
 
 use chrono::DateTime;
+use chrono::TimeDelta;
 use chrono::Utc;
 
+use crate::error::Limit;
 use crate::error::MetricParseError;
+use crate::filter::MetricSelector;
+use crate::follow::FollowIterator;
+use crate::index::IndexLocation;
+use crate::inventory::Inventory;
+use crate::metadata::Metadata;
 use crate::metrics::MetricsChunk;
+use crate::read::ColumnarIterator;
 use crate::read::MetricsIterator;
+use crate::read::PeriodicMetadataIterator;
+use crate::read::ReadOptions;
+use crate::read::ReadOrder;
+use crate::source::Source;
+use crate::visit::MetricsVisitor;
 
 /// `DiagnosticData` defines an API for parsing and reading MongoDB diagnostic data.
 #[derive(Debug)]
 pub struct DiagnosticData {
-    entries: ReadDir,
+    source: Source,
     filter: MetricsFilter,
+    options: ReadOptions,
 }
 
 impl DiagnosticData {
     /// Creates a new `DiagnosticData` that will parse and read
     /// the diagnostic data at the specified `path`.
     ///
-    /// The `path` must be valid and point to a directory containing
-    /// the diagnostic data unarchived.
+    /// The `path` must be valid and point either to a directory containing
+    /// the diagnostic data unarchived or to a `.tar.gz` or `.tgz` archive
+    /// containing the diagnostic data, e.g. as downloaded from the Cloud Manager.
+    /// The archive is read without extracting it to disk.
     pub fn new(path: &Path) -> Result<Self, io::Error> {
-        let entries = fs::read_dir(path)?;
-        let filter = MetricsFilter::default();
+        let source = Source::path(path)?;
 
-        Ok(Self { entries, filter })
+        Ok(Self::from_source(source))
     }
 
     /// Creates a new `DiagnosticData` that will parse and read
     /// the diagnostic data at the specified `path` and filter it
     /// according to the `filter` specification.
     ///
-    /// The `path` must be valid and point to a directory containing
-    /// the diagnostic data unarchived.
+    /// The `path` must be valid and point either to a directory containing
+    /// the diagnostic data unarchived or to a `.tar.gz` or `.tgz` archive
+    /// containing the diagnostic data.
     pub fn filter(path: &Path, filter: MetricsFilter) -> Result<Self, io::Error> {
-        let entries = fs::read_dir(path)?;
+        let source = Source::path(path)?;
+
+        Ok(Self::from_source(source).with_filter(filter))
+    }
+
+    /// Creates a new `DiagnosticData` that will parse and read
+    /// a single diagnostic data file, e.g. a `metrics.*` file,
+    /// at the specified `path`.
+    pub fn from_file(path: &Path) -> Result<Self, io::Error> {
+        let source = Source::file(path)?;
+
+        Ok(Self::from_source(source))
+    }
+
+    /// Creates a new `DiagnosticData` that will parse and read
+    /// the diagnostic data from the `data` buffer, which holds
+    /// the content of a single diagnostic data file.
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self::from_source(Source::bytes(data))
+    }
 
-        Ok(Self { entries, filter })
+    /// Creates a new `DiagnosticData` that will parse and read
+    /// the diagnostic data from the `reader`, which yields
+    /// the content of a single diagnostic data file.
+    ///
+    /// The diagnostic data of a reader can be read only once,
+    /// hence [`latest`](Self::latest) fails on it.
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Self {
+        Self::from_source(Source::reader(reader))
+    }
+
+    /// Creates a new `DiagnosticData` that will parse and read
+    /// the diagnostic data from the specified [`Source`].
+    pub fn from_source(source: Source) -> Self {
+        let filter = MetricsFilter::default();
+        let options = ReadOptions::default();
+
+        Self {
+            source,
+            filter,
+            options,
+        }
+    }
+
+    /// Filters the diagnostic data according to the `filter` specification.
+    pub fn with_filter(mut self, filter: MetricsFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Sets whether the diagnostic data is read in recovery mode.
+    ///
+    /// By default, a document that cannot be decoded, e.g. due to a corrupted
+    /// length or a truncated write, fails the rest of the file. In recovery mode,
+    /// the reader skips forward to the next document it can decode instead and
+    /// records the skipped [regions], which the iterators report once consumed.
+    ///
+    /// [regions]: crate::source::SkippedRegion
+    pub fn with_recovery(mut self, recover: bool) -> Self {
+        self.options.recover = recover;
+        self
+    }
+
+    /// Sets the [`Limits`] that bound the memory allocated while decoding
+    /// the diagnostic data. The [default](Limits::default) limits are used
+    /// unless set otherwise.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Decodes the metrics chunks on a pool of `threads`, while still yielding
+    /// them in the order they are read, i.e. in timestamp order for each node.
+    ///
+    /// The documents are read sequentially, while their decompression and
+    /// decoding, which take most of the time, run in parallel. At most
+    /// `max_in_flight` chunks, but no less than one per thread, are read ahead
+    /// of the one to be yielded next, which bounds the memory held at once.
+    /// The chunks are decoded on the iterating thread if `threads` is at most one.
+    pub fn with_parallel_decoding(mut self, threads: usize, max_in_flight: usize) -> Self {
+        self.options.decode_threads = threads;
+        self.options.max_chunks_in_flight = max_in_flight;
+        self
+    }
+
+    /// Indexes the diagnostic data files read from a directory, storing
+    /// the [index] at the `location`.
+    ///
+    /// The index records where each document of the files starts, along with
+    /// its timestamp, so that later reads of the same files, e.g. by another
+    /// `DiagnosticData`, seek straight to the documents within the time window
+    /// instead of scanning the files from their start. The files are indexed
+    /// as they are read in full, and indexed anew once they change.
+    ///
+    /// [index]: crate::index
+    pub fn with_index(mut self, location: IndexLocation) -> Self {
+        self.options.index = Some(location);
+        self
+    }
+
+    /// Returns an iterator over the metrics chunks in the [columnar layout],
+    /// which shares the timestamps between the metrics of the same section
+    /// and keeps their values in their raw encoding.
+    ///
+    /// The metrics chunks are the same as the ones yielded
+    /// when iterating over this `DiagnosticData`.
+    ///
+    /// [columnar layout]: crate::metrics::columnar
+    pub fn columnar_chunks(self) -> ColumnarIterator {
+        ColumnarIterator::new(self.source, self.filter, self.options)
+    }
+
+    /// Drives the `visitor` with the metrics of the diagnostic data as they are
+    /// decoded, without building the [metrics chunks](MetricsChunk) at all.
+    ///
+    /// The visitor is driven with the same metrics as the ones yielded when
+    /// iterating over this `DiagnosticData`. The chunks are decoded on the calling
+    /// thread, unless [parallel decoding](Self::with_parallel_decoding) is set,
+    /// in which case each chunk is decoded in the [columnar layout] on the pool
+    /// of threads first, and the visitor is driven with its metrics in order.
+    ///
+    /// [columnar layout]: crate::metrics::columnar
+    ///
+    /// Returns the first error returned by the `visitor`, which stops the visit.
+    pub fn visit<V: MetricsVisitor>(self, visitor: &mut V) -> Result<(), V::Error> {
+        read::visit_metrics(self.source, self.filter, self.options, visitor)
+    }
+
+    /// Returns an iterator that follows the diagnostic data in a directory
+    /// while mongod writes it, yielding the metrics chunks as they are written.
+    ///
+    /// The diagnostic data already written is read first. Then the directory
+    /// is polled every `poll_interval` for new rotated files, the chunks appended
+    /// to them, and the rewrites of the interim file. The samples are yielded
+    /// only once, even though mongod writes them into both the interim file
+    /// and a rotated file. The iterator blocks until the next chunk is written.
+    ///
+    /// The chunks are always decoded on the iterating thread, and the files
+    /// are read neither in recovery mode nor through an index.
+    ///
+    /// Returns an error unless the diagnostic data is read from a directory.
+    pub fn follow(self, poll_interval: Duration) -> Result<FollowIterator, io::Error> {
+        FollowIterator::new(self.source, self.filter, self.options, poll_interval)
+    }
+
+    /// Returns an iterator over the metrics chunks from the newest to the oldest,
+    /// for each node, so that the most recent metrics are read first.
+    ///
+    /// The nodes are read one after the other, in the order of the paths of
+    /// their directories. The files of a directory are read from the newest
+    /// one, and the files that end before the time window starts are not read
+    /// at all. The chunks of each file are held until the file is read through,
+    /// hence the files of an archive, which cannot be read backwards, are held
+    /// in memory. The size of the documents held is bounded by
+    /// [`Limits::with_max_reversed_size`]; the rest of a file over the limit
+    /// is skipped with an error.
+    /// Otherwise the chunks are the same as the ones yielded when iterating
+    /// over this `DiagnosticData`, in the reverse order.
+    pub fn newest_first(mut self) -> MetricsIterator {
+        self.options.order = ReadOrder::Reverse;
+        MetricsIterator::new(self.source, self.filter, self.options)
+    }
+
+    /// Selects the diagnostic data of the `duration` before the newest sample,
+    /// e.g. the last 30 minutes the diagnostic data was captured, regardless
+    /// of how long ago it was captured.
+    ///
+    /// The newest sample is looked up in the newest files of the diagnostic data
+    /// that match the `hostname` and the instances of the `filter` specification,
+    /// which sets the time window of the filter. The chunks that cannot be decoded
+    /// are skipped. The time window is left as is if the diagnostic data holds
+    /// no samples.
+    ///
+    /// Returns an error if the diagnostic data cannot be opened anew, e.g. if it is
+    /// read from a [reader](Self::from_reader), since it cannot be read again.
+    pub fn latest(mut self, duration: Duration) -> Result<Self, MetricParseError> {
+        let source = self.source.reopen()?;
+        let hostname = self.filter.hostname.clone();
+        let instances = self.filter.instances.clone();
+
+        if let Some(newest) = read::newest_sample(source, hostname, instances, self.options.clone())
+        {
+            let duration = TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX);
+            self.filter.start = newest.checked_sub_signed(duration);
+            self.filter.end = Some(newest);
+        }
+
+        Ok(self)
+    }
+
+    /// Returns an [`Inventory`] of the diagnostic data, which lists the processes
+    /// it was captured from, e.g. their host and version, along with the files
+    /// and the time range of each of them.
+    ///
+    /// The inventory is built without decoding the metrics chunks, except for
+    /// the last one of each process, hence it is much faster than iterating over
+    /// the diagnostic data. It covers all the diagnostic data regardless of
+    /// the `filter` specification, since it is meant to find out what to filter.
+    pub fn inventory(self) -> Inventory {
+        read::read_inventory(self.source, self.options)
+    }
+
+    /// Returns an iterator over the [periodic metadata] collected by mongod,
+    /// such as the server parameters or the feature compatibility version,
+    /// which may change while the diagnostic data is being captured.
+    ///
+    /// The periodic metadata is filtered according to the `filter`
+    /// specification of this `DiagnosticData`.
+    ///
+    /// [periodic metadata]: crate::metadata::PeriodicMetadata
+    pub fn periodic_metadata(self) -> PeriodicMetadataIterator {
+        PeriodicMetadataIterator::new(self.source, self.filter, self.options)
     }
 }
 
@@ -130,16 +497,19 @@ impl IntoIterator for DiagnosticData {
     type IntoIter = MetricsIterator;
 
     fn into_iter(self) -> Self::IntoIter {
-        MetricsIterator::new(self.entries, self.filter)
+        MetricsIterator::new(self.source, self.filter, self.options)
     }
 }
 
 /// `MetricsFilter` specifies a filter for the diagnostic data.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct MetricsFilter {
     pub(crate) hostname: Option<String>,
     pub(crate) start: Option<DateTime<Utc>>,
     pub(crate) end: Option<DateTime<Utc>>,
+    pub(crate) metrics: MetricSelector,
+    pub(crate) instances: Vec<InstanceSelector>,
+    pub(crate) trim: bool,
 }
 
 impl MetricsFilter {
@@ -161,6 +531,299 @@ impl MetricsFilter {
             hostname,
             start,
             end,
+            metrics: MetricSelector::default(),
+            instances: Vec::new(),
+            trim: false,
+        }
+    }
+
+    /// Sets whether the metrics chunks are trimmed to the time window.
+    ///
+    /// By default, the metrics chunks that overlap the time window are returned
+    /// whole, hence they may contain measurements outside of it. When trimmed,
+    /// the measurements outside of the time window are removed, and the start
+    /// and end timestamps of the metrics and the chunks are adjusted to the
+    /// remaining measurements.
+    pub fn with_trimming(mut self, trim: bool) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// Selects only the metrics of the processes matching any of the `instances`.
+    /// The metrics of all the processes are selected if no instance is set.
+    ///
+    /// Unlike the `hostname`, which is matched against the host information
+    /// of the metadata documents, the instances are matched against the chunk
+    /// [metadata], so that the processes sharing a host can be told apart.
+    /// The chunks of the other processes are not decoded.
+    ///
+    /// [metadata]: crate::metadata::Metadata
+    pub fn with_instances<I>(mut self, instances: I) -> Self
+    where
+        I: IntoIterator<Item = InstanceSelector>,
+    {
+        self.instances = instances.into_iter().collect();
+        self
+    }
+
+    /// Selects only the metrics whose path matches the `pattern`,
+    /// along with the ones selected by the other include patterns.
+    /// All the metrics are selected if no include pattern is set.
+    ///
+    /// The pattern is matched against the groups of the metric path,
+    /// separated by `.`, e.g. `serverStatus.opcounters.insert`.
+    /// Within a group, `*` matches any sequence of characters, while
+    /// a `**` group matches any number of groups, e.g. `serverStatus.opcounters.*`
+    /// or `**.wiredTiger.**`. The groups that contain `.` can only be matched by `*`.
+    ///
+    /// The metrics that are not selected are still decoded, since they are
+    /// encoded together, but they are not included in the [`MetricsChunk`]s.
+    pub fn include_metrics(mut self, pattern: &str) -> Self {
+        self.metrics.include(pattern);
+        self
+    }
+
+    /// Excludes the metrics whose path matches the `pattern`, even if
+    /// they are selected by an include pattern. The `pattern` has the same
+    /// syntax as the one of [include_metrics](MetricsFilter::include_metrics).
+    pub fn exclude_metrics(mut self, pattern: &str) -> Self {
+        self.metrics.exclude(pattern);
+        self
+    }
+}
+
+/// `InstanceSelector` selects the diagnostic data of the processes that match
+/// all of its criteria. The criteria that are not set match any process.
+///
+/// ```
+/// use mprobe_diagnostics::InstanceSelector;
+///
+/// let primary = InstanceSelector::new().with_host("node-1:27018");
+/// let routers = InstanceSelector::new()
+///     .with_process("mongos")
+///     .with_min_version("7.0")
+///     .with_max_version("8.0");
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InstanceSelector {
+    host: Option<String>,
+    process: Option<String>,
+    replica_set: Option<String>,
+    min_version: Option<String>,
+    max_version: Option<String>,
+}
+
+impl InstanceSelector {
+    /// Creates a new `InstanceSelector` that matches any process.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Selects the processes running on the `host`, specified either as
+    /// `hostname:port`, as reported in `serverStatus.host`, or as `hostname`
+    /// to select the processes on all the ports.
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_owned());
+        self
+    }
+
+    /// Selects the processes of the `process` type, e.g. `mongod` or `mongos`.
+    pub fn with_process(mut self, process: &str) -> Self {
+        self.process = Some(process.to_owned());
+        self
+    }
+
+    /// Selects the members of the `replica_set`.
+    pub fn with_replica_set(mut self, replica_set: &str) -> Self {
+        self.replica_set = Some(replica_set.to_owned());
+        self
+    }
+
+    /// Selects the processes running at least the `version`. The version is compared
+    /// only up to the components given, e.g. `7.0` selects the `7.0.0` version onwards.
+    pub fn with_min_version(mut self, version: &str) -> Self {
+        self.min_version = Some(version.to_owned());
+        self
+    }
+
+    /// Selects the processes running at most the `version`. The version is compared
+    /// only up to the components given, e.g. `7.0` selects all the `7.0.x` versions.
+    pub fn with_max_version(mut self, version: &str) -> Self {
+        self.max_version = Some(version.to_owned());
+        self
+    }
+
+    pub(crate) fn matches(&self, metadata: &Metadata) -> bool {
+        let host = self.host.as_deref();
+        let process = self.process.as_deref();
+        let replica_set = self.replica_set.as_deref();
+        let min_version = self.min_version.as_deref();
+        let max_version = self.max_version.as_deref();
+
+        host.is_none_or(|host| filter::matches_host(&metadata.host, host))
+            && process.is_none_or(|process| metadata.process == process)
+            && replica_set.is_none_or(|rs| metadata.replica_set.as_deref() == Some(rs))
+            && min_version.is_none_or(|v| filter::compare_version(&metadata.version, v).is_ge())
+            && max_version.is_none_or(|v| filter::compare_version(&metadata.version, v).is_le())
+    }
+}
+
+/// `Limits` bounds the memory allocated while decoding the diagnostic data.
+///
+/// The sizes and counts that drive the allocations are read from the diagnostic
+/// data itself, so a corrupted file could otherwise make the decoder allocate
+/// far more memory than available. A value over its limit fails the decoding
+/// with a [`MetricParseError::LimitExceeded`] error.
+///
+/// The default limits are well above what mongod writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub(crate) max_document_size: usize,
+    pub(crate) max_chunk_size: usize,
+    pub(crate) max_metrics_per_chunk: usize,
+    pub(crate) max_samples_per_chunk: usize,
+    pub(crate) max_reversed_size: usize,
+}
+
+impl Limits {
+    /// The default maximum size of a BSON document, in bytes.
+    pub const DEFAULT_MAX_DOCUMENT_SIZE: usize = 16 * 1024 * 1024;
+
+    /// The default maximum decompressed size of a metrics chunk, in bytes.
+    pub const DEFAULT_MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+    /// The default maximum amount of metrics in a metrics chunk.
+    pub const DEFAULT_MAX_METRICS_PER_CHUNK: usize = 50_000;
+
+    /// The default maximum amount of samples in a metrics chunk,
+    /// including the reference sample.
+    pub const DEFAULT_MAX_SAMPLES_PER_CHUNK: usize = 1_000;
+
+    /// The default maximum size of the documents held to read a file
+    /// in reverse order, in bytes.
+    pub const DEFAULT_MAX_REVERSED_SIZE: usize = 256 * 1024 * 1024;
+
+    /// Sets the maximum size of a BSON document read from the diagnostic data files.
+    pub fn with_max_document_size(mut self, size: usize) -> Self {
+        self.max_document_size = size;
+        self
+    }
+
+    /// Sets the maximum size of the decompressed data of a metrics chunk.
+    pub fn with_max_chunk_size(mut self, size: usize) -> Self {
+        self.max_chunk_size = size;
+        self
+    }
+
+    /// Sets the maximum amount of metrics in a metrics chunk.
+    pub fn with_max_metrics_per_chunk(mut self, count: usize) -> Self {
+        self.max_metrics_per_chunk = count;
+        self
+    }
+
+    /// Sets the maximum amount of samples in a metrics chunk,
+    /// including the reference sample.
+    pub fn with_max_samples_per_chunk(mut self, count: usize) -> Self {
+        self.max_samples_per_chunk = count;
+        self
+    }
+
+    /// Sets the maximum size of the documents held to read a file, along with
+    /// the interim file that follows it, in reverse order.
+    pub fn with_max_reversed_size(mut self, size: usize) -> Self {
+        self.max_reversed_size = size;
+        self
+    }
+
+    /// Checks that the `value` is within the `limit`.
+    pub(crate) fn check(&self, limit: Limit, value: usize) -> Result<(), MetricParseError> {
+        let max = match limit {
+            Limit::DocumentSize => self.max_document_size,
+            Limit::ChunkSize => self.max_chunk_size,
+            Limit::MetricsPerChunk => self.max_metrics_per_chunk,
+            Limit::SamplesPerChunk => self.max_samples_per_chunk,
+            Limit::ReversedSize => self.max_reversed_size,
+        };
+
+        if value > max {
+            return Err(MetricParseError::LimitExceeded { limit, max, value });
         }
+
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_document_size: Self::DEFAULT_MAX_DOCUMENT_SIZE,
+            max_chunk_size: Self::DEFAULT_MAX_CHUNK_SIZE,
+            max_metrics_per_chunk: Self::DEFAULT_MAX_METRICS_PER_CHUNK,
+            max_samples_per_chunk: Self::DEFAULT_MAX_SAMPLES_PER_CHUNK,
+            max_reversed_size: Self::DEFAULT_MAX_REVERSED_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dataset::Dataset;
+    use crate::dataset::Instance;
+    use crate::dataset::MergedIterator;
+    use crate::error::KeyAccessError;
+    use crate::error::MetricPathParseError;
+    use crate::error::MetricWriteError;
+    use crate::follow::StopHandle;
+    use crate::metadata::PeriodicMetadata;
+    use crate::metrics::MetricPath;
+    use crate::metrics::columnar::ColumnarChunk;
+    use crate::source::SkippedFile;
+    use crate::source::SkippedRegion;
+    use crate::write::FtdcWriter;
+
+    fn assert_send<T: Send>() {}
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn read_pipeline_can_move_across_threads() {
+        assert_send::<DiagnosticData>();
+        assert_send::<Source>();
+        assert_send::<MetricsIterator>();
+        assert_send::<ColumnarIterator>();
+        assert_send::<PeriodicMetadataIterator>();
+        assert_send::<Dataset>();
+        assert_send::<MergedIterator>();
+        assert_send::<FollowIterator>();
+        assert_send::<FtdcWriter<Vec<u8>>>();
+
+        assert_send_sync::<MetricsFilter>();
+        assert_send_sync::<InstanceSelector>();
+        assert_send_sync::<Limits>();
+        assert_send_sync::<MetricsChunk>();
+        assert_send_sync::<ColumnarChunk>();
+        assert_send_sync::<MetricPath>();
+        assert_send_sync::<Inventory>();
+        assert_send_sync::<Instance>();
+        assert_send_sync::<StopHandle>();
+        assert_send_sync::<PeriodicMetadata>();
+        assert_send_sync::<SkippedFile>();
+        assert_send_sync::<SkippedRegion>();
+        assert_send_sync::<MetricParseError>();
+        assert_send_sync::<MetricWriteError>();
+        assert_send_sync::<KeyAccessError>();
+        assert_send_sync::<MetricPathParseError>();
+    }
+
+    #[test]
+    fn metrics_iterator_reads_on_another_thread() {
+        let diagnostic_data = DiagnosticData::from_bytes(Vec::new());
+
+        let chunks = std::thread::spawn(move || diagnostic_data.into_iter().count())
+            .join()
+            .unwrap();
+
+        assert_eq!(chunks, 0);
     }
 }
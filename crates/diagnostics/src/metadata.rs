@@ -1,6 +1,10 @@
 //! Defines an API for reading the metadata associated with the diagnostic metrics.
 
+use bson::Bson;
 use bson::Document;
+use bson::spec::ElementType;
+use chrono::DateTime;
+use chrono::Utc;
 
 use crate::error::KeyAccessError;
 use crate::error::ValueAccessResultExt;
@@ -17,6 +21,20 @@ pub struct Metadata {
 
     /// Specifies the database version on the node.
     pub version: String,
+
+    /// Specifies the name of the replica set the node is a member of, if any.
+    pub replica_set: Option<String>,
+
+    /// Specifies the build information of the process, if available.
+    pub build_info: Option<BuildInfo>,
+
+    /// Specifies the information about the host and its operating system,
+    /// if available.
+    pub host_info: Option<HostInfo>,
+
+    /// Specifies the command line options the process was started with,
+    /// if available.
+    pub cmd_line_opts: Option<CmdLineOpts>,
 }
 
 impl Metadata {
@@ -25,6 +43,8 @@ impl Metadata {
     const HOST_KEY: &str = "host";
     const PROCESS_KEY: &str = "process";
     const VERSION_KEY: &str = "version";
+    const REPL_SET_STATUS_KEY: &str = "replSetGetStatus";
+    const REPL_SET_KEY: &str = "set";
 
     pub(crate) fn from_reference_document(doc: &Document) -> Result<Metadata, KeyAccessError> {
         // In MongoDB 8.0 a new nested field, common, was introduced,
@@ -52,8 +72,556 @@ impl Metadata {
                 .get_str(Self::VERSION_KEY)
                 .map_value_access_err(Self::VERSION_KEY)?
                 .to_owned(),
+            replica_set: [common, doc]
+                .into_iter()
+                .find_map(|doc| doc.get_document(Self::REPL_SET_STATUS_KEY).ok())
+                .and_then(|status| get_string(status, Self::REPL_SET_KEY)),
+            build_info: None,
+            host_info: None,
+            cmd_line_opts: None,
         };
 
         Ok(metadata)
     }
+
+    pub(crate) fn with_process_info(mut self, process_info: &ProcessInfo) -> Metadata {
+        self.build_info = process_info.build_info.clone();
+        self.host_info = process_info.host_info.clone();
+        self.cmd_line_opts = process_info.cmd_line_opts.clone();
+
+        // The replica set status is not collected on every node, e.g. on mongos,
+        // or before the replica set is initiated.
+        if self.replica_set.is_none() {
+            self.replica_set = self
+                .cmd_line_opts
+                .as_ref()
+                .and_then(|opts| opts.repl_set_name.clone());
+        }
+
+        self
+    }
+}
+
+/// `ProcessInfo` contains the information about the process read
+/// from the metadata document that starts each diagnostic data file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProcessInfo {
+    pub(crate) build_info: Option<BuildInfo>,
+    pub(crate) host_info: Option<HostInfo>,
+    pub(crate) cmd_line_opts: Option<CmdLineOpts>,
+}
+
+impl ProcessInfo {
+    const COMMON_KEY: &str = "common";
+    const BUILD_INFO_KEY: &str = "buildInfo";
+    const HOST_INFO_KEY: &str = "hostInfo";
+    const CMD_LINE_OPTS_KEY: &str = "getCmdLineOpts";
+
+    /// Reads the process information from the metadata document.
+    ///
+    /// The parts of the metadata that are missing or that do not have
+    /// the expected structure are skipped, so that the diagnostic metrics
+    /// can still be read.
+    pub(crate) fn from_metadata_document(doc: &Document) -> ProcessInfo {
+        // In MongoDB 8.0 a new nested field, common, was introduced,
+        // and we have to account for it as well until all the previous
+        // versions are no longer supported.
+        let common = match doc.get_document(Self::COMMON_KEY) {
+            Ok(common) => common,
+            Err(_) => doc,
+        };
+
+        let build_info = common
+            .get_document(Self::BUILD_INFO_KEY)
+            .ok()
+            .and_then(|doc| BuildInfo::from_document(doc).ok());
+        let host_info = common
+            .get_document(Self::HOST_INFO_KEY)
+            .ok()
+            .and_then(|doc| HostInfo::from_document(doc).ok());
+        let cmd_line_opts = common
+            .get_document(Self::CMD_LINE_OPTS_KEY)
+            .ok()
+            .map(CmdLineOpts::from_document);
+
+        Self {
+            build_info,
+            host_info,
+            cmd_line_opts,
+        }
+    }
+}
+
+/// `BuildInfo` defines the build information of the process
+/// as reported by the `buildInfo` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildInfo {
+    /// Specifies the database version.
+    pub version: String,
+
+    /// Specifies the commit identifier of the database build.
+    pub git_version: Option<String>,
+
+    /// Specifies the memory allocator, e.g. tcmalloc.
+    pub allocator: Option<String>,
+
+    /// Specifies the JavaScript engine, e.g. mozjs.
+    pub javascript_engine: Option<String>,
+
+    /// Specifies whether the build is a 32-bit or a 64-bit build.
+    pub bits: Option<u32>,
+
+    /// Specifies whether the build is a debug build.
+    pub debug: Option<bool>,
+
+    /// Specifies the maximum size of a BSON object in bytes.
+    pub max_bson_object_size: Option<u64>,
+
+    /// Specifies the storage engines included in the build.
+    pub storage_engines: Vec<String>,
+
+    /// Specifies the modules included in the build, e.g. enterprise.
+    pub modules: Vec<String>,
+}
+
+impl BuildInfo {
+    const VERSION_KEY: &str = "version";
+    const GIT_VERSION_KEY: &str = "gitVersion";
+    const ALLOCATOR_KEY: &str = "allocator";
+    const JAVASCRIPT_ENGINE_KEY: &str = "javascriptEngine";
+    const BITS_KEY: &str = "bits";
+    const DEBUG_KEY: &str = "debug";
+    const MAX_BSON_OBJECT_SIZE_KEY: &str = "maxBsonObjectSize";
+    const STORAGE_ENGINES_KEY: &str = "storageEngines";
+    const MODULES_KEY: &str = "modules";
+
+    pub(crate) fn from_document(doc: &Document) -> Result<BuildInfo, KeyAccessError> {
+        let build_info = Self {
+            version: doc
+                .get_str(Self::VERSION_KEY)
+                .map_value_access_err(Self::VERSION_KEY)?
+                .to_owned(),
+            git_version: get_string(doc, Self::GIT_VERSION_KEY),
+            allocator: get_string(doc, Self::ALLOCATOR_KEY),
+            javascript_engine: get_string(doc, Self::JAVASCRIPT_ENGINE_KEY),
+            bits: get_u64(doc, Self::BITS_KEY).and_then(|b| b.try_into().ok()),
+            debug: doc.get_bool(Self::DEBUG_KEY).ok(),
+            max_bson_object_size: get_u64(doc, Self::MAX_BSON_OBJECT_SIZE_KEY),
+            storage_engines: get_strings(doc, Self::STORAGE_ENGINES_KEY),
+            modules: get_strings(doc, Self::MODULES_KEY),
+        };
+
+        Ok(build_info)
+    }
+}
+
+/// `HostInfo` defines the information about the host and its operating system
+/// as reported by the `hostInfo` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostInfo {
+    /// Specifies the host name.
+    pub hostname: String,
+
+    /// Specifies the CPU architecture, e.g. x86_64.
+    pub cpu_arch: Option<String>,
+
+    /// Specifies the CPU address size in bits.
+    pub cpu_addr_size: Option<u32>,
+
+    /// Specifies the number of logical CPU cores.
+    pub num_cores: Option<u32>,
+
+    /// Specifies the number of physical CPU cores.
+    pub num_physical_cores: Option<u32>,
+
+    /// Specifies the number of CPU sockets.
+    pub num_cpu_sockets: Option<u32>,
+
+    /// Specifies the total amount of system memory in megabytes.
+    pub mem_size_mb: Option<u64>,
+
+    /// Specifies the amount of memory available to the process in megabytes,
+    /// e.g. when it is limited by a cgroup.
+    pub mem_limit_mb: Option<u64>,
+
+    /// Specifies whether NUMA is enabled on the host.
+    pub numa_enabled: Option<bool>,
+
+    /// Specifies the number of NUMA nodes on the host.
+    pub num_numa_nodes: Option<u32>,
+
+    /// Specifies the operating system.
+    pub os: OsInfo,
+}
+
+impl HostInfo {
+    const SYSTEM_KEY: &str = "system";
+    const OS_KEY: &str = "os";
+    const HOSTNAME_KEY: &str = "hostname";
+    const CPU_ARCH_KEY: &str = "cpuArch";
+    const CPU_ADDR_SIZE_KEY: &str = "cpuAddrSize";
+    const NUM_CORES_KEY: &str = "numCores";
+    const NUM_PHYSICAL_CORES_KEY: &str = "numPhysicalCores";
+    const NUM_CPU_SOCKETS_KEY: &str = "numCpuSockets";
+    const MEM_SIZE_MB_KEY: &str = "memSizeMB";
+    const MEM_LIMIT_MB_KEY: &str = "memLimitMB";
+    const NUMA_ENABLED_KEY: &str = "numaEnabled";
+    const NUM_NUMA_NODES_KEY: &str = "numNumaNodes";
+
+    pub(crate) fn from_document(doc: &Document) -> Result<HostInfo, KeyAccessError> {
+        let system = doc
+            .get_document(Self::SYSTEM_KEY)
+            .map_value_access_err(Self::SYSTEM_KEY)?;
+
+        let os = match doc.get_document(Self::OS_KEY) {
+            Ok(os) => OsInfo::from_document(os),
+            Err(_) => OsInfo::default(),
+        };
+
+        let get_u32 = |key| get_u64(system, key).and_then(|v| v.try_into().ok());
+
+        let host_info = Self {
+            hostname: system
+                .get_str(Self::HOSTNAME_KEY)
+                .map_value_access_err(Self::HOSTNAME_KEY)?
+                .to_owned(),
+            cpu_arch: get_string(system, Self::CPU_ARCH_KEY),
+            cpu_addr_size: get_u32(Self::CPU_ADDR_SIZE_KEY),
+            num_cores: get_u32(Self::NUM_CORES_KEY),
+            num_physical_cores: get_u32(Self::NUM_PHYSICAL_CORES_KEY),
+            num_cpu_sockets: get_u32(Self::NUM_CPU_SOCKETS_KEY),
+            mem_size_mb: get_u64(system, Self::MEM_SIZE_MB_KEY),
+            mem_limit_mb: get_u64(system, Self::MEM_LIMIT_MB_KEY),
+            numa_enabled: system.get_bool(Self::NUMA_ENABLED_KEY).ok(),
+            num_numa_nodes: get_u32(Self::NUM_NUMA_NODES_KEY),
+            os,
+        };
+
+        Ok(host_info)
+    }
+}
+
+/// `OsInfo` defines the operating system of the host.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OsInfo {
+    /// Specifies the type of the operating system, e.g. Linux.
+    pub os_type: Option<String>,
+
+    /// Specifies the name of the operating system distribution.
+    pub name: Option<String>,
+
+    /// Specifies the version of the operating system.
+    pub version: Option<String>,
+}
+
+impl OsInfo {
+    const TYPE_KEY: &str = "type";
+    const NAME_KEY: &str = "name";
+    const VERSION_KEY: &str = "version";
+
+    fn from_document(doc: &Document) -> OsInfo {
+        Self {
+            os_type: get_string(doc, Self::TYPE_KEY),
+            name: get_string(doc, Self::NAME_KEY),
+            version: get_string(doc, Self::VERSION_KEY),
+        }
+    }
+}
+
+/// `CmdLineOpts` defines the command line options the process was started with
+/// as reported by the `getCmdLineOpts` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CmdLineOpts {
+    /// Specifies the command line arguments.
+    pub argv: Vec<String>,
+
+    /// Specifies the port the process listens on.
+    pub port: Option<u16>,
+
+    /// Specifies the IP addresses the process binds to.
+    pub bind_ip: Option<String>,
+
+    /// Specifies the name of the replica set the process belongs to.
+    pub repl_set_name: Option<String>,
+
+    /// Specifies the role of the process in a sharded cluster,
+    /// i.e. configsvr or shardsvr.
+    pub cluster_role: Option<String>,
+
+    /// Specifies the directory where the process stores the data.
+    pub db_path: Option<String>,
+
+    /// Specifies the storage engine, e.g. wiredTiger.
+    pub storage_engine: Option<String>,
+
+    /// Specifies the size of the WiredTiger cache in gigabytes.
+    pub cache_size_gb: Option<f64>,
+}
+
+impl CmdLineOpts {
+    const ARGV_KEY: &str = "argv";
+    const PARSED_KEY: &str = "parsed";
+    const PORT_PATH: &[&str] = &["net", "port"];
+    const BIND_IP_PATH: &[&str] = &["net", "bindIp"];
+    const REPL_SET_NAME_PATH: &[&str] = &["replication", "replSetName"];
+    // Older versions, and the --replSet option, use this field instead.
+    const REPL_SET_PATH: &[&str] = &["replication", "replSet"];
+    const CLUSTER_ROLE_PATH: &[&str] = &["sharding", "clusterRole"];
+    const DB_PATH_PATH: &[&str] = &["storage", "dbPath"];
+    const ENGINE_PATH: &[&str] = &["storage", "engine"];
+    const CACHE_SIZE_GB_PATH: &[&str] = &["storage", "wiredTiger", "engineConfig", "cacheSizeGB"];
+
+    pub(crate) fn from_document(doc: &Document) -> CmdLineOpts {
+        let empty = Document::new();
+        let parsed = doc.get_document(Self::PARSED_KEY).unwrap_or(&empty);
+
+        let get_str = |path| {
+            get_path(parsed, path)
+                .and_then(Bson::as_str)
+                .map(str::to_owned)
+        };
+
+        Self {
+            argv: get_strings(doc, Self::ARGV_KEY),
+            port: get_path(parsed, Self::PORT_PATH)
+                .and_then(to_u64)
+                .and_then(|p| p.try_into().ok()),
+            bind_ip: get_str(Self::BIND_IP_PATH),
+            repl_set_name: get_str(Self::REPL_SET_NAME_PATH)
+                .or_else(|| get_str(Self::REPL_SET_PATH)),
+            cluster_role: get_str(Self::CLUSTER_ROLE_PATH),
+            db_path: get_str(Self::DB_PATH_PATH),
+            storage_engine: get_str(Self::ENGINE_PATH),
+            cache_size_gb: get_path(parsed, Self::CACHE_SIZE_GB_PATH).and_then(to_f64),
+        }
+    }
+}
+
+fn get_path<'a>(doc: &'a Document, path: &[&str]) -> Option<&'a Bson> {
+    let (key, path) = path.split_last()?;
+    let mut doc = doc;
+
+    for key in path {
+        doc = doc.get_document(key).ok()?;
+    }
+
+    doc.get(key)
+}
+
+fn get_string(doc: &Document, key: &str) -> Option<String> {
+    doc.get_str(key).ok().map(str::to_owned)
+}
+
+fn get_strings(doc: &Document, key: &str) -> Vec<String> {
+    match doc.get_array(key) {
+        Ok(values) => values
+            .iter()
+            .filter_map(Bson::as_str)
+            .map(str::to_owned)
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn get_u64(doc: &Document, key: &str) -> Option<u64> {
+    doc.get(key).and_then(to_u64)
+}
+
+/// Converts any numeric BSON value to [u64], since the numeric types
+/// of the metadata fields vary across the database versions.
+fn to_u64(value: &Bson) -> Option<u64> {
+    match value.element_type() {
+        ElementType::Int32 => value.as_i32().and_then(|v| v.try_into().ok()),
+        ElementType::Int64 => value.as_i64().and_then(|v| v.try_into().ok()),
+        ElementType::Double => value
+            .as_f64()
+            .filter(|v| v.is_finite() && *v >= 0.0)
+            .map(|v| v as u64),
+        _ => None,
+    }
+}
+
+fn to_f64(value: &Bson) -> Option<f64> {
+    match value.element_type() {
+        ElementType::Int32 => value.as_i32().map(f64::from),
+        ElementType::Int64 => value.as_i64().map(|v| v as f64),
+        ElementType::Double => value.as_f64(),
+        _ => None,
+    }
+}
+
+/// `PeriodicMetadata` defines the metadata that mongod collects periodically,
+/// e.g. the server parameters or the feature compatibility version, which may
+/// change while the diagnostic data is being captured.
+///
+/// mongod writes the full periodic metadata only once and then it writes only
+/// the fields that changed since. The changes are applied to the last full
+/// metadata, so the `metadata` field always reflects the state at `timestamp`.
+#[derive(Debug, Clone)]
+pub struct PeriodicMetadata {
+    /// Specifies the timestamp when the metadata was collected.
+    pub timestamp: DateTime<Utc>,
+
+    /// The full metadata at the specified timestamp.
+    pub metadata: Document,
+
+    /// The metadata fields that changed since the last collection.
+    /// For a full metadata document it is the same as `metadata`.
+    pub changes: Document,
+}
+
+impl PeriodicMetadata {
+    pub(crate) fn new(timestamp: DateTime<Utc>, metadata: Document) -> PeriodicMetadata {
+        Self {
+            timestamp,
+            changes: metadata.clone(),
+            metadata,
+        }
+    }
+
+    /// Creates the periodic metadata at the specified `timestamp`
+    /// by applying the `changes` on top of this metadata.
+    ///
+    /// The changes are written per collector, i.e. a top-level field,
+    /// and each changed field of a collector replaces the previous value.
+    pub(crate) fn apply(&self, timestamp: DateTime<Utc>, changes: Document) -> PeriodicMetadata {
+        let mut metadata = self.metadata.clone();
+
+        for (collector, fields) in changes.iter() {
+            match (metadata.get_mut(collector), fields) {
+                (Some(Bson::Document(previous)), Bson::Document(fields)) => {
+                    for (key, value) in fields {
+                        previous.insert(key, value.clone());
+                    }
+                }
+                _ => {
+                    metadata.insert(collector, fields.clone());
+                }
+            }
+        }
+
+        Self {
+            timestamp,
+            metadata,
+            changes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bson::doc;
+    use chrono::TimeZone;
+
+    fn metadata_document() -> Document {
+        doc! {
+            "buildInfo": {
+                "start": 1,
+                "version": "7.0.12",
+                "gitVersion": "b6513ce0781db6818e24619e8a461eae90bc94fc",
+                "allocator": "tcmalloc",
+                "bits": 64,
+                "debug": false,
+                "maxBsonObjectSize": 16777216,
+                "storageEngines": ["devnull", "wiredTiger"],
+                "modules": [],
+                "end": 1,
+            },
+            "getCmdLineOpts": {
+                "argv": ["/usr/bin/mongod", "--config", "/etc/mongod.conf"],
+                "parsed": {
+                    "net": { "bindIp": "0.0.0.0", "port": 27018 },
+                    "replication": { "replSetName": "rs0" },
+                    "storage": {
+                        "dbPath": "/data/db",
+                        "engine": "wiredTiger",
+                        "wiredTiger": { "engineConfig": { "cacheSizeGB": 1.5 } },
+                    },
+                },
+            },
+            "hostInfo": {
+                "system": {
+                    "hostname": "node-1",
+                    "cpuAddrSize": 64,
+                    "memSizeMB": 7953_i64,
+                    "memLimitMB": 7953_i64,
+                    "numCores": 8,
+                    "numPhysicalCores": 4,
+                    "numCpuSockets": 1,
+                    "cpuArch": "x86_64",
+                    "numaEnabled": false,
+                    "numNumaNodes": 1,
+                },
+                "os": { "type": "Linux", "name": "Ubuntu", "version": "22.04" },
+            },
+        }
+    }
+
+    #[test]
+    fn from_metadata_document_reads_process_info() {
+        let process_info = ProcessInfo::from_metadata_document(&metadata_document());
+
+        let build_info = process_info.build_info.unwrap();
+        assert_eq!(build_info.version, "7.0.12");
+        assert_eq!(build_info.allocator.as_deref(), Some("tcmalloc"));
+        assert_eq!(build_info.bits, Some(64));
+        assert_eq!(build_info.storage_engines, vec!["devnull", "wiredTiger"]);
+
+        let host_info = process_info.host_info.unwrap();
+        assert_eq!(host_info.hostname, "node-1");
+        assert_eq!(host_info.num_cores, Some(8));
+        assert_eq!(host_info.num_physical_cores, Some(4));
+        assert_eq!(host_info.mem_size_mb, Some(7953));
+        assert_eq!(host_info.numa_enabled, Some(false));
+        assert_eq!(host_info.os.os_type.as_deref(), Some("Linux"));
+
+        let cmd_line_opts = process_info.cmd_line_opts.unwrap();
+        assert_eq!(cmd_line_opts.argv.len(), 3);
+        assert_eq!(cmd_line_opts.port, Some(27018));
+        assert_eq!(cmd_line_opts.repl_set_name.as_deref(), Some("rs0"));
+        assert_eq!(cmd_line_opts.storage_engine.as_deref(), Some("wiredTiger"));
+        assert_eq!(cmd_line_opts.cache_size_gb, Some(1.5));
+    }
+
+    #[test]
+    fn from_metadata_document_reads_common_layout() {
+        let doc = doc! { "common": metadata_document() };
+
+        let process_info = ProcessInfo::from_metadata_document(&doc);
+
+        assert_eq!(process_info.build_info.unwrap().version, "7.0.12");
+        assert_eq!(process_info.host_info.unwrap().hostname, "node-1");
+        assert_eq!(process_info.cmd_line_opts.unwrap().port, Some(27018));
+    }
+
+    #[test]
+    fn apply_replaces_changed_collector_fields() {
+        let timestamp = Utc.timestamp_millis_opt(1_000).unwrap();
+        let full = PeriodicMetadata::new(
+            timestamp,
+            doc! {
+                "getParameter": { "start": 1, "featureFlagA": false, "featureFlagB": { "value": 1 } },
+                "featureCompatibilityVersion": { "version": "7.0" },
+            },
+        );
+
+        let timestamp = Utc.timestamp_millis_opt(2_000).unwrap();
+        let changes = doc! {
+            "getParameter": { "featureFlagB": { "enabled": true } },
+            "featureCompatibilityVersion": { "version": "8.0" },
+        };
+
+        let actual = full.apply(timestamp, changes.clone());
+
+        let expected = doc! {
+            "getParameter": { "start": 1, "featureFlagA": false, "featureFlagB": { "enabled": true } },
+            "featureCompatibilityVersion": { "version": "8.0" },
+        };
+
+        assert_eq!(actual.timestamp, timestamp);
+        assert_eq!(actual.metadata, expected);
+        assert_eq!(actual.changes, changes);
+    }
 }
@@ -5,23 +5,34 @@
 //! [metric chunks]: crate::metrics::MetricsChunk
 //! [diagnostic data]: crate::DiagnosticData
 
-mod raw;
+pub mod columnar;
+pub(crate) mod raw;
 
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fmt;
 use std::fmt::Display;
-use std::io::Cursor;
 use std::io::Read;
+use std::ops::RangeBounds;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::OnceLock;
+use std::sync::PoisonError;
 
-use bson::Document;
 use chrono::DateTime;
 use chrono::TimeZone;
 use chrono::Utc;
 
-use crate::bytes;
-use crate::compression;
+use crate::Limits;
 use crate::error::MetricParseError;
+use crate::error::MetricPathParseError;
+use crate::filter::MetricSelector;
 use crate::metadata::Metadata;
-use crate::metrics::raw::MetricParser;
+use crate::metrics::columnar::ColumnarChunk;
+use crate::metrics::columnar::MetricView;
+use crate::metrics::raw::EncodedSamples;
 use crate::metrics::raw::RawMetric;
 
 /// `MetricsChunk` contains a chunk of metrics in a specified time window,
@@ -32,24 +43,39 @@ pub struct MetricsChunk {
     pub metadata: Metadata,
 
     /// A list of diagnostic metrics.
-    pub metrics: Vec<Metric>,
+    metrics: Vec<Metric>,
 
     /// Specifies the timestamp when the recording of these metrics started.
     pub start: DateTime<Utc>,
 
     /// Specifies the timestamp when the recording of these metrics ended.
     pub end: DateTime<Utc>,
+
+    /// Specifies whether the metrics were read from the interim file,
+    /// which mongod keeps rewriting until the samples are written
+    /// into a rotated file. Hence the metrics may still change.
+    pub interim: bool,
+
+    /// The index of the metrics by their paths, built on the first lookup.
+    index: OnceLock<MetricIndex>,
 }
 
 /// `Metric` represents a single diagnostic metric in a specified time window.
 #[derive(Debug, Clone)]
 pub struct Metric {
-    /// Name of the diagnostic metric.
+    /// Name of the diagnostic metric, i.e. its groups joined by a space.
+    ///
+    /// The name is ambiguous if any of the groups contain spaces,
+    /// in which case the [path](Self::path) identifies the metric.
     pub name: Arc<str>,
 
     /// A list of categories that this metric belongs to.
     pub groups: Vec<String>,
 
+    /// Path of the diagnostic metric, which is shared by all
+    /// the metrics with the same groups that are read together.
+    pub path: MetricPath,
+
     /// A list of metric measurements.
     pub measurements: Vec<Measurement>,
 
@@ -60,6 +86,193 @@ pub struct Metric {
     pub end: DateTime<Utc>,
 }
 
+/// `MetricPath` identifies a diagnostic metric by its groups, i.e. the keys
+/// of the sample document that lead to the metric.
+///
+/// The string form of a path joins its groups with a `.`, escaping the `.`
+/// and `\` characters within the groups with a `\`, which makes it unambiguous.
+///
+/// Cloning a path is cheap, since its groups are shared.
+///
+/// # Examples
+///
+/// ```
+/// use mprobe_diagnostics::metrics::MetricPath;
+///
+/// let path: MetricPath = r"wiredTiger.cache.bytes read into cache\.total".parse().unwrap();
+///
+/// assert_eq!(path.segments(), ["wiredTiger", "cache", "bytes read into cache.total"]);
+/// assert_eq!(path.to_string(), r"wiredTiger.cache.bytes read into cache\.total");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MetricPath(Arc<[String]>);
+
+impl MetricPath {
+    const DELIMITER: char = '.';
+    const ESCAPE: char = '\\';
+
+    /// Creates a new `MetricPath` from its `segments`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no `segments`.
+    pub fn new<I, S>(segments: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let segments: Arc<[String]> = segments.into_iter().map(Into::into).collect();
+        assert!(
+            !segments.is_empty(),
+            "metric path to have at least one segment"
+        );
+
+        Self(segments)
+    }
+
+    /// Returns the segments of the path, i.e. the groups of the metric.
+    pub fn segments(&self) -> &[String] {
+        &self.0
+    }
+
+    /// Returns `true` if the path starts with the segments of the `prefix`.
+    pub fn starts_with(&self, prefix: &MetricPath) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+}
+
+impl Borrow<[String]> for MetricPath {
+    fn borrow(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl Display for MetricPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, segment) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, "{}", Self::DELIMITER)?;
+            }
+
+            for c in segment.chars() {
+                if c == Self::DELIMITER || c == Self::ESCAPE {
+                    write!(f, "{}", Self::ESCAPE)?;
+                }
+
+                write!(f, "{c}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for MetricPath {
+    type Err = MetricPathParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut segment = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                Self::ESCAPE => match chars.next() {
+                    Some(c @ (Self::DELIMITER | Self::ESCAPE)) => segment.push(c),
+                    Some(character) => {
+                        return Err(MetricPathParseError::InvalidEscape { character });
+                    }
+                    None => return Err(MetricPathParseError::TrailingEscape),
+                },
+                Self::DELIMITER => segments.push(std::mem::take(&mut segment)),
+                c => segment.push(c),
+            }
+        }
+        segments.push(segment);
+
+        Ok(Self(segments.into()))
+    }
+}
+
+/// `PathInterner` shares the paths of the metrics with the same groups,
+/// so that they are allocated once for all the chunks that are read.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PathInterner(Arc<Mutex<InternedPaths>>);
+
+impl PathInterner {
+    /// Returns the path of the metric with the `groups`.
+    pub(crate) fn intern(&self, groups: &[String]) -> MetricPath {
+        self.lock().intern(groups)
+    }
+
+    /// Returns the paths of the metrics with the `groups`, e.g. all the metrics
+    /// of a chunk. The paths are interned at once, so that the decoding threads
+    /// hold the lock only briefly.
+    pub(crate) fn intern_all<'a, I>(&self, groups: I) -> Vec<MetricPath>
+    where
+        I: IntoIterator<Item = &'a [String]>,
+    {
+        let mut paths = self.lock();
+        groups
+            .into_iter()
+            .map(|groups| paths.intern(groups))
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, InternedPaths> {
+        // The paths remain consistent even if a thread panicked while interning.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Debug, Default)]
+struct InternedPaths(HashSet<MetricPath>);
+
+impl InternedPaths {
+    fn intern(&mut self, groups: &[String]) -> MetricPath {
+        if let Some(path) = self.0.get(groups) {
+            return path.clone();
+        }
+
+        let path = MetricPath(Arc::from(groups));
+        self.0.insert(path.clone());
+        path
+    }
+}
+
+/// The positions of the metrics of a chunk, sorted by their paths,
+/// so that the metrics sharing a prefix are next to each other.
+#[derive(Debug, Clone)]
+pub(crate) struct MetricIndex(Vec<(MetricPath, usize)>);
+
+impl MetricIndex {
+    pub(crate) fn new<'a>(paths: impl Iterator<Item = &'a MetricPath>) -> Self {
+        let mut entries: Vec<(MetricPath, usize)> = paths
+            .enumerate()
+            .map(|(idx, path)| (path.clone(), idx))
+            .collect();
+        entries.sort();
+
+        Self(entries)
+    }
+
+    pub(crate) fn get(&self, path: &MetricPath) -> Option<usize> {
+        self.0
+            .binary_search_by(|(p, _)| p.cmp(path))
+            .ok()
+            .map(|idx| self.0[idx].1)
+    }
+
+    fn starting_with<'a>(&'a self, prefix: &'a MetricPath) -> impl Iterator<Item = usize> + 'a {
+        let start = self.0.partition_point(|(p, _)| p < prefix);
+
+        self.0[start..]
+            .iter()
+            .take_while(|(p, _)| p.starts_with(prefix))
+            .map(|(_, idx)| *idx)
+    }
+}
+
 /// `Measurement` represents a measurement of a metric at a single point in time.
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub struct Measurement {
@@ -118,101 +331,347 @@ impl Display for MetricValue {
     }
 }
 
+/// `ValueType` defines how the raw, 64-bit encoded values
+/// of a metric are converted into [`MetricValue`]s.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ValueType {
+    /// Unsigned 32-bit integer, e.g. the increment of a BSON timestamp.
+    U32,
+
+    /// Signed 32-bit integer.
+    I32,
+
+    /// Signed 64-bit integer.
+    I64,
+
+    /// Floating-point number truncated to a signed 64-bit integer.
+    F64,
+
+    /// Boolean value encoded as 0 or 1.
+    Bool,
+
+    /// Seconds since the Unix epoch, e.g. the time of a BSON timestamp.
+    UnixTime,
+
+    /// Milliseconds since the Unix epoch.
+    UnixTimeMillis,
+}
+
+impl ValueType {
+    /// Converts the raw `value` into a [`MetricValue`] of this type.
+    pub fn convert(&self, value: u64) -> MetricValue {
+        match *self {
+            ValueType::U32 => MetricValue::UInt32(value as u32),
+            ValueType::I32 => MetricValue::Int32(value as i32),
+            ValueType::I64 => MetricValue::Int64(value as i64),
+            ValueType::F64 => MetricValue::Float64(value as i64 as f64),
+            ValueType::Bool => MetricValue::Boolean(value != 0),
+            ValueType::UnixTime => MetricValue::DateTime(
+                Utc.timestamp_opt(value as i64, 0)
+                    .single()
+                    .expect("timestamp to be converted to UTC"),
+            ),
+            ValueType::UnixTimeMillis => MetricValue::DateTime(timestamp_from_millis(value)),
+        }
+    }
+}
+
+/// Converts the raw `value` of a timestamp metric, i.e. the milliseconds
+/// since the Unix epoch, into a timestamp.
+pub(crate) fn timestamp_from_millis(value: u64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(value as i64)
+        .single()
+        .expect("timestamp to be converted to UTC")
+}
+
 impl MetricsChunk {
-    const METRIC_NAME_DELIMITER: &str = " ";
-    const START_TIMESTAMP_METRIC_NAME: &str = "start";
-    const END_TIMESTAMP_METRIC_NAME: &str = "end";
+    /// Creates a new `MetricsChunk` with the `metrics` recorded
+    /// between the `start` and `end` timestamps.
+    pub fn new(
+        metadata: Metadata,
+        metrics: Vec<Metric>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        interim: bool,
+    ) -> Self {
+        Self {
+            metadata,
+            metrics,
+            start,
+            end,
+            interim,
+            index: OnceLock::new(),
+        }
+    }
 
-    pub(crate) fn from_reader<R: Read + ?Sized>(
-        reader: &mut R,
-    ) -> Result<MetricsChunk, MetricParseError> {
-        let data = compression::decompress(reader)?;
-        let mut cursor = Cursor::new(data.as_slice());
+    /// Returns the diagnostic metrics of this chunk.
+    pub fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+
+    /// Returns the diagnostic metrics of this chunk for them to be changed.
+    ///
+    /// The index of the metrics by their paths is dropped,
+    /// and built anew on the next lookup.
+    pub fn metrics_mut(&mut self) -> &mut Vec<Metric> {
+        self.index = OnceLock::new();
+        &mut self.metrics
+    }
 
-        let reference_doc = Document::from_reader(&mut cursor)?;
-        let metrics_count: usize = bytes::read_le_u32(&mut cursor)?.try_into()?;
-        let samples_count: usize = bytes::read_le_u32(&mut cursor)?.try_into()?;
-        let metrics =
-            MetricParser::parse(&reference_doc, &mut cursor, metrics_count, samples_count)?;
+    /// Consumes the chunk, returning its diagnostic metrics.
+    pub fn into_metrics(self) -> Vec<Metric> {
+        self.metrics
+    }
 
-        MetricsChunk::from_raw(metrics, &reference_doc)
+    /// Returns the metric with the given `path`, if any.
+    ///
+    /// The metrics are indexed by their paths on the first lookup.
+    pub fn get(&self, path: &MetricPath) -> Option<&Metric> {
+        self.index().get(path).map(|idx| &self.metrics[idx])
     }
 
+    /// Returns an iterator over the metrics whose paths start with
+    /// the `prefix`, e.g. all the metrics of a section, ordered by their paths.
+    ///
+    /// The metrics are indexed by their paths on the first lookup.
+    pub fn starting_with<'a>(&'a self, prefix: &'a MetricPath) -> impl Iterator<Item = &'a Metric> {
+        self.index()
+            .starting_with(prefix)
+            .map(|idx| &self.metrics[idx])
+    }
+
+    fn index(&self) -> &MetricIndex {
+        self.index
+            .get_or_init(|| MetricIndex::new(self.metrics.iter().map(|m| &m.path)))
+    }
+
+    /// Decodes a metrics chunk from the `reader` straight into the row
+    /// layout, the same way as [`ColumnarChunk::from_reader`] does.
+    pub(crate) fn from_reader<R, F>(
+        reader: &mut R,
+        limits: &Limits,
+        selector: &MetricSelector,
+        paths: &PathInterner,
+        select: F,
+    ) -> Result<Option<MetricsChunk>, MetricParseError>
+    where
+        R: Read + ?Sized,
+        F: FnOnce(Metadata) -> Option<Metadata>,
+    {
+        let Some(samples) = EncodedSamples::from_reader(reader, limits, select)? else {
+            return Ok(None);
+        };
+        let metrics = samples.parse()?;
+
+        MetricsChunk::from_raw(metrics, samples.metadata, selector, paths).map(Some)
+    }
+
+    /// Builds the chunk from the decoded `metrics`, keeping only the ones
+    /// selected by the `selector`. The timestamps are always read, since
+    /// the measurements of the selected metrics depend on them.
     fn from_raw(
         metrics: Vec<RawMetric>,
-        reference_doc: &Document,
+        metadata: Metadata,
+        selector: &MetricSelector,
+        paths: &PathInterner,
     ) -> Result<MetricsChunk, MetricParseError> {
-        let mut metrics_chunk: Vec<Metric> = Vec::with_capacity(metrics.len());
+        let mut selected = Vec::with_capacity(metrics.len());
         let mut chunk_timestamps: Vec<DateTime<Utc>> = Vec::new();
-        let mut timestamps: Vec<DateTime<Utc>> = Vec::new();
+        let mut timestamps: Option<Vec<DateTime<Utc>>> = None;
 
         for metric in metrics.into_iter() {
             if let Some(name) = metric.groups.last()
-                && name == Self::START_TIMESTAMP_METRIC_NAME
+                && name == ColumnarChunk::START_TIMESTAMP_METRIC_NAME
             {
-                let ts = to_timestamps(metric.values).collect();
+                let ts = metric
+                    .values
+                    .into_iter()
+                    .map(timestamp_from_millis)
+                    .collect();
 
                 if metric.groups.len() == 1 {
                     chunk_timestamps = ts;
                 } else {
-                    timestamps = ts;
+                    timestamps = Some(ts);
                 };
 
                 continue;
             }
 
             if let Some(name) = metric.groups.last()
-                && name == Self::END_TIMESTAMP_METRIC_NAME
+                && name == ColumnarChunk::END_TIMESTAMP_METRIC_NAME
             {
                 continue;
             }
 
-            let name: Arc<str> = Arc::from(metric.groups.join(Self::METRIC_NAME_DELIMITER));
+            if !selector.selects(&metric.groups) {
+                continue;
+            }
+
+            let name: Arc<str> =
+                Arc::from(metric.groups.join(ColumnarChunk::METRIC_NAME_DELIMITER));
+            let Some((start, end, timestamps)) = timestamps
+                .as_deref()
+                .and_then(|ts| Some((*ts.first()?, *ts.last()?, ts)))
+            else {
+                return Err(MetricParseError::MetricTimestampNotFound { name });
+            };
+
             let measurements = timestamps
                 .iter()
                 .zip(metric.values)
-                .map(|(start, value)| Measurement {
-                    timestamp: start.to_owned(),
+                .map(|(timestamp, value)| Measurement {
+                    timestamp: *timestamp,
                     value: metric.vtype.convert(value),
                 })
-                .collect::<Vec<Measurement>>();
+                .collect();
 
-            let ts_err = || MetricParseError::MetricTimestampNotFound {
-                name: Arc::clone(&name),
-            };
-            let start = timestamps.first().ok_or_else(ts_err)?.to_owned();
-            let end = timestamps.last().ok_or_else(ts_err)?.to_owned();
+            selected.push((name, metric.groups, measurements, start, end));
+        }
 
-            metrics_chunk.push(Metric {
+        let paths = paths.intern_all(selected.iter().map(|(_, groups, ..)| groups.as_slice()));
+        let metrics_chunk = selected
+            .into_iter()
+            .zip(paths)
+            .map(|((name, groups, measurements, start, end), path)| Metric {
                 name,
-                groups: metric.groups,
+                groups,
+                path,
+                measurements,
                 start,
                 end,
-                measurements,
             })
-        }
+            .collect();
 
         let ts_err = || MetricParseError::MetricTimestampNotFound {
-            name: Arc::from(Self::START_TIMESTAMP_METRIC_NAME),
+            name: Arc::from(ColumnarChunk::START_TIMESTAMP_METRIC_NAME),
         };
-        let start_chunk = chunk_timestamps.first().ok_or_else(ts_err)?.to_owned();
-        let end_chunk = chunk_timestamps.last().ok_or_else(ts_err)?.to_owned();
-
-        let metadata = Metadata::from_reference_document(reference_doc)?;
+        let start = chunk_timestamps.first().ok_or_else(ts_err)?.to_owned();
+        let end = chunk_timestamps.last().ok_or_else(ts_err)?.to_owned();
 
         Ok(MetricsChunk {
-            start: start_chunk,
-            end: end_chunk,
-            metrics: metrics_chunk,
             metadata,
+            metrics: metrics_chunk,
+            start,
+            end,
+            interim: false,
+            index: OnceLock::new(),
         })
     }
+
+    /// Retains only the measurements whose timestamps fall within the `range`
+    /// and adjusts the start and end timestamps accordingly.
+    ///
+    /// Returns `false` if no measurements are left in the chunk.
+    pub(crate) fn retain_within<R: RangeBounds<DateTime<Utc>>>(&mut self, range: &R) -> bool {
+        if range.contains(&self.start) && range.contains(&self.end) {
+            return true;
+        }
+
+        self.metrics.retain_mut(|metric| {
+            metric
+                .measurements
+                .retain(|measurement| range.contains(&measurement.timestamp));
+
+            match (metric.measurements.first(), metric.measurements.last()) {
+                (Some(first), Some(last)) => {
+                    metric.start = first.timestamp;
+                    metric.end = last.timestamp;
+                    true
+                }
+                _ => false,
+            }
+        });
+        self.index = OnceLock::new();
+
+        let start = self.metrics.iter().map(|m| m.start).min();
+        let end = self.metrics.iter().map(|m| m.end).max();
+
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                self.start = start;
+                self.end = end;
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
-fn to_timestamps(values: Vec<u64>) -> impl Iterator<Item = DateTime<Utc>> {
-    values.into_iter().map(|v| {
-        Utc.timestamp_millis_opt(v as i64)
-            .single()
-            .expect("timestamp to be converted to UTC")
-    })
+impl From<ColumnarChunk> for MetricsChunk {
+    fn from(chunk: ColumnarChunk) -> MetricsChunk {
+        MetricsChunk {
+            metrics: chunk.metrics().map(Metric::from).collect(),
+            metadata: chunk.metadata,
+            start: chunk.start,
+            end: chunk.end,
+            interim: chunk.interim,
+            index: OnceLock::new(),
+        }
+    }
+}
+
+impl From<MetricView<'_>> for Metric {
+    fn from(view: MetricView<'_>) -> Metric {
+        Metric {
+            name: view.name(),
+            groups: view.groups().to_vec(),
+            path: view.path().clone(),
+            measurements: view.measurements().collect(),
+            start: view.start(),
+            end: view.end(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metric_path_round_trips_through_string_form() {
+        let cases = [
+            ("serverStatus.uptime", vec!["serverStatus", "uptime"]),
+            (
+                "wiredTiger.cache.bytes read into cache",
+                vec!["wiredTiger", "cache", "bytes read into cache"],
+            ),
+            (r"a\.b.c", vec!["a.b", "c"]),
+            (r"a\\.b", vec![r"a\", "b"]),
+            (r"a\\\.b", vec![r"a\.b"]),
+            ("a..b.", vec!["a", "", "b", ""]),
+            ("", vec![""]),
+        ];
+
+        for (text, segments) in cases {
+            let path: MetricPath = text.parse().unwrap();
+
+            assert_eq!(path.segments(), segments, "{text}");
+            assert_eq!(path.to_string(), text);
+            assert_eq!(path, MetricPath::new(segments));
+        }
+    }
+
+    #[test]
+    fn metric_path_rejects_invalid_escapes() {
+        assert_eq!(
+            r"a\b".parse::<MetricPath>(),
+            Err(MetricPathParseError::InvalidEscape { character: 'b' })
+        );
+        assert_eq!(
+            r"a.b\".parse::<MetricPath>(),
+            Err(MetricPathParseError::TrailingEscape)
+        );
+    }
+
+    #[test]
+    fn metric_path_starts_with_whole_segments() {
+        let path = MetricPath::new(["serverStatus", "connections", "current"]);
+
+        assert!(path.starts_with(&MetricPath::new(["serverStatus"])));
+        assert!(path.starts_with(&path));
+        assert!(!path.starts_with(&MetricPath::new(["server"])));
+        assert!(!path.starts_with(&MetricPath::new(["connections"])));
+    }
 }
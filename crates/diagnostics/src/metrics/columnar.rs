@@ -0,0 +1,338 @@
+//! Defines a columnar layout of the metrics chunks.
+//!
+//! All the metrics of a section of the samples, e.g. `serverStatus`, are
+//! measured at the same timestamps. A [`ColumnarChunk`] therefore keeps
+//! a single timestamp column for each section, shared by its metrics, and
+//! the values of each metric in their raw 64-bit encoding, which makes it
+//! considerably smaller than the equivalent [`MetricsChunk`].
+//!
+//! The metrics are accessed through [`MetricView`]s, which borrow from
+//! the chunk and convert into [`Metric`]s on demand.
+//!
+//! [`MetricsChunk`]: crate::metrics::MetricsChunk
+//! [`Metric`]: crate::metrics::Metric
+
+use std::io::Read;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::Limits;
+use crate::error::MetricParseError;
+use crate::filter::MetricSelector;
+use crate::metadata::Metadata;
+use crate::metrics;
+use crate::metrics::Measurement;
+use crate::metrics::MetricIndex;
+use crate::metrics::MetricPath;
+use crate::metrics::MetricValue;
+use crate::metrics::PathInterner;
+use crate::metrics::ValueType;
+use crate::metrics::raw::EncodedSamples;
+use crate::metrics::raw::RawMetric;
+
+/// `ColumnarChunk` contains a chunk of metrics in a specified time window,
+/// laid out in columns.
+#[derive(Debug, Clone)]
+pub struct ColumnarChunk {
+    /// Metadata associated with all the metrics in this chunk.
+    pub metadata: Metadata,
+
+    /// Specifies the timestamp when the recording of these metrics started.
+    pub start: DateTime<Utc>,
+
+    /// Specifies the timestamp when the recording of these metrics ended.
+    pub end: DateTime<Utc>,
+
+    /// Specifies whether the metrics were read from the interim file,
+    /// which mongod keeps rewriting until the samples are written
+    /// into a rotated file. Hence the metrics may still change.
+    pub interim: bool,
+
+    /// The timestamp columns, one for each section of the samples.
+    sections: Vec<Vec<DateTime<Utc>>>,
+
+    /// The value columns, one for each metric.
+    columns: Vec<Column>,
+
+    /// The index of the columns by their paths, built on the first lookup.
+    index: OnceLock<MetricIndex>,
+}
+
+/// The values of a single metric along with the section they belong to.
+///
+/// The name and the groups of the metric are derived from its path,
+/// which is shared by all the chunks, rather than stored in every column.
+#[derive(Debug, Clone)]
+struct Column {
+    path: MetricPath,
+    value_type: ValueType,
+    section: usize,
+    values: Vec<u64>,
+}
+
+/// `MetricView` is a view of a single metric of a [`ColumnarChunk`].
+#[derive(Debug, Clone, Copy)]
+pub struct MetricView<'a> {
+    column: &'a Column,
+    timestamps: &'a [DateTime<Utc>],
+}
+
+impl<'a> MetricView<'a> {
+    /// Returns the name of the metric, i.e. its groups joined by a space,
+    /// which is built anew on each call.
+    pub fn name(&self) -> Arc<str> {
+        Arc::from(self.groups().join(ColumnarChunk::METRIC_NAME_DELIMITER))
+    }
+
+    /// Returns the categories that the metric belongs to.
+    pub fn groups(&self) -> &'a [String] {
+        self.column.path.segments()
+    }
+
+    /// Returns the path of the metric.
+    pub fn path(&self) -> &'a MetricPath {
+        &self.column.path
+    }
+
+    /// Returns the type of the raw values of the metric.
+    pub fn value_type(&self) -> ValueType {
+        self.column.value_type
+    }
+
+    /// Returns the timestamps of the measurements, which are
+    /// shared by all the metrics of the same section.
+    pub fn timestamps(&self) -> &'a [DateTime<Utc>] {
+        self.timestamps
+    }
+
+    /// Returns the values of the measurements in their raw 64-bit encoding,
+    /// which are converted according to the [value type](Self::value_type).
+    pub fn raw_values(&self) -> &'a [u64] {
+        &self.column.values
+    }
+
+    /// Returns an iterator over the values of the measurements.
+    pub fn values(&self) -> impl ExactSizeIterator<Item = MetricValue> + 'a {
+        let value_type = self.column.value_type;
+        self.column
+            .values
+            .iter()
+            .map(move |v| value_type.convert(*v))
+    }
+
+    /// Returns an iterator over the measurements.
+    pub fn measurements(&self) -> impl Iterator<Item = Measurement> + 'a {
+        self.timestamps
+            .iter()
+            .zip(self.values())
+            .map(|(timestamp, value)| Measurement {
+                timestamp: *timestamp,
+                value,
+            })
+    }
+
+    /// Returns the timestamp when the recording of the metric started.
+    pub fn start(&self) -> DateTime<Utc> {
+        // A metric is never left without measurements.
+        self.timestamps[0]
+    }
+
+    /// Returns the timestamp when the recording of the metric ended.
+    pub fn end(&self) -> DateTime<Utc> {
+        self.timestamps[self.timestamps.len() - 1]
+    }
+}
+
+impl ColumnarChunk {
+    pub(crate) const METRIC_NAME_DELIMITER: &str = " ";
+    pub(crate) const START_TIMESTAMP_METRIC_NAME: &str = "start";
+    pub(crate) const END_TIMESTAMP_METRIC_NAME: &str = "end";
+
+    /// Returns an iterator over the metrics of the chunk.
+    pub fn metrics(&self) -> impl ExactSizeIterator<Item = MetricView<'_>> {
+        self.columns.iter().map(|column| self.view(column))
+    }
+
+    /// Returns the metric with the given `path`, if any.
+    ///
+    /// The metrics are indexed by their paths on the first lookup.
+    pub fn metric(&self, path: &MetricPath) -> Option<MetricView<'_>> {
+        let index = self
+            .index
+            .get_or_init(|| MetricIndex::new(self.columns.iter().map(|c| &c.path)));
+
+        index.get(path).map(|idx| self.view(&self.columns[idx]))
+    }
+
+    fn view<'a>(&'a self, column: &'a Column) -> MetricView<'a> {
+        MetricView {
+            column,
+            timestamps: &self.sections[column.section],
+        }
+    }
+
+    /// Decodes a metrics chunk from the `reader`.
+    ///
+    /// The `select` function completes the metadata read from the reference
+    /// document, and returns `None` if the chunk is not selected, in which case
+    /// its samples are not decoded at all. The paths of the metrics
+    /// are shared through the `paths` interner.
+    pub(crate) fn from_reader<R, F>(
+        reader: &mut R,
+        limits: &Limits,
+        selector: &MetricSelector,
+        paths: &PathInterner,
+        select: F,
+    ) -> Result<Option<ColumnarChunk>, MetricParseError>
+    where
+        R: Read + ?Sized,
+        F: FnOnce(Metadata) -> Option<Metadata>,
+    {
+        let Some(samples) = EncodedSamples::from_reader(reader, limits, select)? else {
+            return Ok(None);
+        };
+        let metrics = samples.parse()?;
+
+        ColumnarChunk::from_raw(metrics, samples.metadata, selector, paths).map(Some)
+    }
+
+    /// Builds the chunk from the decoded `metrics`, keeping only the ones
+    /// selected by the `selector`. The timestamps are always read, since
+    /// the measurements of the selected metrics depend on them.
+    fn from_raw(
+        metrics: Vec<RawMetric>,
+        metadata: Metadata,
+        selector: &MetricSelector,
+        paths: &PathInterner,
+    ) -> Result<ColumnarChunk, MetricParseError> {
+        let mut selected = Vec::with_capacity(metrics.len());
+        let mut sections: Vec<Vec<DateTime<Utc>>> = Vec::new();
+        let mut chunk_timestamps: Vec<DateTime<Utc>> = Vec::new();
+
+        for metric in metrics.into_iter() {
+            if let Some(name) = metric.groups.last()
+                && name == Self::START_TIMESTAMP_METRIC_NAME
+            {
+                let ts = to_timestamps(metric.values).collect();
+
+                if metric.groups.len() == 1 {
+                    chunk_timestamps = ts;
+                } else {
+                    sections.push(ts);
+                };
+
+                continue;
+            }
+
+            if let Some(name) = metric.groups.last()
+                && name == Self::END_TIMESTAMP_METRIC_NAME
+            {
+                continue;
+            }
+
+            if !selector.selects(&metric.groups) {
+                continue;
+            }
+
+            let section = sections
+                .len()
+                .checked_sub(1)
+                .filter(|s| !sections[*s].is_empty())
+                .ok_or_else(|| MetricParseError::MetricTimestampNotFound {
+                    name: Arc::from(metric.groups.join(Self::METRIC_NAME_DELIMITER)),
+                })?;
+
+            selected.push((metric, section));
+        }
+
+        let paths = paths.intern_all(selected.iter().map(|(metric, _)| metric.groups.as_slice()));
+        let columns = selected
+            .into_iter()
+            .zip(paths)
+            .map(|((metric, section), path)| Column {
+                path,
+                value_type: metric.vtype,
+                section,
+                values: metric.values,
+            })
+            .collect();
+
+        let ts_err = || MetricParseError::MetricTimestampNotFound {
+            name: Arc::from(Self::START_TIMESTAMP_METRIC_NAME),
+        };
+        let start = chunk_timestamps.first().ok_or_else(ts_err)?.to_owned();
+        let end = chunk_timestamps.last().ok_or_else(ts_err)?.to_owned();
+
+        Ok(ColumnarChunk {
+            metadata,
+            start,
+            end,
+            interim: false,
+            sections,
+            columns,
+            index: OnceLock::new(),
+        })
+    }
+
+    /// Retains only the measurements whose timestamps fall within the `range`
+    /// and adjusts the start and end timestamps accordingly.
+    ///
+    /// Returns `false` if no measurements are left in the chunk.
+    pub(crate) fn retain_within<R: RangeBounds<DateTime<Utc>>>(&mut self, range: &R) -> bool {
+        if range.contains(&self.start) && range.contains(&self.end) {
+            return true;
+        }
+
+        let retained: Vec<Vec<bool>> = self
+            .sections
+            .iter()
+            .map(|timestamps| timestamps.iter().map(|ts| range.contains(ts)).collect())
+            .collect();
+
+        for column in self.columns.iter_mut() {
+            retain_by(&mut column.values, &retained[column.section]);
+        }
+
+        for (timestamps, retained) in self.sections.iter_mut().zip(&retained) {
+            retain_by(timestamps, retained);
+        }
+
+        self.columns.retain(|column| !column.values.is_empty());
+        self.index = OnceLock::new();
+
+        let sections = &self.sections;
+        let start = self
+            .columns
+            .iter()
+            .filter_map(|c| sections[c.section].first())
+            .min();
+        let end = self
+            .columns
+            .iter()
+            .filter_map(|c| sections[c.section].last())
+            .max();
+
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                self.start = *start;
+                self.end = *end;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Retains the `values` whose counterparts in `retained` are set.
+fn retain_by<T>(values: &mut Vec<T>, retained: &[bool]) {
+    let mut retained = retained.iter();
+    values.retain(|_| retained.next().copied().unwrap_or(false));
+}
+
+fn to_timestamps(values: Vec<u64>) -> impl Iterator<Item = DateTime<Utc>> {
+    values.into_iter().map(metrics::timestamp_from_millis)
+}
@@ -1,33 +1,116 @@
-use core::f64;
 use std::io;
 use std::io::Cursor;
 use std::io::Read;
 
 use bson::Bson;
 use bson::Document;
-use bson::spec::ElementType;
-use chrono::TimeZone;
-use chrono::Utc;
-use rust_decimal::Decimal;
-use rust_decimal::prelude::*;
 
+use crate::Limits;
 use crate::bytes;
+use crate::compression;
+use crate::error::Limit;
 use crate::error::MetricParseError;
-use crate::metrics::MetricValue;
+use crate::metadata::Metadata;
+use crate::metrics::ValueType;
+use crate::number;
+
+/// The decompressed data of a metrics chunk, whose samples are yet to be decoded.
+pub(crate) struct EncodedSamples {
+    pub(crate) metadata: Metadata,
+    reference_doc: Document,
+    data: Vec<u8>,
+    position: u64,
+    metrics_count: usize,
+    samples_count: usize,
+}
+
+impl EncodedSamples {
+    /// Decompresses a metrics chunk from the `reader` and reads its reference document.
+    ///
+    /// The `select` function completes the metadata read from the reference
+    /// document, and returns `None` if the chunk is not selected, in which case
+    /// its samples are not decoded at all.
+    pub(crate) fn from_reader<R, F>(
+        reader: &mut R,
+        limits: &Limits,
+        select: F,
+    ) -> Result<Option<EncodedSamples>, MetricParseError>
+    where
+        R: Read + ?Sized,
+        F: FnOnce(Metadata) -> Option<Metadata>,
+    {
+        let data = compression::decompress(reader, limits.max_chunk_size)?;
+        let mut cursor = Cursor::new(data.as_slice());
+
+        let reference_doc = Document::from_reader(&mut cursor)?;
+        let Some(metadata) = select(Metadata::from_reference_document(&reference_doc)?) else {
+            return Ok(None);
+        };
+
+        let metrics_count: usize = bytes::read_le_u32(&mut cursor)?.try_into()?;
+        let samples_count: usize = bytes::read_le_u32(&mut cursor)?.try_into()?;
+
+        // The samples of all the metrics are allocated upfront,
+        // hence their counts are checked before decoding them.
+        limits.check(Limit::MetricsPerChunk, metrics_count)?;
+        limits.check(Limit::SamplesPerChunk, samples_count.saturating_add(1))?;
+        let position = cursor.position();
+
+        Ok(Some(EncodedSamples {
+            metadata,
+            reference_doc,
+            data,
+            position,
+            metrics_count,
+            samples_count,
+        }))
+    }
+
+    /// Decodes the samples into [`RawMetric`]s.
+    pub(crate) fn parse(&self) -> Result<Vec<RawMetric>, MetricParseError> {
+        let mut collector = RawMetricCollector::new(self.metrics_count, self.samples_count);
+        self.visit(&mut collector)?;
+
+        Ok(collector.metrics)
+    }
+
+    /// Decodes the samples, driving the `visitor` with them.
+    pub(crate) fn visit<V: SampleVisitor>(&self, visitor: &mut V) -> Result<(), V::Error> {
+        let mut cursor = Cursor::new(self.data.as_slice());
+        cursor.set_position(self.position);
+
+        MetricParser::visit(
+            &self.reference_doc,
+            &mut cursor,
+            self.metrics_count,
+            self.samples_count,
+            visitor,
+        )
+    }
+}
 
-pub(super) struct MetricParser;
+pub(crate) struct MetricParser;
 
 impl MetricParser {
-    pub fn parse(
+    /// Decodes the samples of the metrics, driving the `visitor`
+    /// with each metric followed by its values, one metric at a time.
+    pub(crate) fn visit<V: SampleVisitor>(
         reference_doc: &Document,
         data: &mut Cursor<&[u8]>,
         metrics_count: usize,
         samples_count: usize,
-    ) -> Result<Vec<RawMetric>, MetricParseError> {
+        visitor: &mut V,
+    ) -> Result<(), V::Error> {
         let init_values = Self::read_initial_values(reference_doc, metrics_count)?;
-        let metrics = Self::read_samples(data, init_values, samples_count)?;
+        Self::read_samples(data, init_values, samples_count, visitor)
+    }
 
-        Ok(metrics)
+    /// Extracts the metrics of the `document`, in the order they are encoded.
+    pub(crate) fn extract(document: &Document) -> Vec<MetricInitVal> {
+        let mut metrics = Vec::new();
+        Self::select_metrics(document, Vec::new(), &mut metrics);
+
+        metrics
     }
 
     fn read_initial_values(
@@ -45,177 +128,177 @@ impl MetricParser {
         Ok(metrics)
     }
 
+    /// Selects the metrics of the reference document following the rules of mongod's
+    /// `extractMetricsFromDocument`: the numbers are truncated to 64-bit integers,
+    /// the booleans become 0 or 1, the dates their milliseconds since the Unix epoch
+    /// and the timestamps two metrics, their seconds and their increment.
+    /// The arrays are traversed like the documents, keyed by the element index.
+    /// All the other BSON types are ignored.
     fn select_metrics(
         reference_doc: &Document,
         parent_key: Vec<String>,
         metrics: &mut Vec<MetricInitVal>,
     ) {
         for (key, value) in reference_doc {
-            match value.element_type() {
-                ElementType::Int32 => {
-                    let mut parts = parent_key.clone();
-                    parts.push(key.to_owned());
-
-                    metrics.push(MetricInitVal::new(
-                        parts,
-                        ValueType::I32,
-                        value.as_i32().unwrap() as u64,
-                    ));
-                }
-                ElementType::Int64 => {
-                    let mut parts = parent_key.clone();
-                    parts.push(key.to_owned());
-
-                    metrics.push(MetricInitVal::new(
-                        parts,
-                        ValueType::I64,
-                        value.as_i64().unwrap() as u64,
-                    ));
-                }
-                ElementType::Double => {
-                    let mut parts = parent_key.clone();
-                    parts.push(key.to_owned());
-
-                    metrics.push(MetricInitVal::new(
-                        parts,
-                        ValueType::F64,
-                        value.as_f64().unwrap() as u64,
-                    ));
-                }
-                ElementType::Boolean => {
-                    let mut parts = parent_key.clone();
-                    parts.push(key.to_owned());
-
-                    metrics.push(MetricInitVal::new(
-                        parts,
-                        ValueType::Bool,
-                        value.as_bool().unwrap() as u64,
-                    ));
-                }
-                ElementType::Decimal128 => {
-                    let mut parts = parent_key.clone();
-                    parts.push(key.to_owned());
-
-                    let val = match value {
-                        Bson::Decimal128(v) => {
-                            Decimal::from_str(v.to_string().as_str()).unwrap().as_f64()
-                        }
-                        _ => f64::NAN,
-                    };
-
-                    metrics.push(MetricInitVal::new(parts, ValueType::F64, val as u64));
-                }
-                ElementType::DateTime => {
-                    let mut parts = parent_key.clone();
-                    parts.push(key.to_owned());
-
-                    metrics.push(MetricInitVal::new(
-                        parts,
-                        ValueType::UnixTimeMillis,
-                        value.as_datetime().unwrap().timestamp_millis() as u64,
-                    ));
-                }
-                ElementType::Timestamp => {
-                    let mut parts = parent_key.clone();
-                    parts.push(key.to_owned());
-                    parts.push(String::from("time"));
-
-                    metrics.push(MetricInitVal::new(
-                        parts,
-                        ValueType::UnixTime,
-                        value.as_timestamp().unwrap().time as u64,
-                    ));
-
-                    let mut parts = parent_key.clone();
-                    parts.push(key.to_owned());
-                    parts.push(String::from("increment"));
-
-                    metrics.push(MetricInitVal::new(
-                        parts,
-                        ValueType::U32,
-                        value.as_timestamp().unwrap().increment as u64,
-                    ));
-                }
-                ElementType::Array => {
-                    let array = value.as_array().unwrap();
-
-                    for (idx, doc) in array.iter().enumerate() {
-                        if let Some(doc) = doc.as_document() {
-                            let mut parts = parent_key.clone();
-                            parts.push(idx.to_string());
+            Self::select_metric(key, value, &parent_key, metrics);
+        }
+    }
 
-                            Self::select_metrics(doc, parts, metrics);
-                        }
-                    }
-                }
-                ElementType::EmbeddedDocument => {
-                    let mut parts = parent_key.clone();
-                    parts.push(key.to_owned());
+    fn select_metric(
+        key: &str,
+        value: &Bson,
+        parent_key: &[String],
+        metrics: &mut Vec<MetricInitVal>,
+    ) {
+        let mut parts = parent_key.to_vec();
+        parts.push(key.to_owned());
 
-                    Self::select_metrics(value.as_document().unwrap(), parts, metrics);
+        match value {
+            Bson::Int32(value) => {
+                metrics.push(MetricInitVal::new(parts, ValueType::I32, *value as u64));
+            }
+            Bson::Int64(value) => {
+                metrics.push(MetricInitVal::new(parts, ValueType::I64, *value as u64));
+            }
+            Bson::Double(value) => {
+                let value = number::f64_to_i64(*value);
+                metrics.push(MetricInitVal::new(parts, ValueType::F64, value as u64));
+            }
+            Bson::Decimal128(value) => {
+                let value = number::decimal128_to_i64(value);
+                metrics.push(MetricInitVal::new(parts, ValueType::F64, value as u64));
+            }
+            Bson::Boolean(value) => {
+                metrics.push(MetricInitVal::new(parts, ValueType::Bool, *value as u64));
+            }
+            Bson::DateTime(value) => {
+                metrics.push(MetricInitVal::new(
+                    parts,
+                    ValueType::UnixTimeMillis,
+                    value.timestamp_millis() as u64,
+                ));
+            }
+            Bson::Timestamp(value) => {
+                let mut time_parts = parts.clone();
+                time_parts.push(String::from("time"));
+
+                metrics.push(MetricInitVal::new(
+                    time_parts,
+                    ValueType::UnixTime,
+                    value.time as u64,
+                ));
+
+                parts.push(String::from("increment"));
+
+                metrics.push(MetricInitVal::new(
+                    parts,
+                    ValueType::U32,
+                    value.increment as u64,
+                ));
+            }
+            Bson::Array(array) => {
+                for (idx, value) in array.iter().enumerate() {
+                    Self::select_metric(&idx.to_string(), value, &parts, metrics);
                 }
-                _ => continue,
             }
+            Bson::Document(document) => Self::select_metrics(document, parts, metrics),
+            _ => {}
         }
     }
 
-    fn read_samples<R: Read + ?Sized>(
+    /// Reads the samples of the metrics, the first of which is the reference
+    /// document itself, followed by `samples_count` delta-encoded samples.
+    ///
+    /// The deltas are encoded one metric after the other, with the runs
+    /// of zeroes spanning across metrics, hence the values of each metric
+    /// are complete before the ones of the next metric are read.
+    fn read_samples<R, V>(
         reader: &mut R,
         metrics: Vec<MetricInitVal>,
         samples_count: usize,
-    ) -> Result<Vec<RawMetric>, io::Error> {
-        if samples_count == 0 {
-            return Ok(metrics
-                .into_iter()
-                .map(|r| RawMetric::new(r.groups, r.vtype, vec![r.value]))
-                .collect());
-        }
-
-        let metrics_count = metrics.len();
-        let mut samples: Vec<Vec<u64>> = vec![vec![0; samples_count]; metrics_count];
+        visitor: &mut V,
+    ) -> Result<(), V::Error>
+    where
+        R: Read + ?Sized,
+        V: SampleVisitor,
+    {
         let mut zeroes_count: u64 = 0;
 
-        for samples in samples.iter_mut() {
-            for sample in samples.iter_mut() {
-                if zeroes_count != 0 {
-                    *sample = 0;
+        for metric in metrics {
+            let mut value = metric.value;
+            visitor.metric(metric)?;
+            visitor.value(value)?;
+
+            for _ in 0..samples_count {
+                let delta = if zeroes_count != 0 {
                     zeroes_count -= 1;
-                    continue;
-                }
+                    0
+                } else {
+                    let delta = bytes::read_var_u64(reader)?;
+                    if delta == 0 {
+                        zeroes_count = bytes::read_var_u64(reader)?;
+                    }
 
-                let delta = bytes::read_var_u64(reader)?;
-                if delta == 0 {
-                    let zero_count = bytes::read_var_u64(reader)?;
-                    zeroes_count = zero_count;
-                }
+                    delta
+                };
 
-                *sample = delta;
+                value = value.wrapping_add(delta);
+                visitor.value(value)?;
             }
         }
 
-        // Decode delta values
-        for m in 0..metrics_count {
-            samples[m][0] = samples[m][0].wrapping_add(metrics[m].value);
+        Ok(())
+    }
+}
 
-            for s in 1..samples_count {
-                samples[m][s] = samples[m][s].wrapping_add(samples[m][s - 1]);
-            }
+/// `SampleVisitor` receives the samples decoded by the [`MetricParser`],
+/// each metric followed by all its values, in the order they are encoded.
+pub(crate) trait SampleVisitor {
+    type Error: From<io::Error> + From<MetricParseError>;
+
+    fn metric(&mut self, metric: MetricInitVal) -> Result<(), Self::Error>;
+    fn value(&mut self, value: u64) -> Result<(), Self::Error>;
+}
+
+/// Collects the samples into [`RawMetric`]s.
+struct RawMetricCollector {
+    metrics: Vec<RawMetric>,
+    samples_count: usize,
+}
+
+impl RawMetricCollector {
+    fn new(metrics_count: usize, samples_count: usize) -> Self {
+        Self {
+            metrics: Vec::with_capacity(metrics_count),
+            samples_count,
         }
+    }
+}
 
-        let samples: Vec<RawMetric> = samples
-            .into_iter()
-            .zip(metrics)
-            .map(|(s, m)| RawMetric::new(m.groups, m.vtype, s))
-            .collect();
+impl SampleVisitor for RawMetricCollector {
+    type Error = MetricParseError;
+
+    fn metric(&mut self, metric: MetricInitVal) -> Result<(), Self::Error> {
+        let values = Vec::with_capacity(self.samples_count + 1);
+        self.metrics
+            .push(RawMetric::new(metric.groups, metric.vtype, values));
+
+        Ok(())
+    }
+
+    fn value(&mut self, value: u64) -> Result<(), Self::Error> {
+        if let Some(metric) = self.metrics.last_mut() {
+            metric.values.push(value);
+        }
 
-        Ok(samples)
+        Ok(())
     }
 }
 
-pub(super) struct RawMetric {
-    pub(super) groups: Vec<String>,
-    pub(super) vtype: ValueType,
-    pub(super) values: Vec<u64>,
+pub(crate) struct RawMetric {
+    pub(crate) groups: Vec<String>,
+    pub(crate) vtype: ValueType,
+    pub(crate) values: Vec<u64>,
 }
 
 impl RawMetric {
@@ -228,10 +311,10 @@ impl RawMetric {
     }
 }
 
-struct MetricInitVal {
-    groups: Vec<String>,
-    value: u64,
-    vtype: ValueType,
+pub(crate) struct MetricInitVal {
+    pub(crate) groups: Vec<String>,
+    pub(crate) value: u64,
+    pub(crate) vtype: ValueType,
 }
 
 impl MetricInitVal {
@@ -244,36 +327,124 @@ impl MetricInitVal {
     }
 }
 
-#[repr(u8)]
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub(super) enum ValueType {
-    U32,
-    I32,
-    I64,
-    F64,
-    Bool,
-    UnixTime,
-    UnixTimeMillis,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bson::DateTime;
+    use bson::Decimal128;
+    use bson::Timestamp;
+    use bson::doc;
+
+    use crate::metrics::MetricValue;
+
+    fn select_metrics(reference_doc: &Document) -> Vec<(String, ValueType, i64)> {
+        let mut metrics = Vec::new();
+        MetricParser::select_metrics(reference_doc, Vec::new(), &mut metrics);
+
+        metrics
+            .into_iter()
+            .map(|m| (m.groups.join("."), m.vtype, m.value as i64))
+            .collect()
+    }
 
-impl ValueType {
-    pub fn convert(&self, value: u64) -> MetricValue {
-        match *self {
-            ValueType::U32 => MetricValue::UInt32(value as u32),
-            ValueType::I32 => MetricValue::Int32(value as i32),
-            ValueType::I64 => MetricValue::Int64(value as i64),
-            ValueType::F64 => MetricValue::Float64(value as f64),
-            ValueType::Bool => MetricValue::Boolean(value != 0),
-            ValueType::UnixTime => MetricValue::DateTime(
-                Utc.timestamp_opt(value as i64, 0)
-                    .single()
-                    .expect("timestamp to be converted to UTC"),
+    fn decimal(value: &str) -> Decimal128 {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn select_metrics_matches_mongod_extraction() {
+        let cases = [
+            (
+                doc! { "int": 7, "negInt": -7, "long": -9_000_000_000i64 },
+                vec![
+                    ("int", ValueType::I32, 7),
+                    ("negInt", ValueType::I32, -7),
+                    ("long", ValueType::I64, -9_000_000_000),
+                ],
+            ),
+            (
+                doc! { "pos": 2.9, "neg": -2.9, "nan": f64::NAN, "inf": f64::INFINITY },
+                vec![
+                    ("pos", ValueType::F64, 2),
+                    ("neg", ValueType::F64, -2),
+                    ("nan", ValueType::F64, i64::MIN),
+                    ("inf", ValueType::F64, i64::MIN),
+                ],
             ),
-            ValueType::UnixTimeMillis => MetricValue::DateTime(
-                Utc.timestamp_millis_opt(value as i64)
-                    .single()
-                    .expect("timestamp to be converted to UTC"),
+            (
+                doc! {
+                    "half": decimal("2.5"),
+                    "neg": decimal("-7.51"),
+                    "nan": decimal("NaN"),
+                },
+                vec![
+                    ("half", ValueType::F64, 2),
+                    ("neg", ValueType::F64, -8),
+                    ("nan", ValueType::F64, i64::MIN),
+                ],
             ),
+            (
+                doc! {
+                    "yes": true,
+                    "no": false,
+                    "date": DateTime::from_millis(1_730_800_800_123),
+                    "beforeEpoch": DateTime::from_millis(-1),
+                    "ts": Timestamp { time: 1_730_800_800, increment: 3 },
+                },
+                vec![
+                    ("yes", ValueType::Bool, 1),
+                    ("no", ValueType::Bool, 0),
+                    ("date", ValueType::UnixTimeMillis, 1_730_800_800_123),
+                    ("beforeEpoch", ValueType::UnixTimeMillis, -1),
+                    ("ts.time", ValueType::UnixTime, 1_730_800_800),
+                    ("ts.increment", ValueType::U32, 3),
+                ],
+            ),
+            (
+                doc! {
+                    "numbers": [1, 2.5, [3i64, true]],
+                    "docs": [{ "a": 1 }, "skipped", { "b": { "c": 2 } }],
+                    "empty": [],
+                },
+                vec![
+                    ("numbers.0", ValueType::I32, 1),
+                    ("numbers.1", ValueType::F64, 2),
+                    ("numbers.2.0", ValueType::I64, 3),
+                    ("numbers.2.1", ValueType::Bool, 1),
+                    ("docs.0.a", ValueType::I32, 1),
+                    ("docs.2.b.c", ValueType::I32, 2),
+                ],
+            ),
+            (
+                doc! {
+                    "string": "value",
+                    "null": null,
+                    "oid": bson::oid::ObjectId::from_bytes([0; 12]),
+                    "binary": bson::Binary {
+                        subtype: bson::spec::BinarySubtype::Generic,
+                        bytes: vec![1],
+                    },
+                    "nested": { "empty": {}, "value": 1 },
+                },
+                vec![("nested.value", ValueType::I32, 1)],
+            ),
+        ];
+
+        for (reference_doc, expected) in cases {
+            let expected = expected
+                .into_iter()
+                .map(|(name, vtype, value)| (String::from(name), vtype, value))
+                .collect::<Vec<_>>();
+
+            assert_eq!(select_metrics(&reference_doc), expected, "{reference_doc}");
         }
     }
+
+    #[test]
+    fn convert_restores_signed_floats() {
+        let value = number::f64_to_i64(-2.9) as u64;
+
+        assert_eq!(ValueType::F64.convert(value), MetricValue::Float64(-2.0));
+    }
 }
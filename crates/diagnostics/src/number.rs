@@ -0,0 +1,150 @@
+//! Converts the BSON numbers into the 64-bit integers stored by mongod
+//! in the diagnostic data, the same way `BSONElement::numberLong` does.
+
+use std::cmp::Ordering;
+
+use bson::Decimal128;
+
+/// The value returned by the x86-64 conversion instructions, and by the Intel
+/// decimal library that mongod uses, when a number cannot be represented as
+/// a 64-bit integer.
+const INTEGER_INDEFINITE: i64 = i64::MIN;
+
+/// The exponent bias of the Decimal128 format.
+const DECIMAL128_EXPONENT_BIAS: i32 = 6176;
+
+/// The largest coefficient of a canonical Decimal128, i.e. 10^34 - 1.
+const DECIMAL128_MAX_COEFFICIENT: u128 = 9_999_999_999_999_999_999_999_999_999_999_999;
+
+/// Truncates `value` towards zero. NaN and the values out of the `i64` range
+/// convert to [`i64::MIN`], unlike the `as` cast, which saturates them.
+pub(crate) fn f64_to_i64(value: f64) -> i64 {
+    // 2^63 is exactly representable as f64, whereas i64::MAX is not.
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+
+    if value.is_nan() || !(-LIMIT..LIMIT).contains(&value) {
+        return INTEGER_INDEFINITE;
+    }
+
+    value as i64
+}
+
+/// Rounds `value` to the nearest integer, with ties to even. NaN, the infinities
+/// and the values out of the `i64` range convert to [`i64::MIN`].
+pub(crate) fn decimal128_to_i64(value: &Decimal128) -> i64 {
+    let bits = u128::from_le_bytes(value.bytes());
+    let negative = bits >> 127 == 1;
+
+    if (bits >> 125) & 0b11 == 0b11 {
+        // The special values have all the bits of the combination field set.
+        if (bits >> 122) & 0b11111 >= 0b11110 {
+            return INTEGER_INDEFINITE;
+        }
+
+        // The coefficient of the other values in this form is always larger
+        // than the maximum, hence they are non-canonical and equal to zero.
+        return 0;
+    }
+
+    let exponent = ((bits >> 113) & 0x3fff) as i32 - DECIMAL128_EXPONENT_BIAS;
+    let coefficient = bits & ((1 << 113) - 1);
+
+    if coefficient == 0 || coefficient > DECIMAL128_MAX_COEFFICIENT {
+        return 0;
+    }
+
+    let magnitude = if exponent >= 0 {
+        match 10u128
+            .checked_pow(exponent as u32)
+            .and_then(|scale| coefficient.checked_mul(scale))
+        {
+            Some(magnitude) => magnitude,
+            None => return INTEGER_INDEFINITE,
+        }
+    } else {
+        match 10u128.checked_pow(exponent.unsigned_abs()) {
+            Some(scale) => {
+                let quotient = coefficient / scale;
+                let remainder = coefficient % scale;
+
+                match (2 * remainder).cmp(&scale) {
+                    Ordering::Greater => quotient + 1,
+                    Ordering::Equal => quotient + (quotient & 1),
+                    Ordering::Less => quotient,
+                }
+            }
+            // The coefficient is always smaller than half of the scale.
+            None => 0,
+        }
+    };
+
+    let value = if negative {
+        0i128.checked_sub_unsigned(magnitude)
+    } else {
+        i128::try_from(magnitude).ok()
+    };
+
+    value
+        .and_then(|value| i64::try_from(value).ok())
+        .unwrap_or(INTEGER_INDEFINITE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(value: &str) -> Decimal128 {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn f64_to_i64_truncates_and_flags_out_of_range_values() {
+        let cases = [
+            (0.0, 0),
+            (-0.0, 0),
+            (1.9, 1),
+            (-1.9, -1),
+            (-42.5, -42),
+            (-9_223_372_036_854_775_808.0, i64::MIN),
+            (9_223_372_036_854_774_784.0, 9_223_372_036_854_774_784),
+            (9_223_372_036_854_775_808.0, i64::MIN),
+            (f64::INFINITY, i64::MIN),
+            (f64::NEG_INFINITY, i64::MIN),
+            (f64::NAN, i64::MIN),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(f64_to_i64(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn decimal128_to_i64_rounds_half_to_even() {
+        let cases = [
+            ("0", 0),
+            ("-0", 0),
+            ("1", 1),
+            ("-17", -17),
+            ("1.5", 2),
+            ("2.5", 2),
+            ("-2.5", -2),
+            ("-3.5", -4),
+            ("2.5000001", 3),
+            ("0.4999", 0),
+            ("1E+3", 1000),
+            ("12345678901234567890123456789012E-31", 1),
+            ("1E-6176", 0),
+            ("9223372036854775807", i64::MAX),
+            ("-9223372036854775808", i64::MIN),
+            ("9223372036854775808", i64::MIN),
+            ("1E+6111", i64::MIN),
+            ("Infinity", i64::MIN),
+            ("-Infinity", i64::MIN),
+            ("NaN", i64::MIN),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(decimal128_to_i64(&decimal(value)), expected, "{value}");
+        }
+    }
+}
@@ -1,58 +1,102 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::fs;
 use std::fs::File;
 use std::fs::ReadDir;
 use std::io;
+use std::io::BufReader;
 use std::io::Cursor;
-use std::io::ErrorKind;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::iter;
+use std::ops::Bound;
+use std::ops::Range;
+use std::ops::RangeBounds;
+use std::path::Path;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use bson::Document;
+use bson::RawDocument;
+use bson::RawDocumentBuf;
 use bson::error::Error as BsonError;
-use bson::error::ErrorKind as BsonErrorKind;
 use chrono::DateTime;
-use chrono::Duration;
 use chrono::Utc;
 
+use crate::InstanceSelector;
+use crate::Limits;
 use crate::MetricsFilter;
+use crate::bson::DATA_TYPE_KEY;
 use crate::bson::DocumentKind;
+use crate::bson::ID_KEY;
 use crate::bson::ReadDocument;
+use crate::error::ErrorContext;
+use crate::error::Limit;
 use crate::error::MetricParseError;
 use crate::filter::HostnameFilter;
+use crate::filter::MetricSelector;
+use crate::filter::ReverseTimeWindowFilter;
 use crate::filter::TimeWindow;
 use crate::filter::TimeWindowFilter;
+use crate::index;
+use crate::index::FileIndex;
+use crate::index::FileStamp;
+use crate::index::IndexLocation;
+use crate::index::IndexedDocument;
+use crate::index::SharedIndex;
+use crate::inventory::Inventory;
+use crate::inventory::InventoryBuilder;
 use crate::iter::IteratorExt;
+use crate::metadata::Metadata;
+use crate::metadata::PeriodicMetadata;
+use crate::metadata::ProcessInfo;
+use crate::metrics;
 use crate::metrics::MetricsChunk;
+use crate::metrics::PathInterner;
+use crate::metrics::columnar::ColumnarChunk;
+use crate::metrics::raw::EncodedSamples;
+use crate::source::ArchiveReader;
+use crate::source::SharedSkipLog;
+use crate::source::SkipReason;
+use crate::source::SkippedFile;
+use crate::source::SkippedRegion;
+use crate::source::Source;
+use crate::source::SourceFile;
+use crate::source::SourceKind;
+use crate::source::sealed::HasSkipLogs;
+use crate::source::sealed::SkipLogs;
+use crate::visit;
+use crate::visit::ChunkVisit;
+use crate::visit::MetricsVisitor;
+use crate::visit::VisitError;
 
 /// An iterator that reads recursively diagnostic data files from a root directory
-/// identified by a [`std::fs::Path`], decodes metrics from BSON documents
+/// or an archive identified by a [`std::fs::Path`], decodes metrics from BSON documents
 /// and yields [`MetricsChunk`] elements.
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 pub struct MetricsIterator {
-    metric_chunks: Box<dyn Iterator<Item = Result<MetricsChunk, MetricParseError>>>,
+    metric_chunks: Box<dyn Iterator<Item = Result<MetricsChunk, MetricParseError>> + Send>,
+    skip_log: SharedSkipLog,
 }
 
 impl MetricsIterator {
-    pub(crate) fn new(root_dir: ReadDir, filter: MetricsFilter) -> Self {
-        let time_window = Rc::new(TimeWindow::new(filter.start, filter.end));
+    pub(crate) fn new(source: Source, filter: MetricsFilter, options: ReadOptions) -> Self {
+        let (metric_chunks, skip_log) = read_chunks(source, filter, options);
 
-        let traverse_dir = TraverseDir::new(root_dir);
-        let path_sorter = PathSorter::new(traverse_dir);
-        let path_filter = PathFilter::new(path_sorter, time_window.clone());
-
-        let file_reader = FileReader::new(path_filter);
-        let hostname_filter = HostnameFilter::new(file_reader, filter.hostname);
-        let time_window_filter = TimeWindowFilter::new(hostname_filter, time_window.clone());
-
-        let metrics_chunk_filter =
-            time_window_filter.try_filter(|d| d.kind().map(|k| k == DocumentKind::MetricsChunk));
-        let metrics_reader = MetricsChunkReader::new(metrics_chunk_filter);
-        let chunk_filter = metrics_reader
-            .try_filter(move |chunk| Ok(time_window.overlaps(&chunk.start, &chunk.end)));
-        let metric_chunks = Box::new(chunk_filter);
+        Self {
+            metric_chunks,
+            skip_log,
+        }
+    }
+}
 
-        Self { metric_chunks }
+impl HasSkipLogs for MetricsIterator {
+    fn skip_logs(&self) -> SkipLogs<'_> {
+        SkipLogs(vec![&self.skip_log])
     }
 }
 
@@ -65,26 +109,559 @@ impl Iterator for MetricsIterator {
     }
 }
 
+/// An iterator that reads the diagnostic data like a [`MetricsIterator`],
+/// yet yields the metrics in the columnar layout of [`ColumnarChunk`] elements.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub struct ColumnarIterator {
+    metric_chunks: Box<dyn Iterator<Item = Result<ColumnarChunk, MetricParseError>> + Send>,
+    skip_log: SharedSkipLog,
+}
+
+impl ColumnarIterator {
+    pub(crate) fn new(source: Source, filter: MetricsFilter, options: ReadOptions) -> Self {
+        let (metric_chunks, skip_log) = read_chunks(source, filter, options);
+
+        Self {
+            metric_chunks,
+            skip_log,
+        }
+    }
+}
+
+impl HasSkipLogs for ColumnarIterator {
+    fn skip_logs(&self) -> SkipLogs<'_> {
+        SkipLogs(vec![&self.skip_log])
+    }
+}
+
+impl Iterator for ColumnarIterator {
+    type Item = Result<ColumnarChunk, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.metric_chunks.next()
+    }
+}
+
+/// Reads the metrics chunks from the `source` decoded into the layout `C`,
+/// along with the log of the files and the regions skipped while reading them.
+fn read_chunks<C: ChunkLayout>(
+    source: Source,
+    filter: MetricsFilter,
+    options: ReadOptions,
+) -> (
+    Box<dyn Iterator<Item = Result<C, MetricParseError>> + Send>,
+    SharedSkipLog,
+) {
+    let time_window = Arc::new(TimeWindow::new(filter.start, filter.end));
+    let skip_log = SharedSkipLog::default();
+
+    let documents = read_documents(
+        source,
+        filter.hostname.clone(),
+        time_window.clone(),
+        skip_log.clone(),
+        options.clone(),
+    );
+    let metric_chunks = decode_chunks(documents, filter, time_window, &options, false);
+
+    (metric_chunks, skip_log)
+}
+
+/// Decodes the metrics chunks out of the `documents`, selecting the ones
+/// within the `time_window` and the metrics and the instances of the `filter`.
+/// The `documents` are expected to be filtered by the host name already.
+///
+/// The chunks are deduplicated as for following the diagnostic data
+/// as it is written, if `follow` is set, or as for reading them
+/// from the newest to the oldest, if the `options` say so.
+pub(crate) fn decode_chunks<C, D>(
+    documents: D,
+    filter: MetricsFilter,
+    time_window: Arc<TimeWindow>,
+    options: &ReadOptions,
+    follow: bool,
+) -> Box<dyn Iterator<Item = Result<C, MetricParseError>> + Send>
+where
+    C: ChunkLayout,
+    D: Iterator<Item = Result<FileDocument, MetricParseError>> + Send + 'static,
+{
+    let time_window_filter: Box<dyn Iterator<Item = _> + Send> = match options.order {
+        ReadOrder::Forward => Box::new(TimeWindowFilter::new(documents, time_window.clone())),
+        _ => Box::new(ReverseTimeWindowFilter::new(documents, time_window.clone())),
+    };
+
+    let metrics_chunk_filter =
+        time_window_filter.try_filter(|d| d.kind().map(|k| k != DocumentKind::PeriodicMetadata));
+    let encoded_chunks = EncodedChunkReader::new(metrics_chunk_filter);
+    let decoder = ChunkDecoder::new(options.limits, filter.metrics, filter.instances);
+    let decode = move |chunk: Result<EncodedChunk, MetricParseError>| {
+        chunk.and_then(|chunk| decoder.decode::<C>(chunk))
+    };
+
+    // The chunks are read in order, yet they can be decoded in parallel,
+    // since the decoding is where most of the time is spent.
+    let decoded_chunks: Box<dyn Iterator<Item = _> + Send> = if options.decode_threads > 1 {
+        Box::new(encoded_chunks.parallel_map(
+            options.decode_threads,
+            options.max_chunks_in_flight,
+            decode,
+        ))
+    } else {
+        Box::new(encoded_chunks.map(decode))
+    };
+
+    let dedup = match options.order {
+        _ if follow => Dedup::All,
+        ReadOrder::Forward => Dedup::Interim,
+        _ => Dedup::Reverse,
+    };
+    let metrics_reader = MetricsChunkReader::new(decoded_chunks, dedup);
+    let overlap_window = time_window.clone();
+    let chunk_filter = metrics_reader.try_filter(move |chunk| {
+        let (start, end) = chunk.range();
+        Ok(overlap_window.overlaps(&start, &end))
+    });
+
+    if filter.trim {
+        Box::new(chunk_filter.filter_map(move |chunk| match chunk {
+            Ok(mut chunk) => chunk.retain_within(&*time_window).then_some(Ok(chunk)),
+            Err(err) => Some(Err(err)),
+        }))
+    } else {
+        Box::new(chunk_filter)
+    }
+}
+
+/// Drives the `visitor` with the metrics read from the `source`,
+/// the same way they are yielded by the [`MetricsIterator`].
+///
+/// The chunks are decoded straight into the visitor on the calling thread,
+/// unless they are decoded in parallel or read in another order, in which case
+/// they are decoded into [`ColumnarChunk`]s first, the same way they are yielded
+/// by the [`ColumnarIterator`], and the visitor is driven with their metrics.
+pub(crate) fn visit_metrics<V: MetricsVisitor>(
+    source: Source,
+    filter: MetricsFilter,
+    options: ReadOptions,
+    visitor: &mut V,
+) -> Result<(), V::Error> {
+    if options.decode_threads > 1 || options.order != ReadOrder::Forward {
+        let (chunks, skip_log) = read_chunks::<ColumnarChunk>(source, filter, options);
+
+        for chunk in chunks {
+            match chunk {
+                Ok(chunk) => visit::visit_chunk(&chunk, visitor)?,
+                Err(err) => visitor.error(err)?,
+            }
+        }
+
+        return visit_skipped(&skip_log, visitor);
+    }
+
+    let time_window = Arc::new(TimeWindow::new(filter.start, filter.end));
+    let skip_log = SharedSkipLog::default();
+
+    let documents = read_documents(
+        source,
+        filter.hostname,
+        time_window.clone(),
+        skip_log.clone(),
+        options.clone(),
+    );
+    let time_window_filter = TimeWindowFilter::new(documents, time_window.clone());
+
+    let metrics_chunk_filter =
+        time_window_filter.try_filter(|d| d.kind().map(|k| k != DocumentKind::PeriodicMetadata));
+    let encoded_chunks = EncodedChunkReader::new(metrics_chunk_filter);
+    let decoder = ChunkDecoder::new(options.limits, filter.metrics, filter.instances);
+    let mut last_timestamps = HashMap::new();
+
+    for chunk in encoded_chunks {
+        let result = chunk.map_err(VisitError::Parse).and_then(|chunk| {
+            decoder.visit(
+                chunk,
+                &time_window,
+                filter.trim,
+                &mut last_timestamps,
+                visitor,
+            )
+        });
+
+        match result {
+            Ok(()) | Err(VisitError::Skip) => {}
+            Err(VisitError::Parse(err)) => visitor.error(err)?,
+            Err(VisitError::Visitor(err)) => return Err(err),
+        }
+    }
+
+    visit_skipped(&skip_log, visitor)
+}
+
+/// Drives the `visitor` with the files, the regions and the index errors of the `skip_log`.
+fn visit_skipped<V: MetricsVisitor>(
+    skip_log: &SharedSkipLog,
+    visitor: &mut V,
+) -> Result<(), V::Error> {
+    let skip_log = std::mem::take(&mut *skip_log.lock());
+    for file in &skip_log.files {
+        visitor.skipped_file(file)?;
+    }
+    for region in &skip_log.regions {
+        visitor.skipped_region(region)?;
+    }
+    for error in &skip_log.index_errors {
+        visitor.index_error(error)?;
+    }
+
+    Ok(())
+}
+
+/// Builds the [`Inventory`] of the diagnostic data read from the `source`.
+pub(crate) fn read_inventory(source: Source, options: ReadOptions) -> Inventory {
+    let time_window = Arc::new(TimeWindow::new(None, None));
+    let skip_log = SharedSkipLog::default();
+
+    let documents = read_documents(source, None, time_window, skip_log.clone(), options.clone());
+    let mut builder = InventoryBuilder::new(options.limits);
+
+    for document in documents {
+        if let Err(err) = document.and_then(|document| builder.add(document)) {
+            builder.error(err);
+        }
+    }
+
+    let skip_log = std::mem::take(&mut *skip_log.lock());
+    builder.build(skip_log)
+}
+
+/// Returns the time of the newest sample read from the `source`, out of
+/// the newest files of every directory, i.e. of every process, that hold
+/// the metrics of the `hostname` and of the selected `instances`.
+///
+/// The documents and the chunks that cannot be read are skipped, so that
+/// the newest sample is looked up in the older chunks instead.
+pub(crate) fn newest_sample(
+    source: Source,
+    hostname: Option<String>,
+    instances: Vec<InstanceSelector>,
+    mut options: ReadOptions,
+) -> Option<DateTime<Utc>> {
+    options.order = ReadOrder::Newest;
+    let time_window = Arc::new(TimeWindow::default());
+    let skip_log = SharedSkipLog::default();
+
+    let documents = read_documents(source, hostname, time_window, skip_log, options.clone());
+    let mut read_dirs = HashSet::new();
+    let mut newest = None;
+
+    for chunk in EncodedChunkReader::new(documents) {
+        let Ok(EncodedChunk {
+            document,
+            process_info,
+        }) = chunk
+        else {
+            continue;
+        };
+
+        let dir = document.source.dir();
+        if read_dirs.contains(dir) {
+            continue;
+        }
+
+        // The chunks of a directory are read from the newest to the oldest,
+        // yet the newest ones may hold no samples.
+        let select = |metadata| select_instance(&instances, metadata, process_info.as_deref());
+        let samples = document.metrics_chunk().and_then(|data| {
+            EncodedSamples::from_reader(&mut Cursor::new(data), &options.limits, select)
+        });
+        let Some(metrics) = samples.ok().flatten().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+
+        let last = metrics
+            .into_iter()
+            .find(|metric| metric.groups == [ColumnarChunk::START_TIMESTAMP_METRIC_NAME])
+            .and_then(|metric| metric.values.last().copied());
+
+        if let Some(last) = last {
+            let last = metrics::timestamp_from_millis(last);
+            newest = newest.max(Some(last));
+            read_dirs.insert(dir.to_path_buf());
+        }
+    }
+
+    newest
+}
+
+/// Identifies the diagnostic data files found in the `source` and returns their paths
+/// grouped by the directory that holds them, i.e. by the process that generated them.
+/// The files are read only up to their first document, except for the ones
+/// in an archive, which is read through. The sources that are not read from
+/// the file system, e.g. a byte buffer, hold no files.
+pub(crate) fn find_files(
+    source: Source,
+    skip_log: SharedSkipLog,
+    max_document_size: usize,
+) -> Result<BTreeMap<PathBuf, Vec<PathBuf>>, MetricParseError> {
+    let files: Box<dyn Iterator<Item = Result<SourceFile, MetricParseError>>> = match source.kind {
+        SourceKind::Dir(_, root_dir) => Box::new(FileIdentifier::new(
+            TraverseDir::new(root_dir),
+            skip_log,
+            None,
+            max_document_size,
+        )),
+        SourceKind::Files(paths) => Box::new(FileIdentifier::new(
+            paths.into_iter().map(Ok),
+            skip_log,
+            None,
+            max_document_size,
+        )),
+        SourceKind::Archive(path, file) => Box::new(ArchiveReader::from_file(
+            path,
+            file,
+            None,
+            skip_log,
+            max_document_size,
+        )),
+        SourceKind::ArchiveDir(path, file, dir) => Box::new(ArchiveReader::from_file(
+            path,
+            file,
+            Some(dir),
+            skip_log,
+            max_document_size,
+        )),
+        SourceKind::File(path) => {
+            let file = SourceFile::on_disk(FileInfo::from_file(path));
+            Box::new(iter::once(Ok(file)))
+        }
+        SourceKind::Bytes(_) | SourceKind::Reader(_) => Box::new(iter::empty()),
+    };
+
+    let mut dirs = BTreeMap::<PathBuf, Vec<PathBuf>>::new();
+    for file in files {
+        let info = file?.info;
+        dirs.entry(info.dir().to_path_buf())
+            .or_default()
+            .push(info.path().to_path_buf());
+    }
+
+    Ok(dirs)
+}
+
+/// An iterator that reads recursively diagnostic data files from a root directory
+/// or an archive identified by a [`std::fs::Path`] and yields the [`PeriodicMetadata`]
+/// collected by mongod with all the changes applied.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub struct PeriodicMetadataIterator {
+    metadata: Box<dyn Iterator<Item = Result<PeriodicMetadata, MetricParseError>> + Send>,
+    skip_log: SharedSkipLog,
+}
+
+impl PeriodicMetadataIterator {
+    pub(crate) fn new(source: Source, filter: MetricsFilter, options: ReadOptions) -> Self {
+        let time_window = Arc::new(TimeWindow::new(filter.start, filter.end));
+        let skip_log = SharedSkipLog::default();
+
+        let documents = read_documents(
+            source,
+            filter.hostname,
+            time_window.clone(),
+            skip_log.clone(),
+            options,
+        );
+        let periodic_metadata_filter =
+            documents.try_filter(|d| d.kind().map(|k| k == DocumentKind::PeriodicMetadata));
+        let metadata_reader = PeriodicMetadataReader::new(periodic_metadata_filter);
+        let metadata_filter = metadata_reader
+            .try_filter(move |metadata| Ok(time_window.contains(&metadata.timestamp)));
+        let metadata = Box::new(metadata_filter);
+
+        Self { metadata, skip_log }
+    }
+}
+
+impl HasSkipLogs for PeriodicMetadataIterator {
+    fn skip_logs(&self) -> SkipLogs<'_> {
+        SkipLogs(vec![&self.skip_log])
+    }
+}
+
+impl Iterator for PeriodicMetadataIterator {
+    type Item = Result<PeriodicMetadata, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.metadata.next()
+    }
+}
+
+/// `ReadOptions` specifies how the diagnostic data files are read.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ReadOptions {
+    /// Whether the regions of the files that cannot be decoded are skipped,
+    /// instead of failing the rest of the file.
+    pub(crate) recover: bool,
+
+    /// The limits that bound the memory allocated while decoding.
+    pub(crate) limits: Limits,
+
+    /// The amount of threads the metrics chunks are decoded on.
+    /// The chunks are decoded on the iterating thread unless it is over one.
+    pub(crate) decode_threads: usize,
+
+    /// The maximum amount of metrics chunks read ahead of the one
+    /// to be yielded next while decoding in parallel.
+    pub(crate) max_chunks_in_flight: usize,
+
+    /// Where the index of the diagnostic data files is stored, if they are indexed.
+    pub(crate) index: Option<IndexLocation>,
+
+    /// The order in which the metrics chunks are read.
+    pub(crate) order: ReadOrder,
+}
+
+/// `ReadOrder` specifies the order in which the metrics chunks are read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReadOrder {
+    /// From the oldest to the newest.
+    #[default]
+    Forward,
+
+    /// From the newest to the oldest, for each directory.
+    Reverse,
+
+    /// From the newest to the oldest, for each directory, yet only out of
+    /// the newest files of the directory, i.e. the newest rotated file
+    /// and the interim file.
+    Newest,
+}
+
+/// Reads the BSON documents of the diagnostic data files found in `source`
+/// that belong to the specified `hostname`, in the order of the read options.
+/// The files that do not hold diagnostic data, and the regions skipped
+/// in recovery mode, are recorded in the `skip_log`.
+fn read_documents(
+    source: Source,
+    hostname: Option<String>,
+    time_window: Arc<TimeWindow>,
+    skip_log: SharedSkipLog,
+    options: ReadOptions,
+) -> impl Iterator<Item = Result<FileDocument, MetricParseError>> {
+    type Files = Box<dyn Iterator<Item = Result<SourceFile, MetricParseError>> + Send>;
+
+    /// The order of the files found in a source.
+    enum Found {
+        /// The files of a directory, found in no particular order.
+        Unordered,
+
+        /// The files of an archive, sorted for each directory.
+        Stored,
+
+        /// A single file.
+        Single,
+    }
+
+    let index = options.index.map(SharedIndex::new);
+    let max_document_size = options.limits.max_document_size;
+    let (files, found): (Files, Found) = match source.kind {
+        SourceKind::Dir(_, root_dir) => {
+            let paths = TraverseDir::new(root_dir);
+            let identifier =
+                FileIdentifier::new(paths, skip_log.clone(), index.clone(), max_document_size);
+            (Box::new(identifier), Found::Unordered)
+        }
+        SourceKind::Files(paths) => {
+            let paths = paths.into_iter().map(Ok);
+            let identifier =
+                FileIdentifier::new(paths, skip_log.clone(), index.clone(), max_document_size);
+            (Box::new(identifier), Found::Unordered)
+        }
+        SourceKind::Archive(path, file) => {
+            let archive_reader =
+                ArchiveReader::from_file(path, file, None, skip_log.clone(), max_document_size);
+            (Box::new(archive_reader), Found::Stored)
+        }
+        SourceKind::ArchiveDir(path, file, dir) => {
+            let archive_reader = ArchiveReader::from_file(
+                path,
+                file,
+                Some(dir),
+                skip_log.clone(),
+                max_document_size,
+            );
+            (Box::new(archive_reader), Found::Stored)
+        }
+        SourceKind::File(path) => {
+            let file = SourceFile::on_disk(FileInfo::from_file(path));
+            (Box::new(iter::once(Ok(file))), Found::Single)
+        }
+        SourceKind::Bytes(data) => {
+            let file = SourceFile::in_memory(FileInfo::unnamed(), data);
+            (Box::new(iter::once(Ok(file))), Found::Single)
+        }
+        SourceKind::Reader(reader) => {
+            let file = SourceFile::from_reader(FileInfo::unnamed(), reader);
+            (Box::new(iter::once(Ok(file))), Found::Single)
+        }
+    };
+
+    let files: Files = match (found, options.order) {
+        (Found::Single, _) => files,
+        (Found::Stored, ReadOrder::Forward) => {
+            Box::new(PathFilter::new(files, time_window.clone()))
+        }
+        (Found::Unordered, ReadOrder::Forward) => {
+            let path_sorter = PathSorter::new(files);
+            Box::new(PathFilter::new(path_sorter, time_window.clone()))
+        }
+        (_, order) => Box::new(ReversePathSorter::new(
+            files,
+            time_window.clone(),
+            order == ReadOrder::Newest,
+        )),
+    };
+
+    let recovery = options.recover.then(|| skip_log.clone());
+    let mut file_reader = FileReader::new(files, recovery, options.limits);
+    if let Some(index) = index {
+        file_reader = file_reader.with_index(index, time_window, hostname.clone(), skip_log);
+    }
+
+    let documents: Box<dyn Iterator<Item = _> + Send> = match options.order {
+        ReadOrder::Forward => Box::new(file_reader),
+        ReadOrder::Reverse | ReadOrder::Newest => {
+            Box::new(ReverseDocuments::new(file_reader, options.limits))
+        }
+    };
+
+    HostnameFilter::new(documents, hostname)
+}
+
 /// An iterator that traverses recursively a directory tree identified by
-/// a [`std::fs::Path`] and yields [`std::path::PathBuf`] for the contained files only.
+/// a [`std::fs::Path`] and yields the paths of the contained files only.
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 #[derive(Debug)]
-struct TraverseDir {
+pub(crate) struct TraverseDir {
     dirs: Vec<ReadDir>,
 }
 
 impl TraverseDir {
-    fn new(root_dir: ReadDir) -> Self {
+    pub(crate) fn new(root_dir: ReadDir) -> Self {
         let dirs = vec![root_dir];
         Self { dirs }
     }
 }
 
 impl Iterator for TraverseDir {
-    type Item = Result<FileInfo, io::Error>;
+    type Item = Result<PathBuf, MetricParseError>;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
+        let error = |error, path: &Path| {
+            MetricParseError::from(error).with_context(ErrorContext::new(path))
+        };
+
         loop {
             let dir = self.dirs.last_mut()?;
 
@@ -94,25 +671,17 @@ impl Iterator for TraverseDir {
                         if file_type.is_dir() {
                             match fs::read_dir(entry.path()) {
                                 Ok(next_dir) => self.dirs.push(next_dir),
-                                Err(error) => return Some(Err(error)),
+                                Err(err) => return Some(Err(error(err, &entry.path()))),
                             }
                         } else if file_type.is_file() {
-                            // TODO: Process the .interim file last
-                            // For now just skip it.
-                            let path = entry.path();
-                            if path.extension().is_none_or(|e| e == "interim") {
-                                continue;
-                            }
-
-                            let file_info = FileInfo::from(path);
-                            return Some(file_info);
+                            return Some(Ok(entry.path()));
                         } else {
                             continue;
                         }
                     }
-                    Err(error) => return Some(Err(error)),
+                    Err(err) => return Some(Err(error(err, &entry.path()))),
                 },
-                Some(Err(error)) => return Some(Err(error)),
+                Some(Err(err)) => return Some(Err(MetricParseError::from(err))),
                 None => {
                     self.dirs.pop();
                 }
@@ -121,126 +690,345 @@ impl Iterator for TraverseDir {
     }
 }
 
+/// An iterator that identifies the diagnostic data files among the given paths
+/// by their content and yields them as [`SourceFile`]s. The other files
+/// are recorded as skipped.
+///
+/// The files that are indexed are identified from their index instead,
+/// without being opened.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+struct FileIdentifier<I> {
+    iter: I,
+    skip_log: SharedSkipLog,
+    index: Option<SharedIndex>,
+    max_document_size: usize,
+}
+
+impl<I> FileIdentifier<I>
+where
+    I: Iterator<Item = Result<PathBuf, MetricParseError>>,
+{
+    fn new(
+        iter: I,
+        skip_log: SharedSkipLog,
+        index: Option<SharedIndex>,
+        max_document_size: usize,
+    ) -> Self {
+        Self {
+            iter,
+            skip_log,
+            index,
+            max_document_size,
+        }
+    }
+
+    fn identify(&self, path: PathBuf) -> Result<Identified, MetricParseError> {
+        if let Some(file_index) = self.index.as_ref().and_then(|index| index.lookup(&path)) {
+            let (timestamp, interim) = file_index.start();
+            return Ok(Identified::Diagnostic(FileInfo::new(
+                path, timestamp, interim,
+            )));
+        }
+
+        let context = ErrorContext::new(&path);
+        File::open(&path)
+            .and_then(|file| {
+                let mut reader = BufReader::new(file);
+                FileInfo::identify(path, &mut reader, &mut Vec::new(), self.max_document_size)
+            })
+            .map_err(|error| MetricParseError::from(error).with_context(context))
+    }
+}
+
+impl<I> Iterator for FileIdentifier<I>
+where
+    I: Iterator<Item = Result<PathBuf, MetricParseError>>,
+{
+    type Item = Result<SourceFile, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let path = match self.iter.next()? {
+                // The index stored alongside the files is not diagnostic data.
+                Ok(path) if index::is_index_file(&path) => continue,
+                path => path,
+            };
+
+            match path.and_then(|path| self.identify(path)) {
+                Ok(Identified::Diagnostic(info)) => return Some(Ok(SourceFile::on_disk(info))),
+                Ok(Identified::Skipped(file)) => self.skip_log.lock().files.push(file),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+/// The outcome of sniffing the content of a file.
+#[derive(Debug)]
+pub(crate) enum Identified {
+    Diagnostic(FileInfo),
+    Skipped(SkippedFile),
+}
+
 #[derive(Debug)]
-struct FileInfo {
+pub(crate) struct FileInfo {
     path: PathBuf,
     timestamp: DateTime<Utc>,
     uid: u16,
+    interim: bool,
 }
 
 impl FileInfo {
-    pub fn from(path: PathBuf) -> Result<FileInfo, io::Error> {
-        let (timestamp, uid) = match path.extension() {
-            Some(extension) => {
-                let extension = extension.to_str().ok_or_else(|| {
-                    io::Error::new(
-                        ErrorKind::InvalidData,
-                        format!("the file extension ({extension:?}) is not valid UTF-8"),
-                    )
-                })?;
-
-                Self::parse_timestamp_and_uid(extension)
-            }
-            None => {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!("the '{path:?}' path does not have a file extension"),
-                ));
-            }
-        }?;
+    const INTERIM_EXTENSION: &str = "interim";
 
-        Ok(Self {
+    /// Identifies whether the file at `path` holds diagnostic data by reading
+    /// its first BSON document from `reader` into `header`. The files whose first
+    /// document is over `max_document_size` are not read as diagnostic data.
+    ///
+    /// A rotated file starts with a metadata document, whereas the interim file
+    /// starts with a metrics chunk. Both have a date `_id`, which is used to order
+    /// the files. The file name is used only as a hint to break ties.
+    pub(crate) fn identify<R: Read>(
+        path: PathBuf,
+        reader: &mut R,
+        header: &mut Vec<u8>,
+        max_document_size: usize,
+    ) -> Result<Identified, io::Error> {
+        let skipped = |path, reason| Ok(Identified::Skipped(SkippedFile { path, reason }));
+
+        reader.take(4).read_to_end(header)?;
+        if header.is_empty() {
+            return skipped(path, SkipReason::Empty);
+        }
+
+        let size = match <[u8; 4]>::try_from(header.as_slice()) {
+            Ok(size) => i32::from_le_bytes(size),
+            Err(_) => return skipped(path, SkipReason::NotBson),
+        };
+        let size = match usize::try_from(size) {
+            Ok(size) if (5..=max_document_size).contains(&size) => size,
+            _ => return skipped(path, SkipReason::NotBson),
+        };
+
+        reader.take(size as u64 - 4).read_to_end(header)?;
+        if header.len() != size {
+            return skipped(path, SkipReason::NotBson);
+        }
+
+        let document = match RawDocument::from_bytes(header) {
+            Ok(document) => document,
+            Err(_) => return skipped(path, SkipReason::NotBson),
+        };
+
+        let timestamp = document
+            .get(ID_KEY)
+            .ok()
+            .flatten()
+            .and_then(|id| id.as_datetime());
+        let kind = document
+            .get(DATA_TYPE_KEY)
+            .ok()
+            .flatten()
+            .and_then(|kind| kind.as_i32())
+            .and_then(|kind| DocumentKind::try_from(kind).ok());
+
+        let (timestamp, interim) = match (timestamp, kind) {
+            (Some(ts), Some(DocumentKind::Metadata)) => (ts, false),
+            (Some(ts), Some(DocumentKind::MetricsChunk)) => (ts, true),
+            _ => return skipped(path, SkipReason::NotDiagnosticData),
+        };
+
+        Ok(Identified::Diagnostic(Self::new(
+            path,
+            timestamp.to_chrono(),
+            interim,
+        )))
+    }
+
+    /// Creates the `FileInfo` of an identified file that starts
+    /// with a document with the `timestamp`.
+    fn new(path: PathBuf, timestamp: DateTime<Utc>, interim: bool) -> FileInfo {
+        let uid = if interim {
+            u16::MAX
+        } else {
+            Self::name_hint(&path).map_or(0, |(_, uid)| uid)
+        };
+
+        Self {
             path,
             timestamp,
             uid,
-        })
+            interim,
+        }
     }
 
-    fn parse_timestamp_and_uid(extension: &str) -> Result<(DateTime<Utc>, u16), io::Error> {
-        const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H-%M-%S%#z";
+    /// Creates the `FileInfo` of a single file read on its own, which
+    /// may have been renamed, hence its name is used only if it is valid.
+    pub fn from_file(path: PathBuf) -> FileInfo {
+        let interim = path
+            .extension()
+            .is_some_and(|e| e == Self::INTERIM_EXTENSION);
+        let (timestamp, uid) = Self::name_hint(&path).unwrap_or_default();
 
-        match extension.rsplit_once("-") {
-            Some((ts, uid)) => {
-                let ts = DateTime::parse_from_str(ts, TIMESTAMP_FORMAT)
-                    .map_err(|e| {
-                        io::Error::other(format!(
-                            "parsing file extension timestamp ({ts}) failed: {e}"
-                        ))
-                    })?
-                    .with_timezone(&Utc);
-
-                let uid = uid.parse::<u16>().map_err(|e| {
-                    io::Error::other(format!("parsing file extension uid ({uid}) failed: {e}"))
-                })?;
-
-                Ok((ts, uid))
-            }
-            None => Err(io::Error::other(format!(
-                "splitting file extension ({extension}) into ts and uid failed"
-            ))),
+        Self {
+            path,
+            timestamp,
+            uid,
+            interim,
+        }
+    }
+
+    /// Creates the `FileInfo` of a file that was not read from the file system,
+    /// e.g. a byte buffer.
+    pub fn unnamed() -> FileInfo {
+        Self {
+            path: PathBuf::new(),
+            timestamp: DateTime::default(),
+            uid: 0,
+            interim: false,
         }
     }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn is_interim(&self) -> bool {
+        self.interim
+    }
+
+    /// Returns the key that sorts the files in the order they were written,
+    /// with the interim files last.
+    pub(crate) fn order(&self) -> (bool, DateTime<Utc>, u16) {
+        (self.interim, self.timestamp, self.uid)
+    }
+
+    /// Returns the directory that contains the file, which identifies
+    /// the node that generated the diagnostic data.
+    pub(crate) fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new(""))
+    }
+
+    /// Parses the timestamp and the uid that mongod encodes into
+    /// the extension of the rotated files, e.g. `metrics.2024-11-05T10-00-00Z-00000`.
+    fn name_hint(path: &Path) -> Option<(DateTime<Utc>, u16)> {
+        const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H-%M-%S%#z";
+
+        let (ts, uid) = path.extension()?.to_str()?.rsplit_once("-")?;
+        let ts = DateTime::parse_from_str(ts, TIMESTAMP_FORMAT)
+            .ok()?
+            .with_timezone(&Utc);
+        let uid = uid.parse::<u16>().ok()?;
+
+        Some((ts, uid))
+    }
 }
 
-/// An iterator that traverses the given [`std::path::PathBuf`]s
-/// filtering paths based on the file name.
+/// An iterator that traverses the given [`SourceFile`]s filtering out
+/// the ones whose time range does not overlap the time window.
+///
+/// A rotated file holds the samples from its first document up until
+/// the first document of the next file in the same directory. Hence the files
+/// that start after the time window are dropped right away, while the last file
+/// of a directory that starts before the time window is held back until
+/// the next file of the directory shows whether it reaches into the time window.
 /// It assumes the items in the inner iterator are yielded sorted
 /// in ascending order.
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 struct PathFilter<I> {
     iter: I,
-    time_window: Rc<TimeWindow>,
-    time_margin: Duration,
+    time_window: Arc<TimeWindow>,
+    pending: HashMap<PathBuf, SourceFile>,
+    ready: VecDeque<SourceFile>,
 }
 
 impl<I> PathFilter<I>
 where
-    I: Iterator<Item = Result<FileInfo, io::Error>>,
+    I: Iterator<Item = Result<SourceFile, MetricParseError>>,
 {
-    fn new(iter: I, time_window: Rc<TimeWindow>) -> Self {
+    fn new(iter: I, time_window: Arc<TimeWindow>) -> Self {
         Self {
             iter,
             time_window,
-            time_margin: Duration::hours(4),
+            pending: HashMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, file: SourceFile) {
+        let dir = file.info.dir().to_path_buf();
+        let timestamp = file.info.timestamp;
+
+        // The interim file contains only the most recent samples,
+        // which are filtered out later on if they are outside
+        // of the time window.
+        if file.info.interim {
+            self.ready.extend(self.pending.remove(&dir));
+            self.ready.push_back(file);
+            return;
+        }
+
+        if self.time_window.is_after(&timestamp) {
+            return;
+        }
+
+        // Of two files that start before the time window,
+        // the earlier one ends before the later one starts.
+        if self.time_window.is_before(&timestamp) {
+            let file = match self.pending.remove(&dir) {
+                Some(pending) if pending.info.timestamp > timestamp => pending,
+                _ => file,
+            };
+            self.pending.insert(dir, file);
+            return;
         }
+
+        self.ready.extend(self.pending.remove(&dir));
+        self.ready.push_back(file);
     }
 }
 
 impl<I> Iterator for PathFilter<I>
 where
-    I: Iterator<Item = Result<FileInfo, io::Error>>,
+    I: Iterator<Item = Result<SourceFile, MetricParseError>>,
 {
-    type Item = Result<FileInfo, io::Error>;
+    type Item = Result<SourceFile, MetricParseError>;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            match self.iter.next()? {
-                Ok(fi) => {
-                    if self
-                        .time_window
-                        .includes_with_margin(&fi.timestamp, self.time_margin)
-                    {
-                        return Some(Ok(fi));
-                    }
+            if let Some(file) = self.ready.pop_front() {
+                return Some(Ok(file));
+            }
+
+            match self.iter.next() {
+                Some(Ok(file)) => self.push(file),
+                Some(Err(err)) => return Some(Err(err)),
+                None if self.pending.is_empty() => return None,
+                None => {
+                    let mut pending: Vec<SourceFile> =
+                        self.pending.drain().map(|(_, file)| file).collect();
+                    pending.sort_by_key(|file| (file.info.timestamp, file.info.uid));
+                    self.ready.extend(pending);
                 }
-                item => return Some(item),
             }
         }
     }
 }
 
-/// An iterator that traverses the given [`std::path::PathBuf`]s
-/// yielding the paths in sorterd order.
+/// An iterator that traverses the given [`SourceFile`]s
+/// yielding them in sorterd order. The interim files are always
+/// yielded after all the rotated files.
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 struct PathSorter<I> {
     iter: Option<I>,
-    paths: Option<Box<dyn Iterator<Item = Result<FileInfo, io::Error>>>>,
+    paths: Option<Box<dyn Iterator<Item = Result<SourceFile, MetricParseError>> + Send>>,
 }
 
 impl<I> PathSorter<I>
 where
-    I: Iterator<Item = Result<FileInfo, io::Error>>,
+    I: Iterator<Item = Result<SourceFile, MetricParseError>>,
 {
     fn new(iter: I) -> Self {
         Self {
@@ -252,9 +1040,9 @@ where
 
 impl<I> Iterator for PathSorter<I>
 where
-    I: Iterator<Item = Result<FileInfo, io::Error>>,
+    I: Iterator<Item = Result<SourceFile, MetricParseError>>,
 {
-    type Item = Result<FileInfo, io::Error>;
+    type Item = Result<SourceFile, MetricParseError>;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
@@ -265,8 +1053,8 @@ where
                     if let Some(iter) = self.iter.take() {
                         let mut vec = Vec::from_iter(iter);
                         vec.sort_by_cached_key(|key| match key {
-                            Ok(fi) => (fi.timestamp, fi.uid),
-                            Err(_) => (Utc::now(), 0),
+                            Ok(file) => file.info.order(),
+                            Err(_) => (true, Utc::now(), 0),
                         });
 
                         self.paths = Some(Box::new(vec.into_iter()));
@@ -280,108 +1068,2741 @@ where
     }
 }
 
+/// An iterator that traverses the given [`SourceFile`]s yielding them
+/// from the newest to the oldest, one directory after the other.
+///
+/// The directories are yielded in the order of their paths, rather than
+/// by the age of their files, since each directory holds the files of
+/// a single node, so the files are sorted only within each directory.
+///
+/// The interim file of a directory holds the newest samples, yet it is yielded
+/// right after the newest rotated file, whose metadata document describes
+/// the samples of the interim file as well. The files that start after
+/// the time window are dropped, as are the files older than the newest one
+/// that starts before the time window, since they end before it starts.
+/// Only the newest rotated file and the interim file of each directory
+/// are yielded if `newest_only` is set.
 #[must_use = "iterators are lazy and do nothing unless consumed"]
-#[derive(Debug)]
-struct FileReader<I> {
-    iter: I,
-    inner_iter: Option<BsonReader<File>>,
+struct ReversePathSorter<I> {
+    iter: Option<I>,
+    time_window: Arc<TimeWindow>,
+    newest_only: bool,
+    files: std::vec::IntoIter<Result<SourceFile, MetricParseError>>,
 }
 
-impl<I> FileReader<I> {
-    pub fn new(iter: I) -> Self {
+impl<I> ReversePathSorter<I>
+where
+    I: Iterator<Item = Result<SourceFile, MetricParseError>>,
+{
+    fn new(iter: I, time_window: Arc<TimeWindow>, newest_only: bool) -> Self {
         Self {
-            iter,
-            inner_iter: None,
+            iter: Some(iter),
+            time_window,
+            newest_only,
+            files: Vec::new().into_iter(),
         }
     }
-}
 
-impl<I> Iterator for FileReader<I>
-where
-    I: Iterator<Item = Result<FileInfo, io::Error>>,
-{
-    type Item = Result<Document, MetricParseError>;
+    fn sort(&self, iter: I) -> Vec<Result<SourceFile, MetricParseError>> {
+        let mut sorted = Vec::new();
+        let mut dirs = BTreeMap::<PathBuf, (Vec<SourceFile>, Vec<SourceFile>)>::new();
 
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match self.inner_iter {
-                Some(ref mut inner_iter) => match inner_iter.next() {
-                    None => self.inner_iter = None,
-                    item => return item.map(|i| i.map_err(MetricParseError::from)),
-                },
-                None => match self.iter.next()? {
-                    Ok(fi) => match File::open(fi.path) {
-                        Ok(file) => self.inner_iter = Some(BsonReader::new(file)),
-                        Err(err) => return Some(Err(MetricParseError::from(err))),
-                    },
-                    Err(err) => return Some(Err(MetricParseError::from(err))),
-                },
+        for file in iter {
+            match file {
+                Ok(file) => {
+                    let (rotated, interim) = dirs.entry(file.info.dir().to_path_buf()).or_default();
+                    if file.info.interim {
+                        interim.push(file);
+                    } else {
+                        rotated.push(file);
+                    }
+                }
+                Err(err) => sorted.push(Err(err)),
             }
         }
+
+        let time_window = &self.time_window;
+        let within = |file: &SourceFile| !time_window.is_after(&file.info.timestamp);
+        let reaches_start = |file: &SourceFile| time_window.is_before(&file.info.timestamp);
+
+        for (_, (mut rotated, interim)) in dirs {
+            rotated.sort_by_key(|file| Reverse(file.info.order()));
+            let mut rotated = rotated.into_iter().filter(within);
+
+            let newest = rotated.next().into_iter();
+            let mut reached = false;
+            for file in newest.chain(interim.into_iter().filter(within)) {
+                reached |= reaches_start(&file);
+                sorted.push(Ok(file));
+            }
+
+            if self.newest_only {
+                continue;
+            }
+
+            for file in rotated {
+                if reached {
+                    break;
+                }
+
+                reached = reaches_start(&file);
+                sorted.push(Ok(file));
+            }
+        }
+
+        sorted
+    }
+}
+
+impl<I> Iterator for ReversePathSorter<I>
+where
+    I: Iterator<Item = Result<SourceFile, MetricParseError>>,
+{
+    type Item = Result<SourceFile, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(iter) = self.iter.take() {
+            self.files = self.sort(iter).into_iter();
+        }
+
+        self.files.next()
     }
 }
 
-/// An iterator that yields BSON documents fron an underlying [`Read`].
+/// An iterator that reverses the order of the metrics chunks read
+/// by a [`FileReader`] out of the files yielded by a [`ReversePathSorter`].
+///
+/// The documents of a rotated file, along with the ones of the interim file
+/// that follows it, are held back until they are all read. Then the other
+/// documents, e.g. the metadata documents that describe the chunks, are yielded
+/// in the order they were read, followed by the metrics chunks from the newest
+/// to the oldest.
+///
+/// The size of the documents held back is bounded by the [`Limits`]. Once it
+/// is exceeded, the documents held back are dropped with an error, and so are
+/// the rest of the documents of the file.
 #[must_use = "iterators are lazy and do nothing unless consumed"]
-#[derive(Debug, Clone)]
-struct BsonReader<R> {
-    reader: R,
+struct ReverseDocuments<I> {
+    iter: I,
+    limits: Limits,
+    first: Option<Arc<FileInfo>>,
+    group: Vec<FileDocument>,
+    size: usize,
+    exceeded: bool,
+    ready: VecDeque<FileDocument>,
 }
 
-impl<R> BsonReader<R> {
-    fn new(reader: R) -> Self {
-        Self { reader }
+impl<I> ReverseDocuments<I>
+where
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
+{
+    fn new(iter: I, limits: Limits) -> Self {
+        Self {
+            iter,
+            limits,
+            first: None,
+            group: Vec::new(),
+            size: 0,
+            exceeded: false,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Returns whether the `document` belongs to the documents held back.
+    fn is_grouped(&self, document: &FileDocument) -> bool {
+        self.first.as_ref().is_some_and(|first| {
+            Arc::ptr_eq(first, &document.source)
+                || (document.source.interim && document.source.dir() == first.dir())
+        })
+    }
+
+    /// Holds back the `document`, unless the documents held back exceed the limit.
+    fn hold(&mut self, document: FileDocument) -> Result<(), MetricParseError> {
+        if !self.is_grouped(&document) {
+            self.release();
+            self.first = Some(document.source.clone());
+            self.size = 0;
+            self.exceeded = false;
+        }
+
+        if self.exceeded {
+            return Ok(());
+        }
+
+        self.size = self.size.saturating_add(document.document.as_bytes().len());
+        if let Err(err) = self.limits.check(Limit::ReversedSize, self.size) {
+            self.exceeded = true;
+            self.group.clear();
+            return Err(err.with_context(document.context()));
+        }
+
+        self.group.push(document);
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        let (mut chunks, others): (Vec<_>, Vec<_>) = self.group.drain(..).partition(|document| {
+            document
+                .kind()
+                .is_ok_and(|k| k == DocumentKind::MetricsChunk)
+        });
+        chunks.sort_by_key(|document| Reverse(document.timestamp().ok()));
+
+        self.ready.extend(others);
+        self.ready.extend(chunks);
     }
 }
 
-impl<R: Read> Iterator for BsonReader<R> {
-    type Item = Result<Document, BsonError>;
+impl<I> Iterator for ReverseDocuments<I>
+where
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
+{
+    type Item = Result<FileDocument, MetricParseError>;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        match Document::from_reader(&mut self.reader) {
-            Ok(document) => Some(Ok(document)),
-            Err(BsonError {
-                kind: BsonErrorKind::EndOfStream { .. },
-                ..
-            }) => None,
-            Err(error) => Some(Err(error)),
+        loop {
+            if let Some(document) = self.ready.pop_front() {
+                return Some(Ok(document));
+            }
+
+            match self.iter.next() {
+                Some(Ok(document)) => {
+                    if let Err(err) = self.hold(document) {
+                        return Some(Err(err));
+                    }
+                }
+                Some(Err(err)) => return Some(Err(err)),
+                None if self.group.is_empty() => return None,
+                None => self.release(),
+            }
         }
     }
 }
 
-/// An iterator that yields BSON documents fron an underlying [`Read`].
+/// A BSON document read from a diagnostic data file.
+#[derive(Debug)]
+pub(crate) struct FileDocument {
+    pub(crate) document: RawDocumentBuf,
+    pub(crate) source: Arc<FileInfo>,
+    pub(crate) offset: u64,
+}
+
+impl FileDocument {
+    /// Returns the context of the errors that occur while reading the document.
+    pub(crate) fn context(&self) -> ErrorContext {
+        ErrorContext::new(self.source.path())
+            .with_offset(self.offset)
+            .with_timestamp(self.document.timestamp().ok())
+    }
+}
+
+impl ReadDocument for FileDocument {
+    fn kind(&self) -> Result<DocumentKind, MetricParseError> {
+        self.document
+            .kind()
+            .map_err(|e| e.with_context(self.context()))
+    }
+
+    fn timestamp(&self) -> Result<DateTime<Utc>, MetricParseError> {
+        self.document
+            .timestamp()
+            .map_err(|e| e.with_context(self.context()))
+    }
+
+    fn hostname(&self) -> Result<&str, MetricParseError> {
+        self.document
+            .hostname()
+            .map_err(|e| e.with_context(self.context()))
+    }
+
+    fn metrics_chunk(&self) -> Result<&[u8], MetricParseError> {
+        self.document
+            .metrics_chunk()
+            .map_err(|e| e.with_context(self.context()))
+    }
+
+    fn metadata(&self) -> Result<Document, MetricParseError> {
+        self.document
+            .metadata()
+            .map_err(|e| e.with_context(self.context()))
+    }
+
+    fn delta_counter(&self) -> Result<i64, MetricParseError> {
+        self.document
+            .delta_counter()
+            .map_err(|e| e.with_context(self.context()))
+    }
+}
+
+/// An iterator that reads the BSON documents of the given [`SourceFile`]s.
+///
+/// In recovery mode, the regions of the files that cannot be decoded
+/// are skipped and recorded in the given [`SharedSkipLog`].
+///
+/// When the files are indexed, only the documents of the indexed files that are
+/// needed for the time window and the host name are read, while the other files
+/// are read in full and indexed. The index is saved once all the files are read.
 #[must_use = "iterators are lazy and do nothing unless consumed"]
-#[derive(Debug, Clone)]
-struct MetricsChunkReader<I> {
+struct FileReader<I> {
     iter: I,
+    inner_iter: Option<DocumentReader>,
+    recovery: Option<SharedSkipLog>,
+    limits: Limits,
+    index: Option<IndexedRead>,
 }
 
-impl<I> MetricsChunkReader<I>
-where
-    I: Iterator<Item = Result<Document, MetricParseError>>,
-{
-    pub fn new(iter: I) -> Self {
-        Self { iter }
+/// The index of the files read by a [`FileReader`], along with the filters
+/// that select the documents to read from the indexed files.
+///
+/// The index is saved once the files are read through, or once the reader
+/// is dropped before that, e.g. if the iteration stops early. The errors
+/// of saving it are recorded in the skip log.
+struct IndexedRead {
+    index: SharedIndex,
+    time_window: Arc<TimeWindow>,
+    hostname: Option<String>,
+    skip_log: SharedSkipLog,
+    saved: bool,
+}
+
+impl IndexedRead {
+    fn save(&mut self) {
+        if self.saved {
+            return;
+        }
+        self.saved = true;
+
+        // The index only speeds up reading the files, hence
+        // the files are still read if it cannot be saved.
+        let errors = self.index.save();
+        if !errors.is_empty() {
+            self.skip_log.lock().index_errors.extend(errors);
+        }
+    }
+}
+
+impl Drop for IndexedRead {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+impl<I> FileReader<I> {
+    pub fn new(iter: I, recovery: Option<SharedSkipLog>, limits: Limits) -> Self {
+        Self {
+            iter,
+            inner_iter: None,
+            recovery,
+            limits,
+            index: None,
+        }
+    }
+
+    /// Reads the files using the `index`, selecting the documents of the indexed
+    /// files by the `time_window` and the `hostname`. The errors of saving
+    /// the index are recorded in the `skip_log`.
+    pub fn with_index(
+        mut self,
+        index: SharedIndex,
+        time_window: Arc<TimeWindow>,
+        hostname: Option<String>,
+        skip_log: SharedSkipLog,
+    ) -> Self {
+        self.index = Some(IndexedRead {
+            index,
+            time_window,
+            hostname,
+            skip_log,
+            saved: false,
+        });
+        self
+    }
+
+    fn open(&self, file: SourceFile) -> Result<DocumentReader, io::Error> {
+        let mut recorder = None;
+
+        if let Some(indexed) = self.index.as_ref().filter(|_| file.is_on_disk()) {
+            let path = file.info.path();
+
+            // An indexed file has not changed since it was read in full,
+            // hence it is read without recovery.
+            if let Some(file_index) = indexed.index.lookup(path) {
+                let offsets = file_index.select(&indexed.time_window, indexed.hostname.as_deref());
+                let reader = BufReader::new(File::open(path)?);
+                return Ok(DocumentReader::seeking(
+                    reader,
+                    offsets,
+                    file.info,
+                    &self.limits,
+                ));
+            }
+
+            let skipped_regions = self.recovery.as_ref().map(|log| log.lock().regions.len());
+            recorder = FileStamp::of(path).ok().map(|stamp| IndexRecorder {
+                index: indexed.index.clone(),
+                stamp,
+                documents: Vec::new(),
+                skipped_regions,
+            });
+        }
+
+        let (reader, info) = file.open()?;
+        let mut reader = DocumentReader::new(reader, info, self.recovery.clone(), &self.limits);
+        reader.recorder = recorder;
+
+        Ok(reader)
     }
 }
 
-impl<I: Iterator> Iterator for MetricsChunkReader<I>
+impl<I> Iterator for FileReader<I>
 where
-    I: Iterator<Item = Result<Document, MetricParseError>>,
+    I: Iterator<Item = Result<SourceFile, MetricParseError>>,
 {
-    type Item = Result<MetricsChunk, MetricParseError>;
+    type Item = Result<FileDocument, MetricParseError>;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|item| {
-            item.and_then(|document| {
-                document.metrics_chunk().and_then(|data| {
-                    let mut data = Cursor::new(data);
-                    MetricsChunk::from_reader(&mut data)
-                })
+        loop {
+            match self.inner_iter {
+                Some(ref mut inner_iter) => match inner_iter.next() {
+                    None => self.inner_iter = None,
+                    item => return item,
+                },
+                None => match self.iter.next() {
+                    Some(Ok(file)) => {
+                        let context = ErrorContext::new(file.info.path());
+                        match self.open(file) {
+                            Ok(reader) => self.inner_iter = Some(reader),
+                            Err(err) => {
+                                return Some(
+                                    Err(MetricParseError::from(err).with_context(context)),
+                                );
+                            }
+                        }
+                    }
+                    Some(Err(err)) => return Some(Err(err)),
+                    None => {
+                        if let Some(indexed) = &mut self.index {
+                            indexed.save();
+                        }
+
+                        return None;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// The BSON documents read from a file, each along with its byte offset in the file.
+type OffsetDocuments =
+    Box<dyn Iterator<Item = (u64, Result<RawDocumentBuf, MetricParseError>)> + Send>;
+
+/// An iterator that yields the BSON documents of a single file.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+struct DocumentReader {
+    documents: OffsetDocuments,
+    source: Arc<FileInfo>,
+    recovery: Option<SharedSkipLog>,
+    recorder: Option<IndexRecorder>,
+}
+
+impl DocumentReader {
+    fn new(
+        reader: Box<dyn Read + Send>,
+        source: FileInfo,
+        recovery: Option<SharedSkipLog>,
+        limits: &Limits,
+    ) -> Self {
+        let max_size = limits.max_document_size;
+        let documents: OffsetDocuments = match &recovery {
+            Some(skip_log) => Box::new(RecoveringReader::new(
+                reader,
+                source.path().to_path_buf(),
+                skip_log.clone(),
+                max_size,
+            )),
+            None => Box::new(BsonReader::new(reader, max_size)),
+        };
+
+        Self {
+            documents,
+            source: Arc::new(source),
+            recovery,
+            recorder: None,
+        }
+    }
+
+    /// Creates a `DocumentReader` that reads only the documents
+    /// at the `offsets` of the file.
+    fn seeking(
+        reader: BufReader<File>,
+        offsets: Vec<u64>,
+        source: FileInfo,
+        limits: &Limits,
+    ) -> Self {
+        let documents = SeekingReader {
+            reader: BsonReader::new(reader, limits.max_document_size),
+            offsets: offsets.into_iter(),
+        };
+
+        Self {
+            documents: Box::new(documents),
+            source: Arc::new(source),
+            recovery: None,
+            recorder: None,
+        }
+    }
+
+    /// Records the `document` read at the `offset` in the index of the file,
+    /// or gives up on indexing the file if the document cannot be indexed.
+    fn record(&mut self, offset: u64, document: &Result<RawDocumentBuf, MetricParseError>) {
+        let Some(recorder) = self.recorder.as_mut() else {
+            return;
+        };
+
+        let indexed = document.as_ref().ok().and_then(|document| {
+            let kind = document.kind().ok()?;
+            let hostname = match kind {
+                DocumentKind::Metadata => document.hostname().ok().map(str::to_owned),
+                _ => None,
+            };
+
+            Some(IndexedDocument {
+                offset,
+                kind,
+                timestamp: document.timestamp().ok()?,
+                hostname,
             })
-        })
+        });
+
+        match indexed {
+            Some(indexed) => recorder.documents.push(indexed),
+            None => self.recorder = None,
+        }
+    }
+
+    /// Indexes the file once it has been read in full, unless
+    /// some of its regions were skipped in recovery mode.
+    fn finish(&mut self) {
+        let Some(recorder) = self.recorder.take() else {
+            return;
+        };
+
+        let skipped_regions = self.recovery.as_ref().map(|log| log.lock().regions.len());
+        if skipped_regions != recorder.skipped_regions {
+            return;
+        }
+
+        if let Some(file_index) = FileIndex::new(recorder.stamp, recorder.documents) {
+            recorder.index.insert(self.source.path(), file_index);
+        }
+    }
+}
+
+impl Iterator for DocumentReader {
+    type Item = Result<FileDocument, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some((offset, item)) = self.documents.next() else {
+            self.finish();
+            return None;
+        };
+        self.record(offset, &item);
+
+        let document = item
+            .map(|document| FileDocument {
+                document,
+                source: Arc::clone(&self.source),
+                offset,
+            })
+            .map_err(|error| {
+                let context = ErrorContext::new(self.source.path()).with_offset(offset);
+                error.with_context(context)
+            });
+
+        Some(document)
+    }
+}
+
+/// The documents of a file recorded while it is read in full,
+/// to be indexed once the whole file is read.
+struct IndexRecorder {
+    index: SharedIndex,
+    stamp: FileStamp,
+    documents: Vec<IndexedDocument>,
+
+    /// The amount of regions skipped in recovery mode before the file was read.
+    skipped_regions: Option<usize>,
+}
+
+/// An iterator that yields the BSON documents at the given offsets of a file.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+struct SeekingReader<R> {
+    reader: BsonReader<R>,
+    offsets: std::vec::IntoIter<u64>,
+}
+
+impl<R: Read + Seek> Iterator for SeekingReader<R> {
+    type Item = (u64, Result<RawDocumentBuf, MetricParseError>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offsets.next()?;
+
+        if let Err(error) = self.reader.seek(offset) {
+            return Some((offset, Err(MetricParseError::from(error))));
+        }
+
+        match self.reader.next() {
+            Some(item) => Some(item),
+            None => Some((offset, Err(MetricParseError::from(unexpected_eof())))),
+        }
+    }
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the indexed document is past the end of the file",
+    )
+}
+
+/// An iterator that yields the BSON documents from an underlying [`Read`],
+/// skipping the regions that cannot be decoded.
+///
+/// When a document cannot be decoded, the reader scans forward for the next
+/// plausible document boundary, i.e. a valid BSON document that starts with
+/// a date `_id`, as all the diagnostic data documents do, and resumes from it.
+/// The skipped regions are recorded in the [`SharedSkipLog`] along with the
+/// timestamps of the documents around them.
+///
+/// Only a window of the input is kept in memory, which holds at most
+/// a document of the maximum document size, or the bytes being scanned.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+struct RecoveringReader<R> {
+    reader: R,
+    data: Vec<u8>,
+
+    /// The position in `data` of the next document to read.
+    position: usize,
+
+    /// The offset in the input of the first byte in `data`.
+    base: u64,
+
+    /// The offset in the input at which the region being skipped starts.
+    skip_start: Option<u64>,
+
+    eof: bool,
+    finished: bool,
+    path: PathBuf,
+    last_timestamp: Option<DateTime<Utc>>,
+    skip_log: SharedSkipLog,
+    max_document_size: usize,
+}
+
+impl<R: Read> RecoveringReader<R> {
+    /// The first element of every diagnostic data document: a date `_id`.
+    const DOCUMENT_PREFIX: &[u8] = b"\x09_id\x00";
+
+    /// The amount of bytes read at once while scanning for the next document.
+    const SCAN_SIZE: usize = 64 * 1024;
+
+    fn new(reader: R, path: PathBuf, skip_log: SharedSkipLog, max_document_size: usize) -> Self {
+        Self {
+            reader,
+            data: Vec::new(),
+            position: 0,
+            base: 0,
+            skip_start: None,
+            eof: false,
+            finished: false,
+            path,
+            last_timestamp: None,
+            skip_log,
+            max_document_size,
+        }
+    }
+
+    /// Returns the offset in the input of the next document to read.
+    fn offset(&self) -> u64 {
+        self.base + self.position as u64
+    }
+
+    /// Reads from the input until `len` bytes are available from the current
+    /// position, dropping the bytes already read. Returns whether they are.
+    fn fill(&mut self, len: usize) -> Result<bool, io::Error> {
+        let available = self.data.len() - self.position;
+        if available >= len || self.eof {
+            return Ok(available >= len);
+        }
+
+        if self.position >= available {
+            self.data.drain(..self.position);
+            self.base += self.position as u64;
+            self.position = 0;
+        }
+
+        let missing = (len - available) as u64;
+        let read = (&mut self.reader)
+            .take(missing)
+            .read_to_end(&mut self.data)?;
+        self.eof = (read as u64) < missing;
+
+        Ok(self.data.len() - self.position >= len)
+    }
+
+    /// Decodes the document at the current position, if any, returning it
+    /// along with its length. A document larger than the maximum document
+    /// size is treated as corrupted.
+    fn read_document(&mut self) -> Result<Option<(RawDocumentBuf, usize)>, io::Error> {
+        if !self.fill(4)? {
+            return Ok(None);
+        }
+
+        let length = &self.data[self.position..self.position + 4];
+        let length = i32::from_le_bytes(length.try_into().expect("a 4 bytes length"));
+        let Some(length) = usize::try_from(length)
+            .ok()
+            .filter(|l| (5..=self.max_document_size).contains(l))
+        else {
+            return Ok(None);
+        };
+
+        if !self.fill(length)? {
+            return Ok(None);
+        }
+
+        let data = &self.data[self.position..self.position + length];
+        let document = RawDocument::from_bytes(data)
+            .ok()
+            .filter(|document| validate(document).is_ok())
+            .map(|document| (document.to_owned(), length));
+
+        Ok(document)
+    }
+
+    /// Moves the current position forward, to the next position at which
+    /// a document may start, or as far as the data read so far allows.
+    fn advance(&mut self) -> Result<(), io::Error> {
+        let prefix_offset = 4;
+        let from = self.position + 1 + prefix_offset;
+
+        let found = self.data.get(from..).and_then(|data| {
+            data.windows(Self::DOCUMENT_PREFIX.len())
+                .position(|w| w == Self::DOCUMENT_PREFIX)
+        });
+
+        match found {
+            Some(offset) => self.position = from + offset - prefix_offset,
+            None => {
+                // The next document may start within the last bytes read,
+                // with its prefix yet to be read.
+                let tail = prefix_offset + Self::DOCUMENT_PREFIX.len() - 1;
+                let position = self.data.len().saturating_sub(tail);
+                self.position = position.max(self.position + 1).min(self.data.len());
+                self.fill(Self::SCAN_SIZE)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn skip(&mut self, bytes: Range<u64>, end: Option<DateTime<Utc>>) {
+        self.skip_log.lock().regions.push(SkippedRegion {
+            path: self.path.clone(),
+            bytes,
+            start: self.last_timestamp,
+            end,
+        });
+    }
+
+    fn read_next(&mut self) -> Result<Option<(u64, RawDocumentBuf)>, io::Error> {
+        while self.fill(1)? {
+            let Some((document, length)) = self.read_document()? else {
+                self.skip_start.get_or_insert(self.offset());
+                self.advance()?;
+                continue;
+            };
+
+            let offset = self.offset();
+            if let Some(start) = self.skip_start.take() {
+                self.skip(start..offset, document.timestamp().ok());
+            }
+
+            self.position += length;
+            if let Ok(timestamp) = document.timestamp() {
+                self.last_timestamp = Some(timestamp);
+            }
+
+            return Ok(Some((offset, document)));
+        }
+
+        if let Some(start) = self.skip_start.take() {
+            let end = self.offset();
+            self.skip(start..end, None);
+        }
+
+        Ok(None)
+    }
+}
+
+impl<R: Read> Iterator for RecoveringReader<R> {
+    type Item = (u64, Result<RawDocumentBuf, MetricParseError>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.read_next() {
+            Ok(Some((offset, document))) => Some((offset, Ok(document))),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(error) => {
+                self.finished = true;
+                Some((self.offset(), Err(MetricParseError::from(error))))
+            }
+        }
+    }
+}
+
+/// An iterator that yields BSON documents fron an underlying [`Read`],
+/// along with their byte offsets.
+///
+/// The documents are read as raw bytes and only their top-level elements
+/// are validated, leaving the rest to be decoded when accessed. The length
+/// of a document is checked against the maximum document size before
+/// the document is read, since its buffer is allocated upfront.
+///
+/// The reader stops at the first document that cannot be read, since
+/// the position of the next document is unknown, until it is moved
+/// to another document.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+#[derive(Debug, Clone)]
+pub(crate) struct BsonReader<R> {
+    reader: R,
+    offset: u64,
+    max_document_size: usize,
+    failed: bool,
+}
+
+impl<R> BsonReader<R> {
+    pub(crate) fn new(reader: R, max_document_size: usize) -> Self {
+        Self {
+            reader,
+            offset: 0,
+            max_document_size,
+            failed: false,
+        }
+    }
+}
+
+impl<R: Seek> BsonReader<R> {
+    /// Moves the reader to the document at the `offset`.
+    fn seek(&mut self, offset: u64) -> Result<(), io::Error> {
+        // The bytes of a document that failed may have been read in part.
+        if self.failed || offset != self.offset {
+            self.reader.seek(SeekFrom::Start(offset))?;
+            self.offset = offset;
+            self.failed = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for BsonReader<R> {
+    type Item = (u64, Result<RawDocumentBuf, MetricParseError>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let offset = self.offset;
+        let document = self.read();
+        self.failed = document.as_ref().is_some_and(Result::is_err);
+
+        document.map(|document| (offset, document))
+    }
+}
+
+impl<R: Read> BsonReader<R> {
+    /// Reads the next document, advancing the offset past it.
+    fn read(&mut self) -> Option<Result<RawDocumentBuf, MetricParseError>> {
+        // The end of the stream is reached only if no bytes of the next
+        // document length can be read, otherwise the document is truncated.
+        let mut length = Vec::with_capacity(4);
+        match (&mut self.reader).take(4).read_to_end(&mut length) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(error) => return Some(Err(MetricParseError::from(error))),
+        }
+
+        // A length that is negative or smaller than the prefix itself is left
+        // for the BSON decoder to reject, so that the errors remain the same.
+        let mut bytes = length;
+        if let Ok(length) = <[u8; 4]>::try_from(bytes.as_slice())
+            && let Ok(value) = usize::try_from(i32::from_le_bytes(length))
+            && value > bytes.len()
+        {
+            let max = self.max_document_size;
+            if value > max {
+                let limit = Limit::DocumentSize;
+                return Some(Err(MetricParseError::LimitExceeded { limit, max, value }));
+            }
+
+            bytes.resize(value, 0);
+            if let Err(error) = self.reader.read_exact(&mut bytes[4..]) {
+                return Some(Err(MetricParseError::from(error)));
+            }
+        }
+
+        let document = RawDocumentBuf::from_bytes(bytes)
+            .and_then(|document| validate(&document).map(|_| document))
+            .map_err(MetricParseError::from);
+
+        if let Ok(document) = &document {
+            self.offset += document.as_bytes().len() as u64;
+        }
+
+        Some(document)
+    }
+}
+
+/// Checks that the top-level elements of the `document` can be read.
+fn validate(document: &RawDocument) -> Result<(), BsonError> {
+    document.iter().try_for_each(|element| element.map(drop))
+}
+
+/// A metrics chunk document that is yet to be decoded, along with
+/// the process information of the directory it was read from.
+struct EncodedChunk {
+    document: FileDocument,
+    process_info: Option<Arc<ProcessInfo>>,
+}
+
+/// A metrics chunk decoded from an [`EncodedChunk`], along with
+/// the file it was read from.
+struct DecodedChunk<C> {
+    chunk: C,
+    source: Arc<FileInfo>,
+}
+
+/// The layout that the metrics chunks are decoded into, i.e. either
+/// the row layout of [`MetricsChunk`] or the columnar layout of [`ColumnarChunk`].
+///
+/// Each layout is decoded straight from the samples, rather than converted
+/// from the other one, and is deduplicated and trimmed the same way.
+pub(crate) trait ChunkLayout: Sized + Send + 'static {
+    /// Decodes a chunk from the `reader`, as [`ColumnarChunk::from_reader`] does.
+    fn from_reader<F>(
+        reader: &mut Cursor<&[u8]>,
+        limits: &Limits,
+        selector: &MetricSelector,
+        paths: &PathInterner,
+        select: F,
+    ) -> Result<Option<Self>, MetricParseError>
+    where
+        F: FnOnce(Metadata) -> Option<Metadata>;
+
+    /// Returns the timestamps when the recording of the chunk started and ended.
+    fn range(&self) -> (DateTime<Utc>, DateTime<Utc>);
+
+    /// Marks the chunk as read from the interim file.
+    fn set_interim(&mut self, interim: bool);
+
+    /// Returns `true` if none of the metrics of the chunk are selected.
+    fn is_empty(&self) -> bool;
+
+    /// Retains only the measurements within the `range`, returning `false`
+    /// if no measurements are left in the chunk.
+    fn retain_within<R: RangeBounds<DateTime<Utc>>>(&mut self, range: &R) -> bool;
+}
+
+impl ChunkLayout for MetricsChunk {
+    fn from_reader<F>(
+        reader: &mut Cursor<&[u8]>,
+        limits: &Limits,
+        selector: &MetricSelector,
+        paths: &PathInterner,
+        select: F,
+    ) -> Result<Option<Self>, MetricParseError>
+    where
+        F: FnOnce(Metadata) -> Option<Metadata>,
+    {
+        MetricsChunk::from_reader(reader, limits, selector, paths, select)
+    }
+
+    fn range(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        (self.start, self.end)
+    }
+
+    fn set_interim(&mut self, interim: bool) {
+        self.interim = interim;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.metrics().is_empty()
+    }
+
+    fn retain_within<R: RangeBounds<DateTime<Utc>>>(&mut self, range: &R) -> bool {
+        MetricsChunk::retain_within(self, range)
+    }
+}
+
+impl ChunkLayout for ColumnarChunk {
+    fn from_reader<F>(
+        reader: &mut Cursor<&[u8]>,
+        limits: &Limits,
+        selector: &MetricSelector,
+        paths: &PathInterner,
+        select: F,
+    ) -> Result<Option<Self>, MetricParseError>
+    where
+        F: FnOnce(Metadata) -> Option<Metadata>,
+    {
+        ColumnarChunk::from_reader(reader, limits, selector, paths, select)
+    }
+
+    fn range(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        (self.start, self.end)
+    }
+
+    fn set_interim(&mut self, interim: bool) {
+        self.interim = interim;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.metrics().len() == 0
+    }
+
+    fn retain_within<R: RangeBounds<DateTime<Utc>>>(&mut self, range: &R) -> bool {
+        ColumnarChunk::retain_within(self, range)
+    }
+}
+
+/// An iterator that yields the metrics chunk documents as [`EncodedChunk`]s.
+///
+/// The process information read from the metadata documents is attached
+/// to the chunks that follow them in the same directory.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+#[derive(Debug)]
+struct EncodedChunkReader<I> {
+    iter: I,
+    process_info: HashMap<PathBuf, Arc<ProcessInfo>>,
+}
+
+impl<I> EncodedChunkReader<I>
+where
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
+{
+    pub fn new(iter: I) -> Self {
+        Self {
+            iter,
+            process_info: HashMap::new(),
+        }
+    }
+
+    fn read_chunk(
+        &mut self,
+        document: FileDocument,
+    ) -> Result<Option<EncodedChunk>, MetricParseError> {
+        let dir = document.source.dir();
+
+        if document.kind()? == DocumentKind::Metadata {
+            let process_info = ProcessInfo::from_metadata_document(&document.metadata()?);
+            self.process_info
+                .insert(dir.to_path_buf(), Arc::new(process_info));
+
+            return Ok(None);
+        }
+
+        let process_info = self.process_info.get(dir).cloned();
+        Ok(Some(EncodedChunk {
+            document,
+            process_info,
+        }))
+    }
+}
+
+impl<I> Iterator for EncodedChunkReader<I>
+where
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
+{
+    type Item = Result<EncodedChunk, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let document = match self.iter.next()? {
+                Ok(document) => document,
+                Err(err) => return Some(Err(err)),
+            };
+
+            match self.read_chunk(document) {
+                Ok(Some(chunk)) => return Some(Ok(chunk)),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Decodes the [`EncodedChunk`]s into [`MetricsChunk`]s. The chunks are decoded
+/// independently of each other, so the decoder can run on any thread.
+#[derive(Debug, Clone)]
+struct ChunkDecoder {
+    limits: Limits,
+    selector: MetricSelector,
+    instances: Vec<InstanceSelector>,
+    paths: PathInterner,
+}
+
+impl ChunkDecoder {
+    fn new(limits: Limits, selector: MetricSelector, instances: Vec<InstanceSelector>) -> Self {
+        Self {
+            limits,
+            selector,
+            instances,
+            paths: PathInterner::default(),
+        }
+    }
+
+    /// Decodes the `chunk`, returning `None` if it belongs
+    /// to none of the selected instances.
+    fn decode<C: ChunkLayout>(
+        &self,
+        chunk: EncodedChunk,
+    ) -> Result<Option<DecodedChunk<C>>, MetricParseError> {
+        let EncodedChunk {
+            document,
+            process_info,
+        } = chunk;
+
+        let select = |metadata| self.select(metadata, process_info.as_deref());
+        let chunk = document
+            .metrics_chunk()
+            .and_then(|data| {
+                C::from_reader(
+                    &mut Cursor::new(data),
+                    &self.limits,
+                    &self.selector,
+                    &self.paths,
+                    select,
+                )
+            })
+            .map_err(|err| err.with_context(document.context()))?;
+
+        Ok(chunk.map(|chunk| DecodedChunk {
+            chunk,
+            source: document.source,
+        }))
+    }
+
+    /// Decodes the `chunk` driving the `visitor` with its metrics, the same
+    /// way they are yielded by the [`MetricsIterator`]. The measurements
+    /// are trimmed to the `time_window` if `trim` is set.
+    fn visit<V: MetricsVisitor>(
+        &self,
+        chunk: EncodedChunk,
+        time_window: &TimeWindow,
+        trim: bool,
+        last_timestamps: &mut HashMap<PathBuf, DateTime<Utc>>,
+        visitor: &mut V,
+    ) -> Result<(), VisitError<V::Error>> {
+        let EncodedChunk {
+            document,
+            process_info,
+        } = chunk;
+
+        let select = |metadata| self.select(metadata, process_info.as_deref());
+        let samples = document
+            .metrics_chunk()
+            .and_then(|data| {
+                EncodedSamples::from_reader(&mut Cursor::new(data), &self.limits, select)
+            })
+            .map_err(|err| err.with_context(document.context()))?;
+
+        let Some(samples) = samples else {
+            return Ok(());
+        };
+
+        // The measurements of an interim chunk that were already
+        // read from the rotated files in the same directory are skipped.
+        let dir = document.source.dir();
+        let interim = document.source.interim;
+        let after = if interim {
+            last_timestamps.get(dir).copied()
+        } else {
+            None
+        };
+
+        let mut visit = ChunkVisit::new(
+            visitor,
+            &samples.metadata,
+            interim,
+            &self.selector,
+            &self.paths,
+            time_window,
+            trim,
+            after,
+        );
+
+        let result = match samples.visit(&mut visit) {
+            Ok(()) => visit.finish(),
+            Err(VisitError::Skip) => Ok(()),
+            Err(err) => Err(err),
+        };
+
+        if let Err(VisitError::Parse(err)) = result {
+            return Err(VisitError::Parse(err.with_context(document.context())));
+        }
+        result?;
+
+        if !interim && let Some(end) = visit.end() {
+            let last = last_timestamps.entry(dir.to_path_buf()).or_insert(end);
+            *last = end.max(*last);
+        }
+
+        Ok(())
+    }
+
+    fn select(&self, metadata: Metadata, process_info: Option<&ProcessInfo>) -> Option<Metadata> {
+        select_instance(&self.instances, metadata, process_info)
+    }
+}
+
+/// Completes the `metadata` of a chunk with the `process_info`,
+/// returning `None` if the chunk belongs to none of the `instances`.
+fn select_instance(
+    instances: &[InstanceSelector],
+    metadata: Metadata,
+    process_info: Option<&ProcessInfo>,
+) -> Option<Metadata> {
+    let metadata = match process_info {
+        Some(process_info) => metadata.with_process_info(process_info),
+        None => metadata,
+    };
+
+    (instances.is_empty() || instances.iter().any(|i| i.matches(&metadata))).then_some(metadata)
+}
+
+/// An iterator that yields the metrics chunks decoded by a [`ChunkDecoder`].
+///
+/// The chunks read from an interim file are deduplicated against
+/// the chunks read from the rotated files in the same directory,
+/// since mongod may have already written some of their samples
+/// into the last rotated file.
+///
+/// When following the diagnostic data as it is written, the interim file
+/// is read every time it is rewritten, and its samples are written later
+/// into a rotated file, hence all the chunks are deduplicated then.
+/// When reading the newest chunks first, every chunk keeps only the samples
+/// taken before the chunks already read from the same directory.
+///
+/// The chunks left without any selected metrics are not yielded, whether
+/// they had none to begin with or lost them all to the deduplication.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+#[derive(Debug)]
+struct MetricsChunkReader<I> {
+    iter: I,
+    timestamps: HashMap<PathBuf, DateTime<Utc>>,
+    dedup: Dedup,
+}
+
+/// The chunks deduplicated by a [`MetricsChunkReader`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dedup {
+    /// The chunks of the interim files, against the last sample read.
+    Interim,
+    /// All the chunks, against the last sample read.
+    All,
+    /// All the chunks, against the first sample read.
+    Reverse,
+}
+
+impl<I, C> MetricsChunkReader<I>
+where
+    I: Iterator<Item = Result<Option<DecodedChunk<C>>, MetricParseError>>,
+    C: ChunkLayout,
+{
+    pub fn new(iter: I, dedup: Dedup) -> Self {
+        Self {
+            iter,
+            timestamps: HashMap::new(),
+            dedup,
+        }
+    }
+
+    fn read_chunk(&mut self, decoded: DecodedChunk<C>) -> Option<C> {
+        let DecodedChunk { mut chunk, source } = decoded;
+        let dir = source.dir();
+        chunk.set_interim(source.interim);
+
+        if self.dedup == Dedup::Reverse {
+            if let Some(first) = self.timestamps.get(dir) {
+                let range = (Bound::Unbounded, Bound::Excluded(*first));
+                if !chunk.retain_within(&range) {
+                    return None;
+                }
+            }
+
+            let start = chunk.range().0;
+            match self.timestamps.get_mut(dir) {
+                Some(ts) => *ts = start.min(*ts),
+                None => {
+                    self.timestamps.insert(dir.to_path_buf(), start);
+                }
+            }
+
+            return (!chunk.is_empty()).then_some(chunk);
+        }
+
+        let all = self.dedup == Dedup::All;
+        if (source.interim || all)
+            && let Some(last) = self.timestamps.get(dir)
+        {
+            let range = (Bound::Excluded(*last), Bound::Unbounded);
+            if !chunk.retain_within(&range) {
+                return None;
+            }
+        }
+
+        if !source.interim || all {
+            let end = chunk.range().1;
+            match self.timestamps.get_mut(dir) {
+                Some(ts) => *ts = end.max(*ts),
+                None => {
+                    self.timestamps.insert(dir.to_path_buf(), end);
+                }
+            }
+        }
+
+        (!chunk.is_empty()).then_some(chunk)
+    }
+}
+
+impl<I, C> Iterator for MetricsChunkReader<I>
+where
+    I: Iterator<Item = Result<Option<DecodedChunk<C>>, MetricParseError>>,
+    C: ChunkLayout,
+{
+    type Item = Result<C, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next()? {
+                Ok(Some(decoded)) => {
+                    if let Some(chunk) = self.read_chunk(decoded) {
+                        return Some(Ok(chunk));
+                    }
+                }
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// An iterator that reads [`PeriodicMetadata`] from BSON documents
+/// applying the changes to the last full metadata read from
+/// the same directory.
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+#[derive(Debug)]
+struct PeriodicMetadataReader<I> {
+    iter: I,
+    last_metadata: HashMap<PathBuf, PeriodicMetadata>,
+}
+
+impl<I> PeriodicMetadataReader<I>
+where
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
+{
+    pub fn new(iter: I) -> Self {
+        Self {
+            iter,
+            last_metadata: HashMap::new(),
+        }
+    }
+
+    fn read_metadata(
+        &mut self,
+        document: &FileDocument,
+    ) -> Result<PeriodicMetadata, MetricParseError> {
+        let timestamp = document.timestamp()?;
+        let changes = document.metadata()?;
+        let dir = document.source.dir();
+
+        // If the full metadata could not be found, e.g. the file containing it
+        // is missing, we start from the changes we have at hand.
+        let metadata = match self.last_metadata.get(dir) {
+            Some(last) if document.delta_counter()? != 0 => last.apply(timestamp, changes),
+            _ => PeriodicMetadata::new(timestamp, changes),
+        };
+
+        self.last_metadata
+            .insert(dir.to_path_buf(), metadata.clone());
+
+        Ok(metadata)
+    }
+}
+
+impl<I> Iterator for PeriodicMetadataReader<I>
+where
+    I: Iterator<Item = Result<FileDocument, MetricParseError>>,
+{
+    type Item = Result<PeriodicMetadata, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next()
+            .map(|item| item.and_then(|document| self.read_metadata(&document)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+    use std::fs;
+
+    use bson::doc;
+    use chrono::Duration;
+    use chrono::TimeZone;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    use crate::DiagnosticData;
+    use crate::dataset::Dataset;
+    use crate::metrics::Measurement;
+    use crate::metrics::MetricPath;
+    use crate::metrics::MetricValue;
+    use crate::metrics::ValueType;
+    use crate::source::SkipReport;
+    use crate::testing;
+    use crate::testing::TempDir;
+    use crate::testing::seconds;
+    use crate::write::FtdcWriter;
+
+    fn identify(name: &str, data: Vec<u8>) -> Identified {
+        let mut reader = Cursor::new(data);
+        let max_document_size = Limits::DEFAULT_MAX_DOCUMENT_SIZE;
+        FileInfo::identify(
+            PathBuf::from(name),
+            &mut reader,
+            &mut Vec::new(),
+            max_document_size,
+        )
+        .unwrap()
+    }
+
+    fn skip_reason(identified: Identified) -> SkipReason {
+        match identified {
+            Identified::Skipped(file) => file.reason,
+            Identified::Diagnostic(info) => panic!("{info:?} was not skipped"),
+        }
+    }
+
+    #[test]
+    fn identify_reads_timestamp_from_first_document() {
+        let timestamp = Utc.with_ymd_and_hms(2024, 11, 5, 10, 0, 0).unwrap();
+        let data = doc! { "_id": bson::DateTime::from_chrono(timestamp), "type": 0 }
+            .to_vec()
+            .unwrap();
+
+        let Identified::Diagnostic(info) = identify("renamed.bin", data) else {
+            panic!("the file was not identified");
+        };
+
+        assert_eq!(info.timestamp, timestamp);
+        assert_eq!(info.uid, 0);
+        assert!(!info.interim);
+    }
+
+    #[test]
+    fn identify_skips_files_without_diagnostic_data() {
+        let not_ftdc = doc! { "_id": 1, "type": 0 }.to_vec().unwrap();
+        let truncated = doc! { "type": 0 }.to_vec().unwrap()[..6].to_vec();
+
+        assert_eq!(
+            skip_reason(identify("empty", Vec::new())),
+            SkipReason::Empty
+        );
+        assert_eq!(
+            skip_reason(identify("mongod.log", b"{\"t\":1}".to_vec())),
+            SkipReason::NotBson
+        );
+        assert_eq!(
+            skip_reason(identify("truncated", truncated)),
+            SkipReason::NotBson
+        );
+        assert_eq!(
+            skip_reason(identify("other", not_ftdc)),
+            SkipReason::NotDiagnosticData
+        );
+    }
+
+    #[test]
+    fn identify_skips_files_whose_first_document_exceeds_limit() {
+        let (data, _) = documents(&[0]);
+        let identify = |max_document_size| {
+            let mut reader = Cursor::new(data.clone());
+            FileInfo::identify(
+                PathBuf::from("f"),
+                &mut reader,
+                &mut Vec::new(),
+                max_document_size,
+            )
+            .unwrap()
+        };
+
+        assert!(matches!(identify(data.len()), Identified::Diagnostic(_)));
+        assert_eq!(skip_reason(identify(data.len() - 1)), SkipReason::NotBson);
+    }
+
+    fn documents(timestamps: &[i64]) -> (Vec<u8>, Vec<usize>) {
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+
+        for ts in timestamps {
+            offsets.push(data.len());
+            let metadata = doc! { "hostInfo": { "system": { "hostname": "node-1" } } };
+            doc! { "_id": bson::DateTime::from_millis(*ts), "type": 0, "doc": metadata }
+                .to_writer(&mut data)
+                .unwrap();
+        }
+
+        (data, offsets)
+    }
+
+    fn recover(data: Vec<u8>) -> (Vec<i64>, Vec<SkippedRegion>) {
+        let skip_log = SharedSkipLog::default();
+        let reader = RecoveringReader::new(
+            Cursor::new(data),
+            PathBuf::from("f"),
+            skip_log.clone(),
+            Limits::DEFAULT_MAX_DOCUMENT_SIZE,
+        );
+        let timestamps = reader
+            .map(|(_, d)| d.unwrap().get_datetime("_id").unwrap().timestamp_millis())
+            .collect();
+
+        let regions = skip_log.lock().regions.clone();
+        (timestamps, regions)
+    }
+
+    fn millis(ts: i64) -> Option<DateTime<Utc>> {
+        Utc.timestamp_millis_opt(ts).single()
+    }
+
+    #[test]
+    fn bson_reader_yields_the_documents_decoded_as_owned_documents() {
+        let (mut data, offsets) = documents(&[1000, 2000, 3000]);
+        data.truncate(data.len() - 3);
+
+        // The documents as they were decoded before the raw documents.
+        let mut cursor = Cursor::new(data.as_slice());
+        let mut expected = Vec::new();
+        while let Ok(document) = Document::from_reader(&mut cursor) {
+            expected.push((offsets[expected.len()] as u64, document));
+        }
+
+        let read = BsonReader::new(Cursor::new(data), Limits::DEFAULT_MAX_DOCUMENT_SIZE)
+            .collect::<Vec<_>>();
+        let (last, complete) = read.split_last().unwrap();
+        let complete = complete
+            .iter()
+            .map(|(offset, document)| {
+                let document = document.as_ref().unwrap();
+                (*offset, Document::try_from(document.as_ref()).unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(complete, expected);
+        assert_eq!(complete.len(), 2);
+        assert_eq!(last.0, offsets[2] as u64);
+        assert!(last.1.is_err());
+    }
+
+    #[test]
+    fn recovering_reader_skips_corrupted_document() {
+        let (mut data, offsets) = documents(&[1000, 2000, 3000]);
+        data[offsets[1]..offsets[1] + 4].copy_from_slice(&[0xff; 4]);
+
+        let (timestamps, regions) = recover(data);
+
+        assert_eq!(timestamps, vec![1000, 3000]);
+        assert_eq!(
+            regions,
+            vec![SkippedRegion {
+                path: PathBuf::from("f"),
+                bytes: offsets[1] as u64..offsets[2] as u64,
+                start: millis(1000),
+                end: millis(3000),
+            }]
+        );
+    }
+
+    #[test]
+    fn recovering_reader_skips_truncated_last_document() {
+        let (mut data, offsets) = documents(&[1000, 2000]);
+        data.truncate(data.len() - 3);
+        let length = data.len();
+
+        let (timestamps, regions) = recover(data);
+
+        assert_eq!(timestamps, vec![1000]);
+        assert_eq!(
+            regions,
+            vec![SkippedRegion {
+                path: PathBuf::from("f"),
+                bytes: offsets[1] as u64..length as u64,
+                start: millis(1000),
+                end: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn recovering_reader_scans_corrupted_region_within_bounded_window() {
+        let scan_size = RecoveringReader::<Cursor<Vec<u8>>>::SCAN_SIZE;
+        let (documents, offsets) = documents(&[1000, 2000]);
+        let garbage = vec![0xff; 10 * scan_size];
+        let data = [&documents[..offsets[1]], &garbage, &documents[offsets[1]..]].concat();
+
+        let skip_log = SharedSkipLog::default();
+        let max_document_size = 1024;
+        let mut reader = RecoveringReader::new(
+            Cursor::new(data),
+            PathBuf::from("f"),
+            skip_log.clone(),
+            max_document_size,
+        );
+
+        let offsets_read = reader
+            .by_ref()
+            .map(|(offset, document)| document.map(|_| offset))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        // The corrupted region is scanned without being held in memory.
+        assert!(reader.data.capacity() <= 4 * scan_size);
+
+        let garbage_end = (offsets[1] + garbage.len()) as u64;
+        assert_eq!(offsets_read, vec![0, garbage_end]);
+        assert_eq!(
+            skip_log.lock().regions,
+            vec![SkippedRegion {
+                path: PathBuf::from("f"),
+                bytes: offsets[1] as u64..garbage_end,
+                start: millis(1000),
+                end: millis(2000),
+            }]
+        );
+    }
+
+    #[test]
+    fn recovery_mode_reads_metrics_chunks_after_corrupted_region() {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(1);
+        uptime_samples(&mut writer, 0..3);
+
+        let mut data = writer.finish().unwrap();
+        let second = i32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        data[second..second + 4].copy_from_slice(&[0x10, 0, 0, 0]);
+
+        let strict = DiagnosticData::from_bytes(data.clone()).into_iter();
+        assert!(strict.collect::<Result<Vec<_>, _>>().is_err());
+
+        let mut recovering = DiagnosticData::from_bytes(data)
+            .with_recovery(true)
+            .into_iter();
+        let chunks = recovering.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(
+            chunks.iter().map(|c| c.start).collect::<Vec<_>>(),
+            vec![seconds(0), seconds(2)]
+        );
+        assert_eq!(recovering.skipped_regions().len(), 1);
+        assert_eq!(recovering.skipped_regions()[0].start, Some(seconds(0)));
+    }
+
+    #[test]
+    fn errors_carry_the_document_offset() {
+        let (mut data, offsets) = documents(&[1000, 2000]);
+        data[offsets[1]..offsets[1] + 4].copy_from_slice(&[0xff; 4]);
+
+        let error = DiagnosticData::from_bytes(data)
+            .into_iter()
+            .find_map(Result::err)
+            .unwrap();
+        let context = error.context().unwrap();
+
+        assert_eq!(context.path(), None);
+        assert_eq!(context.offset(), Some(offsets[1] as u64));
+        assert!(matches!(
+            error.without_context(),
+            MetricParseError::BsonDeserialzation(_)
+        ));
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("offset: {}", offsets[1]))
+        );
+    }
+
+    #[test]
+    fn errors_carry_the_chunk_timestamp() {
+        let (mut data, _) = documents(&[1000]);
+        let offset = data.len();
+        doc! {
+            "_id": bson::DateTime::from_millis(2000),
+            "type": 1,
+            "data": bson::Binary {
+                subtype: bson::spec::BinarySubtype::Generic,
+                bytes: vec![4, 0, 0, 0, 1, 2, 3, 4],
+            },
+        }
+        .to_writer(&mut data)
+        .unwrap();
+
+        let error = DiagnosticData::from_bytes(data)
+            .into_iter()
+            .find_map(Result::err)
+            .unwrap();
+        let context = error.context().unwrap();
+
+        assert_eq!(context.offset(), Some(offset as u64));
+        assert_eq!(context.timestamp(), millis(2000));
+        assert!(matches!(error.without_context(), MetricParseError::Io(_)));
+    }
+
+    fn limit_error(data: DiagnosticData) -> MetricParseError {
+        let error = data.into_iter().find_map(Result::err).unwrap();
+        error.without_context().clone()
+    }
+
+    #[test]
+    fn reading_stops_at_the_first_document_that_cannot_be_read() {
+        let mut data = Vec::new();
+        let metadata = doc! { "padding": "x".repeat(1000) };
+        doc! { "_id": bson::DateTime::from_millis(0), "type": 0, "doc": metadata }
+            .to_writer(&mut data)
+            .unwrap();
+        let oversized = data.len();
+        let (valid, offsets) = documents(&[1000, 2000]);
+        data.extend(valid);
+
+        let max = oversized - 1;
+        let mut reader = BsonReader::new(Cursor::new(data.clone()), max);
+        let read = reader.by_ref().collect::<Vec<_>>();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].0, 0);
+        assert!(matches!(
+            read[0].1,
+            Err(MetricParseError::LimitExceeded { value, .. }) if value == oversized
+        ));
+
+        // The reader resumes once moved to a document that can be read.
+        let offset = (oversized + offsets[1]) as u64;
+        reader.seek(offset).unwrap();
+        let (at, document) = reader.next().unwrap();
+        assert_eq!(at, offset);
+        let document = document.unwrap();
+        assert_eq!(
+            document.get_datetime("_id").unwrap().timestamp_millis(),
+            2000
+        );
+        assert!(reader.next().is_none());
+
+        let limits = Limits::default().with_max_document_size(max);
+        let read = DiagnosticData::from_bytes(data)
+            .with_limits(limits)
+            .into_iter()
+            .collect::<Vec<_>>();
+        assert_eq!(read.len(), 1);
+        assert!(read[0].is_err());
+    }
+
+    #[test]
+    fn limits_bound_the_document_size() {
+        let (data, _) = documents(&[1000]);
+        let size = data.len();
+        let limits = Limits::default().with_max_document_size(size - 1);
+
+        let error = limit_error(DiagnosticData::from_bytes(data).with_limits(limits));
+
+        assert!(matches!(
+            error,
+            MetricParseError::LimitExceeded {
+                limit: Limit::DocumentSize,
+                max,
+                value,
+            } if max == size - 1 && value == size
+        ));
+    }
+
+    #[test]
+    fn limits_bound_the_declared_chunk_size() {
+        let (mut data, _) = documents(&[1000]);
+        doc! {
+            "_id": bson::DateTime::from_millis(2000),
+            "type": 1,
+            "data": bson::Binary {
+                subtype: bson::spec::BinarySubtype::Generic,
+                bytes: vec![0xff, 0xff, 0xff, 0xff],
+            },
+        }
+        .to_writer(&mut data)
+        .unwrap();
+
+        let error = limit_error(DiagnosticData::from_bytes(data));
+
+        assert!(matches!(
+            error,
+            MetricParseError::LimitExceeded {
+                limit: Limit::ChunkSize,
+                max: Limits::DEFAULT_MAX_CHUNK_SIZE,
+                value: 0xffff_ffff,
+            }
+        ));
+    }
+
+    #[test]
+    fn limits_bound_the_metrics_and_samples_per_chunk() {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(3);
+        uptime_samples(&mut writer, 0..3);
+        let data = writer.finish().unwrap();
+
+        let limits = Limits::default().with_max_samples_per_chunk(2);
+        let error = limit_error(DiagnosticData::from_bytes(data.clone()).with_limits(limits));
+        assert!(matches!(
+            error,
+            MetricParseError::LimitExceeded {
+                limit: Limit::SamplesPerChunk,
+                max: 2,
+                value: 3,
+            }
+        ));
+
+        let limits = Limits::default().with_max_metrics_per_chunk(4);
+        let error = limit_error(DiagnosticData::from_bytes(data.clone()).with_limits(limits));
+        assert!(matches!(
+            error,
+            MetricParseError::LimitExceeded {
+                limit: Limit::MetricsPerChunk,
+                max: 4,
+                value: 5,
+            }
+        ));
+
+        let chunks = DiagnosticData::from_bytes(data).into_iter();
+        assert_eq!(chunks.collect::<Result<Vec<_>, _>>().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn metrics_filter_selects_metrics_by_path() {
+        let mut writer = FtdcWriter::new(Vec::new());
+        for idx in 0..2 {
+            let ts = seconds(idx);
+            let status = doc! {
+                "start": ts,
+                "host": "node-1",
+                "process": "mongod",
+                "version": "8.0.4",
+                "opcounters": { "insert": idx, "query": idx * 2 },
+                "connections": { "current": 10 },
+                "end": ts,
+            };
+            let sample = doc! { "start": ts, "serverStatus": status, "end": ts };
+            writer.write_sample(ts, &sample).unwrap();
+        }
+        let data = writer.finish().unwrap();
+
+        let filter = MetricsFilter::default()
+            .include_metrics("serverStatus.opcounters.*")
+            .exclude_metrics("**.query");
+        let chunks = DiagnosticData::from_bytes(data)
+            .with_filter(filter)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(chunks.len(), 1);
+        let metrics = chunks[0].metrics();
+        assert_eq!(
+            metrics.iter().map(|m| &*m.name).collect::<Vec<_>>(),
+            vec!["serverStatus opcounters insert"]
+        );
+        assert_eq!(metrics[0].measurements.len(), 2);
+        assert_eq!(metrics[0].start, seconds(0));
+    }
+
+    fn instances() -> Vec<u8> {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(1);
+        let processes = [
+            ("node-1:27017", "mongod", "7.0.12", None),
+            ("node-1:27018", "mongod", "8.0.4", Some("rs0")),
+            ("node-1:27019", "mongos", "8.0.4", None),
+        ];
+
+        for (idx, (host, process, version, replica_set)) in processes.into_iter().enumerate() {
+            let ts = seconds(idx as i64);
+            let status = doc! {
+                "start": ts,
+                "host": host,
+                "process": process,
+                "version": version,
+                "uptime": idx as i64,
+                "end": ts,
+            };
+            let mut sample = doc! { "start": ts, "serverStatus": status };
+            if let Some(replica_set) = replica_set {
+                sample.insert(
+                    "replSetGetStatus",
+                    doc! { "start": ts, "set": replica_set, "end": ts },
+                );
+            }
+            sample.insert("end", ts);
+
+            writer.write_sample(ts, &sample).unwrap();
+        }
+
+        writer.finish().unwrap()
+    }
+
+    fn selected_hosts(instances: Vec<InstanceSelector>) -> Vec<String> {
+        let filter = MetricsFilter::default().with_instances(instances);
+
+        DiagnosticData::from_bytes(self::instances())
+            .with_filter(filter)
+            .into_iter()
+            .map(|chunk| chunk.unwrap().metadata.host)
+            .collect()
+    }
+
+    #[test]
+    fn metrics_filter_selects_instances() {
+        let cases = [
+            (vec![], vec!["node-1:27017", "node-1:27018", "node-1:27019"]),
+            (
+                vec![InstanceSelector::new().with_host("node-1:27018")],
+                vec!["node-1:27018"],
+            ),
+            (
+                vec![InstanceSelector::new().with_host("node-1")],
+                vec!["node-1:27017", "node-1:27018", "node-1:27019"],
+            ),
+            (
+                vec![InstanceSelector::new().with_process("mongos")],
+                vec!["node-1:27019"],
+            ),
+            (
+                vec![InstanceSelector::new().with_replica_set("rs0")],
+                vec!["node-1:27018"],
+            ),
+            (
+                vec![InstanceSelector::new().with_max_version("7.0")],
+                vec!["node-1:27017"],
+            ),
+            (
+                vec![
+                    InstanceSelector::new()
+                        .with_process("mongod")
+                        .with_min_version("8.0"),
+                ],
+                vec!["node-1:27018"],
+            ),
+            (
+                vec![
+                    InstanceSelector::new().with_host("node-1:27017"),
+                    InstanceSelector::new().with_process("mongos"),
+                ],
+                vec!["node-1:27017", "node-1:27019"],
+            ),
+        ];
+
+        for (instances, expected) in cases {
+            assert_eq!(selected_hosts(instances.clone()), expected, "{instances:?}");
+        }
+    }
+
+    fn uptime_samples(writer: &mut FtdcWriter<Vec<u8>>, range: Range<i64>) {
+        testing::write_samples(writer, "node-1", range);
+    }
+
+    fn read_window(data: Vec<u8>, start: i64, end: i64, trim: bool) -> Vec<MetricsChunk> {
+        let filter =
+            MetricsFilter::new(None, Some(seconds(start)), Some(seconds(end))).with_trimming(trim);
+
+        DiagnosticData::from_bytes(data)
+            .with_filter(filter)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn trimming_keeps_measurements_within_time_window() {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(5);
+        uptime_samples(&mut writer, 0..10);
+        let data = writer.finish().unwrap();
+
+        let chunks = read_window(data.clone(), 3, 6, false);
+        let ranges = chunks.iter().map(|c| (c.start, c.end)).collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![(seconds(0), seconds(4)), (seconds(5), seconds(9))]
+        );
+
+        let chunks = read_window(data, 3, 6, true);
+        let ranges = chunks.iter().map(|c| (c.start, c.end)).collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![(seconds(3), seconds(4)), (seconds(5), seconds(6))]
+        );
+
+        for metric in chunks.iter().flat_map(|c| c.metrics()) {
+            assert!(metric.start >= seconds(3) && metric.end <= seconds(6));
+            assert!(
+                metric
+                    .measurements
+                    .iter()
+                    .all(|m| (seconds(3)..=seconds(6)).contains(&m.timestamp))
+            );
+        }
+    }
+
+    #[test]
+    fn chunks_without_selected_metrics_are_skipped_with_or_without_trimming() {
+        let mut writer = FtdcWriter::new(Vec::new());
+        uptime_samples(&mut writer, 0..5);
+        for idx in 5..10 {
+            let ts = seconds(idx);
+            let status = doc! {
+                "start": ts,
+                "host": "node-1",
+                "process": "mongod",
+                "version": "8.0.4",
+                "uptime": idx,
+                "end": ts,
+            };
+            let system = doc! { "start": ts, "cpu": 3 * idx, "end": ts };
+            let sample =
+                doc! { "start": ts, "serverStatus": status, "systemMetrics": system, "end": ts };
+            writer.write_sample(ts, &sample).unwrap();
+        }
+        let data = writer.finish().unwrap();
+
+        for (trim, expected) in [
+            (false, (seconds(5), seconds(9))),
+            (true, (seconds(5), seconds(7))),
+        ] {
+            let filter = || {
+                MetricsFilter::new(None, Some(seconds(3)), Some(seconds(7)))
+                    .include_metrics("systemMetrics.cpu")
+                    .with_trimming(trim)
+            };
+
+            let chunks = DiagnosticData::from_bytes(data.clone())
+                .with_filter(filter())
+                .into_iter()
+                .map(|chunk| chunk.map(|c| (c.start, c.end)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(chunks, vec![expected], "trim: {trim}");
+
+            let columnar = DiagnosticData::from_bytes(data.clone())
+                .with_filter(filter())
+                .columnar_chunks()
+                .map(|chunk| chunk.map(|c| (c.start, c.end)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(columnar, chunks, "trim: {trim}");
+        }
+    }
+
+    #[test]
+    fn metrics_chunk_looks_up_metrics_by_path() {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(2);
+        for idx in 0..4 {
+            let ts = seconds(idx);
+            let status = doc! {
+                "start": ts,
+                "host": "node-1",
+                "process": "mongod",
+                "version": "8.0.4",
+                "uptime": idx,
+                "wiredTiger": { "cache": { "bytes dirty": idx, "a.b": 1, "a": { "b": 2 } } },
+                "end": ts,
+            };
+            let sample = doc! { "start": ts, "serverStatus": status, "end": ts };
+            writer.write_sample(ts, &sample).unwrap();
+        }
+        let data = writer.finish().unwrap();
+
+        let chunks = DiagnosticData::from_bytes(data)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(chunks.len(), 2);
+
+        let path = |path: &str| path.parse::<MetricPath>().unwrap();
+        let chunk = &chunks[0];
+
+        let dirty = chunk.get(&path("serverStatus.wiredTiger.cache.bytes dirty"));
+        assert_eq!(dirty.unwrap().groups.last().unwrap(), "bytes dirty");
+
+        let dotted = chunk
+            .get(&path(r"serverStatus.wiredTiger.cache.a\.b"))
+            .unwrap();
+        let nested = chunk
+            .get(&path("serverStatus.wiredTiger.cache.a.b"))
+            .unwrap();
+        assert_eq!(dotted.measurements[0].value, MetricValue::Int32(1));
+        assert_eq!(nested.measurements[0].value, MetricValue::Int32(2));
+        assert!(chunk.get(&path("serverStatus.missing")).is_none());
+
+        let cache = chunk
+            .starting_with(&path("serverStatus.wiredTiger.cache"))
+            .map(|m| m.path.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cache,
+            vec![
+                "serverStatus.wiredTiger.cache.a.b",
+                r"serverStatus.wiredTiger.cache.a\.b",
+                "serverStatus.wiredTiger.cache.bytes dirty",
+            ]
+        );
+
+        // The paths are interned across the chunks.
+        let uptime = path("serverStatus.uptime");
+        let first = chunks[0].get(&uptime).unwrap();
+        let second = chunks[1].get(&uptime).unwrap();
+        assert!(std::ptr::eq(first.path.segments(), second.path.segments()));
+
+        // The metrics changed after the first lookup are still found.
+        let mut chunk = chunks[0].clone();
+        let removed = chunk.metrics_mut().remove(0);
+        chunk.metrics_mut().reverse();
+        let dirty = path("serverStatus.wiredTiger.cache.bytes dirty");
+
+        assert_eq!(removed.path, uptime);
+        assert!(chunk.get(&uptime).is_none());
+        assert_eq!(chunk.get(&dirty).unwrap().path, dirty);
+        let cache = chunk
+            .starting_with(&path("serverStatus.wiredTiger.cache"))
+            .map(|m| m.path.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(cache.len(), 3);
+        assert!(cache.is_sorted());
+
+        chunk.metrics_mut().push(removed);
+        assert_eq!(chunk.get(&uptime).unwrap().path, uptime);
+    }
+
+    #[test]
+    fn columnar_chunks_share_timestamps_within_sections() {
+        let mut writer = FtdcWriter::new(Vec::new());
+        for idx in 0..4 {
+            let ts = seconds(idx);
+            let later = ts + Duration::milliseconds(10);
+            let sample = doc! {
+                "start": ts,
+                "serverStatus": {
+                    "start": ts,
+                    "host": "node-1",
+                    "process": "mongod",
+                    "version": "8.0.4",
+                    "uptime": idx,
+                    "connections": { "current": 2 * idx },
+                    "end": ts,
+                },
+                "systemMetrics": { "start": later, "cpu": 3 * idx, "end": later },
+                "end": later,
+            };
+            writer.write_sample(ts, &sample).unwrap();
+        }
+        let data = writer.finish().unwrap();
+
+        let chunks = DiagnosticData::from_bytes(data.clone())
+            .columnar_chunks()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+
+        let chunk = &chunks[0];
+        let metric = |path: &str| chunk.metric(&path.parse().unwrap()).unwrap();
+        let uptime = metric("serverStatus.uptime");
+        let connections = metric("serverStatus.connections.current");
+        let cpu = metric("systemMetrics.cpu");
+
+        assert!(std::ptr::eq(uptime.timestamps(), connections.timestamps()));
+        assert!(!std::ptr::eq(uptime.timestamps(), cpu.timestamps()));
+        assert_eq!(cpu.raw_values(), &[0, 3, 6, 9]);
+        assert_eq!(cpu.start(), seconds(0) + Duration::milliseconds(10));
+
+        // The name and the groups are derived from the shared path.
+        assert_eq!(
+            connections.name().as_ref(),
+            "serverStatus connections current"
+        );
+        assert!(std::ptr::eq(
+            connections.groups(),
+            connections.path().segments()
+        ));
+
+        let expected = DiagnosticData::from_bytes(data)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let converted = MetricsChunk::from(chunk.clone());
+
+        assert_eq!(
+            (converted.start, converted.end),
+            (expected[0].start, expected[0].end)
+        );
+        assert_eq!(converted.metrics().len(), expected[0].metrics().len());
+        for (metric, expected) in converted.metrics().iter().zip(expected[0].metrics()) {
+            assert_eq!(metric.name, expected.name);
+            assert_eq!(metric.measurements, expected.measurements);
+        }
+    }
+
+    #[test]
+    fn time_window_filter_skips_chunks_ending_before_time_window() {
+        let mut data = Vec::new();
+        doc! {
+            "_id": bson::DateTime::from_chrono(seconds(0)),
+            "type": 1,
+            "data": bson::Binary {
+                subtype: bson::spec::BinarySubtype::Generic,
+                bytes: vec![4, 0, 0, 0, 1, 2, 3, 4],
+            },
+        }
+        .to_writer(&mut data)
+        .unwrap();
+
+        let mut writer = FtdcWriter::new(data).with_max_samples(5);
+        uptime_samples(&mut writer, 5..15);
+        let data = writer.finish().unwrap();
+
+        // The undecodable chunk ends before the chunk that starts at 5s,
+        // hence it is not read at all, while the chunk starting at 5s
+        // is read since it may reach into the time window.
+        let chunks = read_window(data, 7, 8, false);
+        let starts = chunks.iter().map(|c| c.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![seconds(5)]);
+    }
+
+    fn file_info(dir: &str, secs: i64, interim: bool) -> FileInfo {
+        FileInfo {
+            path: Path::new(dir).join(format!("metrics.{secs}")),
+            timestamp: seconds(secs),
+            uid: 0,
+            interim,
+        }
+    }
+
+    type VisitedChunk = Vec<(String, Vec<Measurement>)>;
+
+    #[derive(Default)]
+    struct RecordingVisitor {
+        chunks: Vec<VisitedChunk>,
+        ended: usize,
+    }
+
+    impl MetricsVisitor for RecordingVisitor {
+        type Error = Infallible;
+
+        fn chunk_start(&mut self, _: &Metadata, _: bool) -> Result<(), Infallible> {
+            self.chunks.push(Vec::new());
+            Ok(())
+        }
+
+        fn metric(&mut self, path: &MetricPath, _: ValueType) -> Result<(), Infallible> {
+            let chunk = self.chunks.last_mut().unwrap();
+            chunk.push((path.to_string(), Vec::new()));
+            Ok(())
+        }
+
+        fn sample(
+            &mut self,
+            timestamp: DateTime<Utc>,
+            value: MetricValue,
+        ) -> Result<(), Infallible> {
+            let chunk = self.chunks.last_mut().unwrap();
+            let (_, measurements) = chunk.last_mut().unwrap();
+            measurements.push(Measurement { timestamp, value });
+            Ok(())
+        }
+
+        fn chunk_end(&mut self) -> Result<(), Infallible> {
+            self.ended += 1;
+            Ok(())
+        }
+
+        fn error(&mut self, error: MetricParseError) -> Result<(), Infallible> {
+            panic!("unexpected error: {error}");
+        }
+    }
+
+    /// Visits the diagnostic data made by `data`, asserting that the visitor
+    /// is driven with the same metrics as the ones yielded by the iterator.
+    fn assert_visit_matches_iteration<F: Fn() -> DiagnosticData>(data: F) -> Vec<VisitedChunk> {
+        let mut visitor = RecordingVisitor::default();
+        data().visit(&mut visitor).unwrap();
+
+        let expected = data()
+            .into_iter()
+            .map(|chunk| {
+                chunk
+                    .unwrap()
+                    .into_metrics()
+                    .into_iter()
+                    .map(|m| (m.path.to_string(), m.measurements))
+                    .collect::<VisitedChunk>()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(visitor.chunks, expected);
+        assert_eq!(visitor.ended, expected.len());
+
+        visitor.chunks
+    }
+
+    #[test]
+    fn visitor_is_driven_with_iterated_metrics() {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(4);
+        uptime_samples(&mut writer, 0..10);
+        let data = writer.finish().unwrap();
+
+        let chunks = assert_visit_matches_iteration(|| DiagnosticData::from_bytes(data.clone()));
+        assert_eq!(chunks.len(), 3);
+
+        let chunks = assert_visit_matches_iteration(|| {
+            let filter = MetricsFilter::new(None, Some(seconds(2)), Some(seconds(6)))
+                .include_metrics("serverStatus.uptime")
+                .with_trimming(true);
+
+            DiagnosticData::from_bytes(data.clone()).with_filter(filter)
+        });
+        let uptime = chunks
+            .iter()
+            .flatten()
+            .flat_map(|(_, measurements)| measurements)
+            .map(|m| m.value)
+            .collect::<Vec<_>>();
+        assert_eq!(uptime, (2..=6).map(MetricValue::Int64).collect::<Vec<_>>());
+
+        let chunks = assert_visit_matches_iteration(|| {
+            let filter = MetricsFilter::new(None, Some(seconds(20)), None);
+            DiagnosticData::from_bytes(data.clone()).with_filter(filter)
+        });
+        assert!(chunks.is_empty());
+    }
+
+    #[test]
+    fn visitor_is_driven_with_chunks_decoded_in_parallel() {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(4);
+        uptime_samples(&mut writer, 0..20);
+        let data = writer.finish().unwrap();
+
+        let filter =
+            || MetricsFilter::new(None, Some(seconds(2)), Some(seconds(13))).with_trimming(true);
+        let sequential = assert_visit_matches_iteration(|| {
+            DiagnosticData::from_bytes(data.clone()).with_filter(filter())
+        });
+        let parallel = assert_visit_matches_iteration(|| {
+            DiagnosticData::from_bytes(data.clone())
+                .with_filter(filter())
+                .with_parallel_decoding(4, 8)
+        });
+
+        assert_eq!(parallel.len(), 4);
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn visitor_skips_measurements_of_interim_file_already_read() {
+        let dir = TempDir::new("visit");
+
+        // The rotated files start with a metadata document, unlike the interim file.
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(10);
+        let metadata = testing::metadata("node-1");
+        writer.write_metadata(seconds(0), &metadata).unwrap();
+        uptime_samples(&mut writer, 0..6);
+        let rotated = writer.finish().unwrap();
+        fs::write(dir.join("metrics.2024-11-05T10-00-00Z-00000"), rotated).unwrap();
+
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(10);
+        uptime_samples(&mut writer, 4..9);
+        let interim = writer.finish().unwrap();
+        fs::write(dir.join("metrics.interim"), interim).unwrap();
+
+        let chunks = assert_visit_matches_iteration(|| DiagnosticData::new(&dir).unwrap());
+
+        let starts = chunks
+            .iter()
+            .map(|chunk| chunk[0].1[0].timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(starts, vec![seconds(0), seconds(6)]);
+    }
+
+    #[test]
+    fn path_filter_skips_files_outside_time_window() {
+        let files = [
+            file_info("a", 0, false),
+            file_info("b", 0, false),
+            file_info("a", 10, false),
+            file_info("b", 30, false),
+            file_info("a", 20, false),
+            file_info("a", 40, false),
+            file_info("b", 50, true),
+        ];
+        let files = files
+            .into_iter()
+            .map(|info| Ok(SourceFile::in_memory(info, Vec::new())));
+
+        let time_window = Arc::new(TimeWindow::new(Some(seconds(15)), Some(seconds(35))));
+        let paths = PathFilter::new(files, time_window)
+            .map(|file| file.unwrap().info.path)
+            .collect::<Vec<_>>();
+
+        let expected = [
+            "b/metrics.0",
+            "b/metrics.30",
+            "a/metrics.10",
+            "a/metrics.20",
+            "b/metrics.50",
+        ];
+        assert_eq!(paths, expected.map(PathBuf::from));
+    }
+
+    #[test]
+    fn parallel_decoding_yields_chunks_in_order() {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(3);
+        uptime_samples(&mut writer, 0..60);
+        let data = writer.finish().unwrap();
+
+        let read = |data: DiagnosticData| {
+            data.into_iter()
+                .map(|chunk| {
+                    let chunk = chunk.unwrap();
+                    let values = chunk.metrics()[0].measurements.iter().map(|m| m.value);
+                    (chunk.start, values.collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let sequential = read(DiagnosticData::from_bytes(data.clone()));
+        let parallel = read(DiagnosticData::from_bytes(data).with_parallel_decoding(4, 6));
+
+        assert_eq!(sequential.len(), 20);
+        assert_eq!(parallel, sequential);
+    }
+
+    /// Writes two rotated files and an interim file, whose samples overlap
+    /// the ones of the last rotated file, into a new directory named `name`.
+    fn rotated_and_interim_files(name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+
+        let metadata = testing::metadata("node-1");
+        for (name, range) in [("00-00", 0..10), ("00-10", 10..20)] {
+            let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(5);
+            writer
+                .write_metadata(seconds(range.start), &metadata)
+                .unwrap();
+            uptime_samples(&mut writer, range);
+            let rotated = writer.finish().unwrap();
+            fs::write(
+                dir.join(format!("metrics.2024-11-05T10-{name}Z-00000")),
+                rotated,
+            )
+            .unwrap();
+        }
+
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(10);
+        uptime_samples(&mut writer, 18..25);
+        let interim = writer.finish().unwrap();
+        fs::write(dir.join("metrics.interim"), interim).unwrap();
+
+        dir
+    }
+
+    fn uptime(chunks: impl Iterator<Item = Result<MetricsChunk, MetricParseError>>) -> Vec<i64> {
+        chunks
+            .flat_map(|chunk| chunk.unwrap().into_metrics())
+            .filter(|metric| metric.path.to_string() == "serverStatus.uptime")
+            .flat_map(|metric| metric.measurements)
+            .map(|m| match m.value {
+                MetricValue::Int64(value) => value,
+                value => panic!("unexpected value: {value:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn interim_file_is_read_after_rotated_files_without_duplicates() {
+        let dir = rotated_and_interim_files("interim");
+
+        let chunks = DiagnosticData::new(&dir)
+            .unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let ranges = chunks
+            .iter()
+            .map(|c| (c.start, c.end, c.interim))
+            .collect::<Vec<_>>();
+        let expected = [
+            (seconds(0), seconds(4), false),
+            (seconds(5), seconds(9), false),
+            (seconds(10), seconds(14), false),
+            (seconds(15), seconds(19), false),
+            (seconds(20), seconds(24), true),
+        ];
+        assert_eq!(ranges, expected);
+
+        // The samples of the interim file already written
+        // into the last rotated file are yielded only once.
+        assert_eq!(
+            uptime(chunks.into_iter().map(Ok)),
+            (0..25).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn archive_files_are_read_in_the_order_they_were_written() {
+        let dir = rotated_and_interim_files("archive-order");
+
+        // The files are stored in the reverse order they were written.
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for name in [
+            "metrics.interim",
+            "metrics.2024-11-05T10-00-10Z-00000",
+            "metrics.2024-11-05T10-00-00Z-00000",
+        ] {
+            let path = Path::new("diagnostic.data").join(name);
+            builder.append_path_with_name(dir.join(name), path).unwrap();
+        }
+        let archive_path = dir.join("diagnostic.data.tar.gz");
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+        fs::write(&archive_path, archive).unwrap();
+
+        let chunks = DiagnosticData::new(&archive_path)
+            .unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let merged = Dataset::new(&archive_path)
+            .unwrap()
+            .merged()
+            .unwrap()
+            .map(|chunk| chunk.map(|(_, chunk)| chunk))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        for chunks in [chunks, merged] {
+            assert!(chunks.windows(2).all(|c| c[0].end < c[1].start));
+            assert_eq!(
+                uptime(chunks.into_iter().map(Ok)),
+                (0..25).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn newest_first_yields_chunks_from_newest_to_oldest() {
+        let dir = rotated_and_interim_files("newest-first");
+
+        let chunks = DiagnosticData::new(&dir)
+            .unwrap()
+            .newest_first()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(chunks.windows(2).all(|c| c[0].start > c[1].start));
+
+        // The samples are yielded only once, even though the interim file
+        // holds samples of the last rotated file as well.
+        let mut values = uptime(chunks.into_iter().map(Ok));
+        values.sort_unstable();
+        assert_eq!(values, (0..25).collect::<Vec<_>>());
+
+        let filter =
+            MetricsFilter::new(None, Some(seconds(7)), Some(seconds(13))).with_trimming(true);
+        let chunks = DiagnosticData::new(&dir)
+            .unwrap()
+            .with_filter(filter)
+            .newest_first();
+        assert_eq!(uptime(chunks), [10, 11, 12, 13, 7, 8, 9]);
+    }
+
+    #[test]
+    fn reverse_path_sorter_skips_files_outside_time_window() {
+        let sort = |time_window, newest_only| {
+            let files = [
+                file_info("a", 0, false),
+                file_info("a", 10, false),
+                file_info("a", 20, false),
+                file_info("a", 30, true),
+                file_info("a", 40, false),
+                file_info("b", 0, false),
+                file_info("b", 5, true),
+                file_info("b", 50, false),
+            ];
+            let files = files
+                .into_iter()
+                .map(|info| Ok(SourceFile::in_memory(info, Vec::new())));
+            ReversePathSorter::new(files, Arc::new(time_window), newest_only)
+                .map(|file| file.unwrap().info.path)
+                .collect::<Vec<_>>()
+        };
+
+        let paths = sort(TimeWindow::new(Some(seconds(15)), Some(seconds(35))), false);
+        let expected = [
+            "a/metrics.20",
+            "a/metrics.30",
+            "a/metrics.10",
+            "b/metrics.0",
+            "b/metrics.5",
+        ];
+        assert_eq!(paths, expected.map(PathBuf::from));
+
+        // The files are newest first within each directory only, while
+        // the directories follow the order of their paths.
+        let paths = sort(TimeWindow::default(), true);
+        let expected = [
+            "a/metrics.40",
+            "a/metrics.30",
+            "b/metrics.50",
+            "b/metrics.5",
+        ];
+        assert_eq!(paths, expected.map(PathBuf::from));
+    }
+
+    #[test]
+    fn limits_bound_the_documents_held_to_read_newest_first() {
+        let dir = rotated_and_interim_files("reversed-size");
+        let oldest = dir.join("metrics.2024-11-05T10-00-00Z-00000");
+        let size = fs::metadata(&oldest).unwrap().len() as usize;
+
+        // The newest rotated file and the interim file are held together,
+        // which exceeds the limit, while the oldest file fits within it.
+        let limits = Limits::default().with_max_reversed_size(size);
+        let chunks = DiagnosticData::new(&dir)
+            .unwrap()
+            .with_limits(limits)
+            .newest_first()
+            .collect::<Vec<_>>();
+
+        let (errors, chunks): (Vec<_>, Vec<_>) = chunks.into_iter().partition(Result::is_err);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0].as_ref().unwrap_err().without_context(),
+            MetricParseError::LimitExceeded {
+                limit: Limit::ReversedSize,
+                max,
+                value,
+            } if *max == size && *value > size
+        ));
+        assert_eq!(uptime(chunks.into_iter()), [5, 6, 7, 8, 9, 0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn latest_selects_samples_before_newest_one() {
+        let dir = rotated_and_interim_files("latest");
+
+        let filter = MetricsFilter::default().with_trimming(true);
+        let chunks = DiagnosticData::new(&dir)
+            .unwrap()
+            .with_filter(filter)
+            .latest(std::time::Duration::from_secs(5))
+            .unwrap();
+        let values = uptime(chunks.into_iter());
+
+        assert_eq!(values, (19..25).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn latest_selects_samples_before_newest_one_of_selected_instances() {
+        let dir = TempDir::new("latest-instances");
+        for (host, samples) in [("node-1:27017", 0..10), ("node-1:27018", 0..20)] {
+            let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(5);
+            testing::write_samples(&mut writer, host, samples);
+
+            let node = dir.join(host.replace(':', "-"));
+            fs::create_dir_all(&node).unwrap();
+            fs::write(node.join("metrics.interim"), writer.finish().unwrap()).unwrap();
+        }
+
+        let filter = MetricsFilter::default()
+            .with_instances([InstanceSelector::new().with_host("node-1:27017")])
+            .with_trimming(true);
+        let chunks = DiagnosticData::new(&dir)
+            .unwrap()
+            .with_filter(filter)
+            .latest(std::time::Duration::from_secs(5))
+            .unwrap();
+        let values = uptime(chunks.into_iter());
+
+        assert_eq!(values, (4..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn latest_skips_chunks_that_cannot_be_decoded() {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(5);
+        uptime_samples(&mut writer, 0..10);
+        let mut data = writer.finish().unwrap();
+        doc! {
+            "_id": bson::DateTime::from_chrono(seconds(20)),
+            "type": 1,
+            "data": bson::Binary {
+                subtype: bson::spec::BinarySubtype::Generic,
+                bytes: vec![16, 0, 0, 0, 0xff, 0xff, 0xff, 0xff],
+            },
+        }
+        .to_writer(&mut data)
+        .unwrap();
+
+        let dir = TempDir::new("latest-corrupt");
+        fs::write(dir.join("metrics.interim"), data).unwrap();
+
+        let filter = MetricsFilter::default().with_trimming(true);
+        let chunks = DiagnosticData::new(&dir)
+            .unwrap()
+            .with_filter(filter)
+            .with_recovery(true)
+            .latest(std::time::Duration::from_secs(3))
+            .unwrap();
+        let values = uptime(chunks.into_iter().filter(Result::is_ok));
+
+        assert_eq!(values, (6..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn latest_fails_on_diagnostic_data_read_from_reader() {
+        let (data, _) = documents(&[1000]);
+
+        let result = DiagnosticData::from_reader(Cursor::new(data))
+            .latest(std::time::Duration::from_secs(5));
+
+        assert!(matches!(
+            result,
+            Err(MetricParseError::Io(err)) if err.kind() == io::ErrorKind::Unsupported
+        ));
     }
 }
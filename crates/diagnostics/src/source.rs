@@ -0,0 +1,869 @@
+//! Defines the sources from which the diagnostic data can be read.
+//!
+//! Besides a directory tree, the diagnostic data can be read from
+//! an archive, a single file, a byte buffer or any [reader]. All the sources
+//! feed the same filter and decode pipeline. One usually creates a [Source]
+//! through one of the [diagnostic data] constructors.
+//!
+//! [reader]: std::io::Read
+//! [diagnostic data]: crate::DiagnosticData
+
+use std::fmt;
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fs;
+use std::fs::File;
+use std::fs::ReadDir;
+use std::io;
+use std::io::BufReader;
+use std::io::Cursor;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::SyncSender;
+use std::thread;
+
+use chrono::DateTime;
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use tar::Archive;
+use tar::EntryType;
+
+use crate::error::IndexSaveError;
+use crate::error::MetricParseError;
+use crate::index;
+use crate::read::FileInfo;
+use crate::read::Identified;
+
+/// `Source` specifies where the diagnostic data is read from.
+pub struct Source {
+    pub(crate) kind: SourceKind,
+}
+
+pub(crate) enum SourceKind {
+    /// A directory tree containing the diagnostic data files, along with its path.
+    Dir(PathBuf, ReadDir),
+
+    /// A gzip-compressed tar archive containing the diagnostic data files,
+    /// along with its path.
+    Archive(PathBuf, File),
+
+    /// The diagnostic data files in a single directory, e.g. of a [dataset] instance.
+    ///
+    /// [dataset]: crate::dataset::Dataset
+    Files(Vec<PathBuf>),
+
+    /// The diagnostic data files in a single directory of a gzip-compressed
+    /// tar archive, along with the path of the archive and the path
+    /// of the directory relative to the archive root.
+    ArchiveDir(PathBuf, File, PathBuf),
+
+    /// A single diagnostic data file.
+    File(PathBuf),
+
+    /// The content of a single diagnostic data file.
+    Bytes(Vec<u8>),
+
+    /// A reader that yields the content of a single diagnostic data file.
+    Reader(Box<dyn Read + Send>),
+}
+
+impl Source {
+    const ARCHIVE_EXTENSIONS: [&str; 2] = [".tar.gz", ".tgz"];
+
+    /// Creates a `Source` that reads the diagnostic data at the specified `path`,
+    /// which can point either to a directory or to a `.tar.gz` or `.tgz` archive.
+    pub fn path(path: &Path) -> Result<Source, io::Error> {
+        if path.is_file() && Self::is_archive(path) {
+            let file = File::open(path)?;
+            return Ok(Self::from(SourceKind::Archive(path.to_path_buf(), file)));
+        }
+
+        let entries = fs::read_dir(path)?;
+        Ok(Self::from(SourceKind::Dir(path.to_path_buf(), entries)))
+    }
+
+    /// Creates a `Source` that reads a single diagnostic data file,
+    /// e.g. a `metrics.*` file, at the specified `path`.
+    pub fn file(path: &Path) -> Result<Source, io::Error> {
+        if !path.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the '{path:?}' path does not point to a file"),
+            ));
+        }
+
+        Ok(Self::from(SourceKind::File(path.to_path_buf())))
+    }
+
+    /// Creates a `Source` that reads the diagnostic data from the `data` buffer,
+    /// which holds the content of a single diagnostic data file.
+    pub fn bytes(data: Vec<u8>) -> Source {
+        Self::from(SourceKind::Bytes(data))
+    }
+
+    /// Creates a `Source` that reads the diagnostic data from the `reader`,
+    /// which yields the content of a single diagnostic data file.
+    pub fn reader<R: Read + Send + 'static>(reader: R) -> Source {
+        Self::from(SourceKind::Reader(Box::new(reader)))
+    }
+
+    /// Opens the source anew, so that the diagnostic data can be read once more.
+    /// The diagnostic data read from a reader cannot be read again.
+    pub(crate) fn reopen(&self) -> Result<Source, io::Error> {
+        let kind = match &self.kind {
+            SourceKind::Dir(path, _) => SourceKind::Dir(path.clone(), fs::read_dir(path)?),
+            SourceKind::Archive(path, _) => SourceKind::Archive(path.clone(), File::open(path)?),
+            SourceKind::Files(paths) => SourceKind::Files(paths.clone()),
+            SourceKind::ArchiveDir(path, _, dir) => {
+                SourceKind::ArchiveDir(path.clone(), File::open(path)?, dir.clone())
+            }
+            SourceKind::File(path) => SourceKind::File(path.clone()),
+            SourceKind::Bytes(data) => SourceKind::Bytes(data.clone()),
+            SourceKind::Reader(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the diagnostic data read from a reader cannot be read again",
+                ));
+            }
+        };
+
+        Ok(Self::from(kind))
+    }
+
+    fn is_archive(path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                Self::ARCHIVE_EXTENSIONS
+                    .iter()
+                    .any(|extension| name.ends_with(extension))
+            })
+    }
+}
+
+impl From<SourceKind> for Source {
+    fn from(kind: SourceKind) -> Self {
+        Self { kind }
+    }
+}
+
+impl Debug for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            SourceKind::Dir(path, _) => f.debug_tuple("Dir").field(path).finish(),
+            SourceKind::Archive(path, _) => f.debug_tuple("Archive").field(path).finish(),
+            SourceKind::Files(paths) => f.debug_tuple("Files").field(paths).finish(),
+            SourceKind::ArchiveDir(path, _, dir) => {
+                f.debug_tuple("ArchiveDir").field(path).field(dir).finish()
+            }
+            SourceKind::File(path) => f.debug_tuple("File").field(path).finish(),
+            SourceKind::Bytes(data) => f.debug_tuple("Bytes").field(&data.len()).finish(),
+            SourceKind::Reader(_) => f.debug_tuple("Reader").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// A file found in a [`Source`] that does not hold diagnostic data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedFile {
+    /// Path of the file, relative to the archive root if it was read from an archive.
+    pub path: PathBuf,
+
+    /// Specifies why the file was skipped.
+    pub reason: SkipReason,
+}
+
+/// `SkipReason` specifies why a file was not read as diagnostic data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The file is empty.
+    Empty,
+
+    /// The file does not start with a BSON document.
+    NotBson,
+
+    /// The first BSON document of the file is neither a metadata document
+    /// nor a metrics chunk with a date `_id`.
+    NotDiagnosticData,
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Empty => write!(f, "the file is empty"),
+            SkipReason::NotBson => write!(f, "the file does not start with a BSON document"),
+            SkipReason::NotDiagnosticData => {
+                write!(f, "the file does not start with a diagnostic data document")
+            }
+        }
+    }
+}
+
+/// A region of a diagnostic data file that could not be decoded and was skipped
+/// while reading in recovery mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedRegion {
+    /// Path of the file, relative to the archive root if it was read from an archive.
+    pub path: PathBuf,
+
+    /// The byte range of the region in the file.
+    pub bytes: Range<u64>,
+
+    /// Timestamp of the last document read before the region, if any.
+    /// The diagnostic data missing due to the region was captured after it.
+    pub start: Option<DateTime<Utc>>,
+
+    /// Timestamp of the first document read after the region, if any.
+    /// The diagnostic data missing due to the region was captured before it.
+    pub end: Option<DateTime<Utc>>,
+}
+
+/// The files and the regions skipped while reading a [`Source`],
+/// along with the indexes of the files that could not be saved.
+#[derive(Debug, Default)]
+pub(crate) struct SkipLog {
+    pub(crate) files: Vec<SkippedFile>,
+    pub(crate) regions: Vec<SkippedRegion>,
+    pub(crate) index_errors: Vec<IndexSaveError>,
+}
+
+/// The [`SkipLog`] shared between the pipeline that reads the source
+/// and the iterator that reports what was skipped.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SharedSkipLog(Arc<Mutex<SkipLog>>);
+
+impl SharedSkipLog {
+    /// Locks the skip log. The skip log is only ever appended to,
+    /// so it is still consistent even if a thread panicked holding it.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, SkipLog> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// `SkipReport` reports what was skipped so far while reading the diagnostic data,
+/// which is complete once the iterator that implements it has been consumed.
+pub trait SkipReport: sealed::HasSkipLogs {
+    /// Returns the files found so far in the source that do not hold
+    /// diagnostic data and were therefore skipped.
+    fn skipped_files(&self) -> Vec<SkippedFile> {
+        self.skip_logs().collect(|skip_log| &skip_log.files)
+    }
+
+    /// Returns the regions of the files read so far that could not be decoded
+    /// and were therefore skipped. The regions are skipped only in recovery mode.
+    fn skipped_regions(&self) -> Vec<SkippedRegion> {
+        self.skip_logs().collect(|skip_log| &skip_log.regions)
+    }
+
+    /// Returns the errors of saving the index of the files, if they are indexed.
+    /// The index is saved once the files are read through.
+    fn index_errors(&self) -> Vec<IndexSaveError> {
+        self.skip_logs().collect(|skip_log| &skip_log.index_errors)
+    }
+}
+
+impl<T: sealed::HasSkipLogs> SkipReport for T {}
+
+/// Keeps [`SkipReport`] from being implemented outside of the crate.
+pub(crate) mod sealed {
+    use super::SharedSkipLog;
+    use super::SkipLog;
+
+    /// The skip logs of the sources read by an iterator.
+    pub struct SkipLogs<'a>(pub(crate) Vec<&'a SharedSkipLog>);
+
+    impl SkipLogs<'_> {
+        /// Collects the entries of the `field` of each skip log.
+        pub(crate) fn collect<T: Clone>(&self, field: impl Fn(&SkipLog) -> &Vec<T>) -> Vec<T> {
+            self.0
+                .iter()
+                .flat_map(|skip_log| field(&skip_log.lock()).clone())
+                .collect()
+        }
+    }
+
+    pub trait HasSkipLogs {
+        fn skip_logs(&self) -> SkipLogs<'_>;
+    }
+}
+
+/// A diagnostic data file found in a [`Source`].
+pub(crate) struct SourceFile {
+    pub(crate) info: FileInfo,
+    content: FileContent,
+}
+
+enum FileContent {
+    Disk,
+    Memory(Vec<u8>),
+    Reader(Box<dyn Read + Send>),
+    Archive(ArchiveEntries, usize),
+}
+
+impl SourceFile {
+    pub(crate) fn on_disk(info: FileInfo) -> SourceFile {
+        Self {
+            info,
+            content: FileContent::Disk,
+        }
+    }
+
+    pub(crate) fn in_memory(info: FileInfo, data: Vec<u8>) -> SourceFile {
+        Self {
+            info,
+            content: FileContent::Memory(data),
+        }
+    }
+
+    pub(crate) fn from_reader(info: FileInfo, reader: Box<dyn Read + Send>) -> SourceFile {
+        Self {
+            info,
+            content: FileContent::Reader(reader),
+        }
+    }
+
+    /// Returns whether the file is read from the disk.
+    pub(crate) fn is_on_disk(&self) -> bool {
+        matches!(self.content, FileContent::Disk)
+    }
+
+    /// Opens the file for reading.
+    pub(crate) fn open(self) -> Result<(Box<dyn Read + Send>, FileInfo), io::Error> {
+        let reader: Box<dyn Read + Send> = match self.content {
+            FileContent::Disk => Box::new(BufReader::new(File::open(self.info.path())?)),
+            FileContent::Memory(data) => Box::new(Cursor::new(data)),
+            FileContent::Reader(reader) => reader,
+            FileContent::Archive(entries, position) => Box::new(entries.open(position)?),
+        };
+
+        Ok((reader, self.info))
+    }
+}
+
+/// An iterator that streams the diagnostic data files out of a gzip-compressed
+/// tar archive without extracting it to disk.
+///
+/// The entries of the archive are listed on a separate thread, one directory
+/// at a time, keeping the paths of the files relative to the archive root.
+/// The files are identified by their first document and the ones that do not
+/// hold diagnostic data are recorded as skipped. Since the files of a directory
+/// may be stored in any order, they are listed until the directory ends and
+/// then yielded in the order they were written, with the interim files last,
+/// so that they can be deduplicated against the rotated files. The files
+/// of a directory are expected to be stored together, as tar does when
+/// archiving a directory tree. If a directory is given, only the files
+/// directly in it are read, while the others are ignored.
+///
+/// Only the first document of each file is read while listing the entries.
+/// The content of a file is streamed out of the archive once it is opened,
+/// see [`ArchiveEntries`].
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+#[derive(Debug)]
+pub(crate) struct ArchiveReader {
+    entries: Receiver<Result<ArchiveEntry, io::Error>>,
+    content: ArchiveEntries,
+    skip_log: SharedSkipLog,
+}
+
+#[derive(Debug)]
+enum ArchiveEntry {
+    File(FileInfo, usize),
+    Skipped(SkippedFile),
+}
+
+impl ArchiveReader {
+    /// Creates an `ArchiveReader` that lists the entries of the archive read from
+    /// the `reader`, and streams the content of its files out of the archive
+    /// opened anew with the `reopen` function.
+    pub(crate) fn new<R, F>(
+        reader: R,
+        reopen: F,
+        dir: Option<PathBuf>,
+        skip_log: SharedSkipLog,
+        max_document_size: usize,
+    ) -> ArchiveReader
+    where
+        R: Read + Send + 'static,
+        F: FnMut() -> Result<R, io::Error> + Send + 'static,
+    {
+        // Only the files of one directory are listed
+        // while the files of the previous one are read.
+        let (sender, entries) = mpsc::sync_channel(1);
+
+        thread::spawn(move || {
+            if let Err(error) =
+                Self::list_entries(reader, dir.as_deref(), max_document_size, &sender)
+            {
+                let _ = sender.send(Err(error));
+            }
+        });
+
+        Self {
+            entries,
+            content: ArchiveEntries::new(reopen),
+            skip_log,
+        }
+    }
+
+    /// Creates an `ArchiveReader` of the archive `file` at the `path`.
+    pub(crate) fn from_file(
+        path: PathBuf,
+        file: File,
+        dir: Option<PathBuf>,
+        skip_log: SharedSkipLog,
+        max_document_size: usize,
+    ) -> ArchiveReader {
+        let reopen = move || File::open(&path);
+        Self::new(file, reopen, dir, skip_log, max_document_size)
+    }
+
+    fn list_entries<R: Read>(
+        reader: R,
+        dir: Option<&Path>,
+        max_document_size: usize,
+        sender: &SyncSender<Result<ArchiveEntry, io::Error>>,
+    ) -> Result<(), io::Error> {
+        let mut archive = Archive::new(GzDecoder::new(BufReader::new(reader)));
+        let mut files = Vec::new();
+
+        for (position, entry) in archive.entries()?.enumerate() {
+            let mut entry = entry?;
+
+            if entry.header().entry_type() != EntryType::Regular {
+                continue;
+            }
+
+            let path = entry.path()?.into_owned();
+            if index::is_index_file(&path) || dir.is_some_and(|dir| path.parent() != Some(dir)) {
+                continue;
+            }
+
+            // The rest of the entry is skipped once the next one is read.
+            let mut header = Vec::new();
+            match FileInfo::identify(path, &mut entry, &mut header, max_document_size)? {
+                Identified::Diagnostic(info) => {
+                    let other_dir = files
+                        .first()
+                        .is_some_and(|(first, _): &(FileInfo, _)| first.dir() != info.dir());
+                    if other_dir && !Self::send_sorted(&mut files, sender) {
+                        return Ok(());
+                    }
+
+                    files.push((info, position));
+                }
+                Identified::Skipped(file) => {
+                    if sender.send(Ok(ArchiveEntry::Skipped(file))).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        Self::send_sorted(&mut files, sender);
+
+        Ok(())
+    }
+
+    /// Sends the `files` of a directory in the order they were written.
+    /// Returns `false` if the files are no longer received.
+    fn send_sorted(
+        files: &mut Vec<(FileInfo, usize)>,
+        sender: &SyncSender<Result<ArchiveEntry, io::Error>>,
+    ) -> bool {
+        files.sort_by_key(|(info, _)| info.order());
+        files
+            .drain(..)
+            .all(|(info, position)| sender.send(Ok(ArchiveEntry::File(info, position))).is_ok())
+    }
+}
+
+impl Iterator for ArchiveReader {
+    type Item = Result<SourceFile, MetricParseError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.entries.recv().ok()? {
+                Ok(ArchiveEntry::File(info, position)) => {
+                    let content = FileContent::Archive(self.content.clone(), position);
+                    return Some(Ok(SourceFile { info, content }));
+                }
+                Ok(ArchiveEntry::Skipped(file)) => self.skip_log.lock().files.push(file),
+                Err(error) => return Some(Err(MetricParseError::from(error))),
+            }
+        }
+    }
+}
+
+/// `ArchiveEntries` streams the content of the entries of an archive
+/// on a separate thread, which reads through the archive up to each entry
+/// that is opened and pipes its content in chunks to the reader of the entry.
+///
+/// The entries are streamed one at a time, hence an entry must be read through
+/// or dropped before the next one is opened. The archive is opened anew
+/// whenever an entry stored before the last one is opened.
+#[derive(Debug, Clone)]
+struct ArchiveEntries(Sender<EntryRequest>);
+
+#[derive(Debug)]
+struct EntryRequest {
+    position: usize,
+    pipe: SyncSender<Result<Vec<u8>, io::Error>>,
+}
+
+impl ArchiveEntries {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    fn new<R, F>(reopen: F) -> Self
+    where
+        R: Read + Send + 'static,
+        F: FnMut() -> Result<R, io::Error> + Send + 'static,
+    {
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || Self::stream_entries(reopen, &requests));
+
+        Self(sender)
+    }
+
+    /// Opens the entry at the `position` of the archive.
+    fn open(&self, position: usize) -> Result<EntryReader, io::Error> {
+        let (pipe, chunks) = mpsc::sync_channel(1);
+        self.0
+            .send(EntryRequest { position, pipe })
+            .map_err(|_| io::Error::other("the archive is no longer read"))?;
+
+        Ok(EntryReader {
+            chunks,
+            chunk: Cursor::default(),
+        })
+    }
+
+    fn stream_entries<R, F>(mut reopen: F, requests: &Receiver<EntryRequest>)
+    where
+        R: Read,
+        F: FnMut() -> Result<R, io::Error>,
+    {
+        let mut request = requests.recv().ok();
+        while let Some(next) = request {
+            request = Self::stream_pass(&mut reopen, next, requests);
+        }
+    }
+
+    /// Streams the requested entries in a single pass over the archive, as long
+    /// as each one is stored after the previous one. Returns the next request
+    /// that needs another pass, if any.
+    fn stream_pass<R, F>(
+        reopen: &mut F,
+        request: EntryRequest,
+        requests: &Receiver<EntryRequest>,
+    ) -> Option<EntryRequest>
+    where
+        R: Read,
+        F: FnMut() -> Result<R, io::Error>,
+    {
+        let EntryRequest {
+            position: mut wanted,
+            mut pipe,
+        } = request;
+
+        let mut archive = match reopen() {
+            Ok(reader) => Archive::new(GzDecoder::new(BufReader::new(reader))),
+            Err(error) => {
+                let _ = pipe.send(Err(error));
+                return requests.recv().ok();
+            }
+        };
+        let entries = match archive.entries() {
+            Ok(entries) => entries,
+            Err(error) => {
+                let _ = pipe.send(Err(error));
+                return requests.recv().ok();
+            }
+        };
+
+        for (position, entry) in entries.enumerate() {
+            if position < wanted && entry.is_ok() {
+                continue;
+            }
+
+            match entry {
+                Ok(entry) => Self::pump(entry, pipe),
+                Err(error) => {
+                    // The entries after a corrupted one cannot be read.
+                    let _ = pipe.send(Err(error));
+                    return requests.recv().ok();
+                }
+            }
+
+            let request = requests.recv().ok()?;
+            if request.position <= position {
+                return Some(request);
+            }
+            (wanted, pipe) = (request.position, request.pipe);
+        }
+
+        let error = io::Error::new(io::ErrorKind::UnexpectedEof, "the archive entry is missing");
+        let _ = pipe.send(Err(error));
+        requests.recv().ok()
+    }
+
+    /// Sends the content of the `entry` through the `pipe`, until it is read
+    /// through or the reader of the entry is dropped.
+    fn pump<R: Read>(mut entry: R, pipe: SyncSender<Result<Vec<u8>, io::Error>>) {
+        loop {
+            let mut chunk = vec![0; Self::CHUNK_SIZE];
+            let chunk = match entry.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    Ok(chunk)
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => Err(error),
+            };
+
+            let failed = chunk.is_err();
+            if pipe.send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+/// A reader of the content of an archive entry, piped by [`ArchiveEntries`].
+struct EntryReader {
+    chunks: Receiver<Result<Vec<u8>, io::Error>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.chunk.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.chunks.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk?),
+                // The pipe is closed once the entry is read through.
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use tar::Builder;
+    use tar::Header;
+
+    use crate::DiagnosticData;
+    use crate::metrics::Measurement;
+    use crate::testing;
+    use crate::testing::TempDir;
+    use crate::write::FtdcWriter;
+
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let mut builder = Builder::new(encoder);
+
+        for (path, data) in entries {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mtime(1_700_000_000);
+            header.set_cksum();
+
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn document(kind: i32, timestamp_millis: i64) -> Vec<u8> {
+        let document = bson::doc! {
+            "_id": bson::DateTime::from_millis(timestamp_millis),
+            "type": kind,
+        };
+
+        document.to_vec().unwrap()
+    }
+
+    fn archive_reader(data: Vec<u8>, skip_log: SharedSkipLog) -> ArchiveReader {
+        let reader = Cursor::new(data.clone());
+        let reopen = move || Ok(Cursor::new(data.clone()));
+        ArchiveReader::new(reader, reopen, None, skip_log, 1024)
+    }
+
+    #[test]
+    fn archive_reader_yields_metrics_files_with_interim_files_last() {
+        let interim = document(1, 3);
+        let first = document(0, 1);
+        let second = document(0, 2);
+
+        let data = archive(&[
+            ("node-1/diagnostic.data/metrics.interim", &interim),
+            ("node-1/diagnostic.data/renamed", &first),
+            ("node-1/mongod.log", b"log"),
+            (
+                "node-1/diagnostic.data/metrics.2024-11-05T12-00-00Z-00000",
+                &second,
+            ),
+        ]);
+
+        let skip_log = SharedSkipLog::default();
+        let files = archive_reader(data, skip_log.clone())
+            .map(|file| {
+                let (mut reader, info) = file.unwrap().open().unwrap();
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content).unwrap();
+
+                (info.path().to_path_buf(), content)
+            })
+            .collect::<Vec<_>>();
+
+        let dir = Path::new("node-1/diagnostic.data");
+        let expected = vec![
+            (dir.join("renamed"), first),
+            (dir.join("metrics.2024-11-05T12-00-00Z-00000"), second),
+            (dir.join("metrics.interim"), interim),
+        ];
+
+        assert_eq!(files, expected);
+        assert_eq!(
+            skip_log.lock().files,
+            vec![SkippedFile {
+                path: PathBuf::from("node-1/mongod.log"),
+                reason: SkipReason::NotBson,
+            }]
+        );
+    }
+
+    #[test]
+    fn archive_reader_streams_files_stored_in_any_order() {
+        // The files are larger than the chunks they are streamed in.
+        let file = |timestamp_millis| {
+            let mut data = document(0, timestamp_millis);
+            data.extend((0..200_000).map(|idx| idx as u8));
+            data
+        };
+        let (first, second, third) = (file(1), file(2), file(3));
+        let data = archive(&[
+            ("dir/third", &third),
+            ("dir/first", &first),
+            ("dir/second", &second),
+        ]);
+
+        let mut files = archive_reader(data, SharedSkipLog::default()).map(Result::unwrap);
+        let read = |file: SourceFile| {
+            let (mut reader, info) = file.open().unwrap();
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content).unwrap();
+            (info.path().to_path_buf(), content)
+        };
+
+        // A file read in part does not hold up the next ones,
+        // even those stored before it.
+        let (mut reader, _) = files.next().unwrap().open().unwrap();
+        let mut start = vec![0; first.len() / 2];
+        reader.read_exact(&mut start).unwrap();
+        assert_eq!(start, first[..start.len()]);
+        drop(reader);
+
+        assert_eq!(
+            read(files.next().unwrap()),
+            (PathBuf::from("dir/second"), second)
+        );
+        assert_eq!(
+            read(files.next().unwrap()),
+            (PathBuf::from("dir/third"), third)
+        );
+        assert!(files.next().is_none());
+    }
+
+    fn diagnostic_data() -> Vec<u8> {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(4);
+        let metadata = testing::metadata("node-1");
+        writer
+            .write_metadata(testing::seconds(0), &metadata)
+            .unwrap();
+        testing::write_samples(&mut writer, "node-1", 0..10);
+
+        writer.finish().unwrap()
+    }
+
+    type ReadChunk = (
+        DateTime<Utc>,
+        DateTime<Utc>,
+        Vec<(String, Vec<Measurement>)>,
+    );
+
+    fn read(source: Source) -> Vec<Result<ReadChunk, MetricParseError>> {
+        DiagnosticData::from_source(source)
+            .into_iter()
+            .map(|chunk| {
+                chunk.map(|chunk| {
+                    let (start, end) = (chunk.start, chunk.end);
+                    let metrics = chunk.into_metrics().into_iter();
+                    let metrics = metrics.map(|m| (m.path.to_string(), m.measurements));
+                    (start, end, metrics.collect())
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sources_of_the_same_data_yield_the_same_chunks() {
+        let data = diagnostic_data();
+        let expected = read(Source::bytes(data.clone()))
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(expected.len(), 3);
+
+        let dir = TempDir::new("source");
+        let path = dir.join("metrics.2024-11-05T10-00-00Z-00000");
+        fs::write(&path, &data).unwrap();
+
+        let sources = [
+            Source::file(&path).unwrap(),
+            Source::path(&dir).unwrap(),
+            Source::reader(Cursor::new(data)),
+        ];
+        for source in sources {
+            let chunks = read(source).into_iter().collect::<Result<Vec<_>, _>>();
+            assert_eq!(chunks.unwrap(), expected);
+        }
+
+        let path = dir.to_path_buf();
+        drop(dir);
+        assert!(Source::file(&path).is_err());
+    }
+
+    #[test]
+    fn truncated_reader_yields_error_after_complete_chunks() {
+        let mut data = diagnostic_data();
+        data.truncate(data.len() - 10);
+
+        let chunks = read(Source::reader(Cursor::new(data)));
+        let (last, complete) = chunks.split_last().unwrap();
+
+        assert_eq!(complete.len(), 2);
+        assert!(complete.iter().all(Result::is_ok));
+        assert!(last.is_err());
+    }
+}
@@ -0,0 +1,83 @@
+//! Builds the diagnostic data shared by the tests of the crate.
+
+use std::fs;
+use std::ops::Deref;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+
+use bson::Document;
+use bson::doc;
+use chrono::DateTime;
+use chrono::TimeDelta;
+use chrono::TimeZone;
+use chrono::Utc;
+
+use crate::write::FtdcWriter;
+
+/// A directory created under the temporary directory for a test,
+/// which is removed along with its content once dropped,
+/// even if the test panics.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates a new empty directory named after `name` and the process,
+    /// so that the tests run at the same time do not share it.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("mprobe-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Returns the timestamp `secs` seconds after the diagnostic data of the tests starts.
+pub(crate) fn seconds(secs: i64) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 11, 5, 10, 0, 0).unwrap() + TimeDelta::seconds(secs)
+}
+
+/// Returns a metadata document of the process running on `hostname`.
+pub(crate) fn metadata(hostname: &str) -> Document {
+    doc! { "hostInfo": { "system": { "hostname": hostname } } }
+}
+
+/// Returns a sample taken at `ts` of the `serverStatus` of the `host`,
+/// which has been up for `uptime` seconds.
+pub(crate) fn sample(ts: DateTime<Utc>, host: &str, version: &str, uptime: i64) -> Document {
+    let status = doc! {
+        "start": ts,
+        "host": host,
+        "process": "mongod",
+        "version": version,
+        "uptime": uptime,
+        "end": ts,
+    };
+
+    doc! { "start": ts, "serverStatus": status, "end": ts }
+}
+
+/// Writes a sample of the `host` at each of the `secs`,
+/// with the uptime of the host set to the second.
+pub(crate) fn write_samples(writer: &mut FtdcWriter<Vec<u8>>, host: &str, secs: Range<i64>) {
+    for sec in secs {
+        let ts = seconds(sec);
+        writer
+            .write_sample(ts, &sample(ts, host, "8.0.4", sec))
+            .unwrap();
+    }
+}
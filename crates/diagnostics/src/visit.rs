@@ -0,0 +1,371 @@
+//! Defines a push-based API for visiting diagnostic metrics.
+//!
+//! Instead of building a [metrics chunk] for each chunk of the diagnostic data,
+//! the [diagnostic data] can drive a [`MetricsVisitor`] with the metrics and
+//! their measurements as they are decoded. This suits the consumers that stream
+//! the metrics elsewhere, e.g. into files, since no intermediate metrics are built.
+//!
+//! The visitor is driven with the same metrics, filtered the same way,
+//! as the ones yielded when iterating over the diagnostic data.
+//!
+//! [metrics chunk]: crate::metrics::MetricsChunk
+//! [diagnostic data]: crate::DiagnosticData
+
+use std::io;
+use std::ops::RangeBounds;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::error::IndexSaveError;
+use crate::error::MetricParseError;
+use crate::filter::MetricSelector;
+use crate::filter::TimeWindow;
+use crate::metadata::Metadata;
+use crate::metrics;
+use crate::metrics::MetricPath;
+use crate::metrics::MetricValue;
+use crate::metrics::PathInterner;
+use crate::metrics::ValueType;
+use crate::metrics::columnar::ColumnarChunk;
+use crate::metrics::raw::MetricInitVal;
+use crate::metrics::raw::SampleVisitor;
+use crate::source::SkippedFile;
+use crate::source::SkippedRegion;
+
+/// `MetricsVisitor` receives the metrics of the diagnostic data as they are decoded.
+///
+/// For each chunk of the diagnostic data, the visitor is called with:
+///
+/// 1. [chunk_start](Self::chunk_start), along with the metadata of the chunk;
+/// 2. [metric](Self::metric) for each metric of the chunk, followed by
+///    [sample](Self::sample) for each of its measurements;
+/// 3. [chunk_end](Self::chunk_end).
+///
+/// The chunks and the metrics that are left without measurements,
+/// e.g. by trimming them to the time window, are not visited at all.
+///
+/// If a chunk cannot be decoded, the visitor is called with [error](Self::error)
+/// instead, which may happen after some of the metrics of the chunk have already
+/// been visited. The chunk does not end in that case and the metrics visited
+/// since its start should be discarded.
+///
+/// Returning an error from any of the functions stops the visit.
+pub trait MetricsVisitor {
+    /// The type of the errors returned by the visitor.
+    type Error;
+
+    /// Called when a chunk starts, with its `metadata` and whether
+    /// it was read from the interim file.
+    fn chunk_start(&mut self, metadata: &Metadata, interim: bool) -> Result<(), Self::Error> {
+        let _ = (metadata, interim);
+        Ok(())
+    }
+
+    /// Called when a metric starts, with its `path` and the type of its values.
+    fn metric(&mut self, path: &MetricPath, value_type: ValueType) -> Result<(), Self::Error>;
+
+    /// Called for each measurement of the metric that started last.
+    fn sample(&mut self, timestamp: DateTime<Utc>, value: MetricValue) -> Result<(), Self::Error>;
+
+    /// Called when all the metrics of the chunk have been visited.
+    fn chunk_end(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called when a chunk, or the document holding it, cannot be decoded.
+    fn error(&mut self, error: MetricParseError) -> Result<(), Self::Error>;
+
+    /// Called once all the chunks have been visited, for each file
+    /// of the source that does not hold diagnostic data.
+    fn skipped_file(&mut self, file: &SkippedFile) -> Result<(), Self::Error> {
+        let _ = file;
+        Ok(())
+    }
+
+    /// Called once all the chunks have been visited, for each region
+    /// of the files that could not be decoded in recovery mode.
+    fn skipped_region(&mut self, region: &SkippedRegion) -> Result<(), Self::Error> {
+        let _ = region;
+        Ok(())
+    }
+
+    /// Called once all the chunks have been visited, for each index
+    /// of the files that could not be saved, if they are indexed.
+    fn index_error(&mut self, error: &IndexSaveError) -> Result<(), Self::Error> {
+        let _ = error;
+        Ok(())
+    }
+}
+
+/// The reasons for which the visit of a chunk stops.
+#[derive(Debug)]
+pub(crate) enum VisitError<E> {
+    /// The chunk cannot be decoded.
+    Parse(MetricParseError),
+
+    /// The visitor returned an error.
+    Visitor(E),
+
+    /// The chunk is outside the time window, hence it is not visited.
+    Skip,
+}
+
+impl<E> From<MetricParseError> for VisitError<E> {
+    fn from(error: MetricParseError) -> Self {
+        VisitError::Parse(error)
+    }
+}
+
+impl<E> From<io::Error> for VisitError<E> {
+    fn from(error: io::Error) -> Self {
+        VisitError::Parse(MetricParseError::from(error))
+    }
+}
+
+/// The kind of the metric whose values are being decoded.
+enum Current {
+    None,
+    ChunkTimestamps,
+    SectionTimestamps,
+    Skipped,
+    Selected {
+        path: MetricPath,
+        value_type: ValueType,
+        started: bool,
+    },
+}
+
+/// `ChunkVisit` drives a [`MetricsVisitor`] with the samples of a single chunk,
+/// applying the same rules as the ones used to build a [`ColumnarChunk`].
+///
+/// The timestamps of the chunk are expected to be its first metric, as mongod
+/// writes them, so that the chunk can be checked against the time window before
+/// any of its metrics are visited.
+pub(crate) struct ChunkVisit<'a, V> {
+    visitor: &'a mut V,
+    metadata: &'a Metadata,
+    interim: bool,
+    selector: &'a MetricSelector,
+    paths: &'a PathInterner,
+    time_window: &'a TimeWindow,
+    trim: bool,
+    after: Option<DateTime<Utc>>,
+    chunk_timestamps: Vec<DateTime<Utc>>,
+    section_timestamps: Vec<DateTime<Utc>>,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    filter_window: bool,
+    filter_after: bool,
+    current: Current,
+    sample: usize,
+    started: bool,
+}
+
+impl<'a, V: MetricsVisitor> ChunkVisit<'a, V> {
+    /// Creates a new `ChunkVisit` of a chunk with the `metadata`. The measurements
+    /// are trimmed to the `time_window` if `trim` is set, and only the ones
+    /// after the `after` timestamp are visited, if it is set.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        visitor: &'a mut V,
+        metadata: &'a Metadata,
+        interim: bool,
+        selector: &'a MetricSelector,
+        paths: &'a PathInterner,
+        time_window: &'a TimeWindow,
+        trim: bool,
+        after: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            visitor,
+            metadata,
+            interim,
+            selector,
+            paths,
+            time_window,
+            trim,
+            after,
+            chunk_timestamps: Vec::new(),
+            section_timestamps: Vec::new(),
+            range: None,
+            filter_window: false,
+            filter_after: false,
+            current: Current::None,
+            sample: 0,
+            started: false,
+        }
+    }
+
+    /// Returns the timestamp when the recording of the chunk ended, if known.
+    pub(crate) fn end(&self) -> Option<DateTime<Utc>> {
+        self.range.map(|(_, end)| end)
+    }
+
+    /// Ends the visit of the chunk, once all its samples have been decoded.
+    pub(crate) fn finish(&mut self) -> Result<(), VisitError<V::Error>> {
+        self.end_metric()?;
+
+        if self.range.is_none() {
+            return Err(VisitError::Parse(timestamp_not_found(
+                ColumnarChunk::START_TIMESTAMP_METRIC_NAME,
+            )));
+        }
+
+        if self.started {
+            self.visitor.chunk_end().map_err(VisitError::Visitor)?;
+        }
+
+        Ok(())
+    }
+
+    fn end_metric(&mut self) -> Result<(), VisitError<V::Error>> {
+        if !matches!(self.current, Current::ChunkTimestamps) {
+            return Ok(());
+        }
+
+        let (Some(start), Some(end)) =
+            (self.chunk_timestamps.first(), self.chunk_timestamps.last())
+        else {
+            return Ok(());
+        };
+
+        self.range = Some((*start, *end));
+        if !self.time_window.overlaps(start, end) {
+            return Err(VisitError::Skip);
+        }
+
+        // As when trimming a chunk, the measurements are filtered
+        // only if the chunk is not entirely within the range.
+        let window = self.time_window;
+        self.filter_window = self.trim && !(window.contains(start) && window.contains(end));
+        self.filter_after = self.after.is_some_and(|after| *start <= after);
+
+        Ok(())
+    }
+
+    fn includes(&self, timestamp: &DateTime<Utc>) -> bool {
+        (!self.filter_window || self.time_window.contains(timestamp))
+            && (!self.filter_after || self.after.is_none_or(|after| *timestamp > after))
+    }
+}
+
+impl<V: MetricsVisitor> SampleVisitor for ChunkVisit<'_, V> {
+    type Error = VisitError<V::Error>;
+
+    fn metric(&mut self, metric: MetricInitVal) -> Result<(), Self::Error> {
+        self.end_metric()?;
+        self.sample = 0;
+
+        let name = metric.groups.last().map(String::as_str);
+        self.current = if name == Some(ColumnarChunk::START_TIMESTAMP_METRIC_NAME) {
+            if metric.groups.len() == 1 {
+                self.chunk_timestamps.clear();
+                Current::ChunkTimestamps
+            } else {
+                self.section_timestamps.clear();
+                Current::SectionTimestamps
+            }
+        } else if name == Some(ColumnarChunk::END_TIMESTAMP_METRIC_NAME)
+            || !self.selector.selects(&metric.groups)
+        {
+            Current::Skipped
+        } else if self.range.is_none() {
+            return Err(VisitError::Parse(timestamp_not_found(
+                ColumnarChunk::START_TIMESTAMP_METRIC_NAME,
+            )));
+        } else if self.section_timestamps.is_empty() {
+            let name = metric.groups.join(ColumnarChunk::METRIC_NAME_DELIMITER);
+            return Err(VisitError::Parse(timestamp_not_found(&name)));
+        } else {
+            Current::Selected {
+                path: self.paths.intern(&metric.groups),
+                value_type: metric.vtype,
+                started: false,
+            }
+        };
+
+        Ok(())
+    }
+
+    fn value(&mut self, value: u64) -> Result<(), Self::Error> {
+        let sample = self.sample;
+        self.sample += 1;
+
+        match &mut self.current {
+            Current::ChunkTimestamps => {
+                self.chunk_timestamps
+                    .push(metrics::timestamp_from_millis(value));
+            }
+            Current::SectionTimestamps => {
+                self.section_timestamps
+                    .push(metrics::timestamp_from_millis(value));
+            }
+            Current::None | Current::Skipped => {}
+            Current::Selected { .. } => {
+                let Some(timestamp) = self.section_timestamps.get(sample).copied() else {
+                    return Ok(());
+                };
+
+                if !self.includes(&timestamp) {
+                    return Ok(());
+                }
+
+                if !self.started {
+                    self.started = true;
+                    self.visitor
+                        .chunk_start(self.metadata, self.interim)
+                        .map_err(VisitError::Visitor)?;
+                }
+
+                let Current::Selected {
+                    path,
+                    value_type,
+                    started,
+                } = &mut self.current
+                else {
+                    return Ok(());
+                };
+
+                if !*started {
+                    *started = true;
+                    self.visitor
+                        .metric(path, *value_type)
+                        .map_err(VisitError::Visitor)?;
+                }
+
+                self.visitor
+                    .sample(timestamp, value_type.convert(value))
+                    .map_err(VisitError::Visitor)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Drives the `visitor` with the metrics of an already decoded `chunk`,
+/// the same way a [`ChunkVisit`] does while decoding it.
+pub(crate) fn visit_chunk<V: MetricsVisitor>(
+    chunk: &ColumnarChunk,
+    visitor: &mut V,
+) -> Result<(), V::Error> {
+    visitor.chunk_start(&chunk.metadata, chunk.interim)?;
+
+    for metric in chunk.metrics() {
+        let mut measurements = metric.measurements().peekable();
+        if measurements.peek().is_none() {
+            continue;
+        }
+
+        visitor.metric(metric.path(), metric.value_type())?;
+        for measurement in measurements {
+            visitor.sample(measurement.timestamp, measurement.value)?;
+        }
+    }
+
+    visitor.chunk_end()
+}
+
+fn timestamp_not_found(name: &str) -> MetricParseError {
+    MetricParseError::MetricTimestampNotFound { name: name.into() }
+}
@@ -0,0 +1,592 @@
+//! Defines an API for writing diagnostic data files.
+//!
+//! The [FtdcWriter] encodes metadata documents and samples, or the [metric chunks]
+//! read from other diagnostic data, into the format generated by mongod. This is
+//! useful for building test fixtures, sharing trimmed copies of the diagnostic
+//! data, or generating synthetic load scenarios.
+//!
+//! [metric chunks]: crate::metrics::MetricsChunk
+
+use std::io::Write;
+
+use bson::Binary;
+use bson::Bson;
+use bson::Document;
+use bson::Timestamp;
+use bson::doc;
+use bson::spec::BinarySubtype;
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::bytes;
+use crate::compression;
+use crate::error::MetricWriteError;
+use crate::metrics::Metric;
+use crate::metrics::MetricValue;
+use crate::metrics::MetricsChunk;
+use crate::metrics::raw::MetricInitVal;
+use crate::metrics::raw::MetricParser;
+
+/// `FtdcWriter` writes diagnostic data in the format generated by mongod.
+///
+/// The samples are buffered and written as a metrics chunk once the chunk is full,
+/// the schema of a sample differs from the reference document of the chunk, or
+/// the writer is flushed. Hence [FtdcWriter::finish] must be called
+/// to write the buffered samples.
+///
+/// # Example
+///
+/// ```
+/// use bson::doc;
+/// use chrono::Utc;
+/// use mprobe_diagnostics::write::FtdcWriter;
+///
+/// let mut writer = FtdcWriter::new(Vec::new());
+///
+/// let now = Utc::now();
+/// writer.write_metadata(now, &doc! { "hostInfo": { "system": { "hostname": "node-1" } } })?;
+/// writer.write_sample(now, &doc! { "start": now, "connections": 10, "end": now })?;
+///
+/// let data = writer.finish()?;
+/// # Ok::<(), mprobe_diagnostics::error::MetricWriteError>(())
+/// ```
+pub struct FtdcWriter<W: Write> {
+    writer: W,
+    max_samples: usize,
+    chunk: Option<PendingChunk>,
+}
+
+impl<W: Write> FtdcWriter<W> {
+    /// The maximum amount of samples, including the reference document,
+    /// that mongod writes into a metrics chunk.
+    pub const DEFAULT_MAX_SAMPLES: usize = 300;
+
+    const ID_KEY: &str = "_id";
+    const DATA_TYPE_KEY: &str = "type";
+    const METADATA_KEY: &str = "doc";
+    const METRICS_CHUNK_KEY: &str = "data";
+
+    const START_KEY: &str = "start";
+    const END_KEY: &str = "end";
+    const TIME_KEY: &str = "time";
+    const INCREMENT_KEY: &str = "increment";
+
+    const SERVER_STATUS_KEY: &str = "serverStatus";
+    const HOST_KEY: &str = "host";
+    const PROCESS_KEY: &str = "process";
+    const VERSION_KEY: &str = "version";
+
+    /// Creates a new `FtdcWriter` that writes the diagnostic data into `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            max_samples: Self::DEFAULT_MAX_SAMPLES,
+            chunk: None,
+        }
+    }
+
+    /// Sets the maximum amount of samples, including the reference document,
+    /// written into a metrics chunk.
+    pub fn with_max_samples(mut self, max_samples: usize) -> Self {
+        self.max_samples = max_samples.max(1);
+        self
+    }
+
+    /// Writes a metadata document, e.g. the `buildInfo`, `getCmdLineOpts` and
+    /// `hostInfo` command results, collected at the `timestamp`.
+    ///
+    /// The buffered samples are written first.
+    pub fn write_metadata(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        metadata: &Document,
+    ) -> Result<(), MetricWriteError> {
+        self.flush()?;
+
+        let document = doc! {
+            Self::ID_KEY: timestamp,
+            Self::DATA_TYPE_KEY: 0,
+            Self::METADATA_KEY: metadata,
+        };
+
+        document.to_writer(&mut self.writer)?;
+        Ok(())
+    }
+
+    /// Buffers a sample collected at the `timestamp`.
+    ///
+    /// The metrics of the sample are extracted the same way mongod does.
+    /// If they differ from the ones of the reference document, or the chunk
+    /// is full, the buffered samples are written first and the sample becomes
+    /// the reference document of a new chunk.
+    pub fn write_sample(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        sample: &Document,
+    ) -> Result<(), MetricWriteError> {
+        let metrics = MetricParser::extract(sample);
+
+        if let Some(chunk) = self.chunk.as_mut()
+            && chunk.samples_count() < self.max_samples
+            && chunk.matches(&metrics)
+        {
+            chunk.push(metrics);
+            return Ok(());
+        }
+
+        self.flush()?;
+        self.chunk = Some(PendingChunk::new(timestamp, sample.clone(), metrics));
+
+        Ok(())
+    }
+
+    /// Writes the metrics `chunk` as samples, one for each of its measurements,
+    /// into their own metrics chunk.
+    ///
+    /// The samples are rebuilt from the metrics, therefore the values that are
+    /// not metrics, e.g. strings, are lost, except for the host, process and
+    /// version of the chunk metadata. The end timestamps of the sections are
+    /// set to their start timestamps.
+    ///
+    /// The time and the increment metrics of a BSON timestamp are written back
+    /// as a timestamp. An increment without its time, e.g. when the time
+    /// was filtered out, is widened to a 64-bit integer instead, since BSON has
+    /// no unsigned integer type, hence it is read back as an `Int64` metric.
+    pub fn write_chunk(&mut self, chunk: &MetricsChunk) -> Result<(), MetricWriteError> {
+        let samples_count = chunk.metrics().first().map_or(0, |m| m.measurements.len());
+
+        if let Some(metric) = chunk
+            .metrics()
+            .iter()
+            .find(|m| m.measurements.len() != samples_count)
+        {
+            return Err(MetricWriteError::MeasurementCountMismatch {
+                name: metric.name.clone(),
+            });
+        }
+
+        self.flush()?;
+
+        for idx in 0..samples_count {
+            let (timestamp, sample) = Self::build_sample(chunk, idx);
+            self.write_sample(timestamp, &sample)?;
+        }
+
+        self.flush()
+    }
+
+    /// Writes the buffered samples as a metrics chunk.
+    pub fn flush(&mut self) -> Result<(), MetricWriteError> {
+        let Some(chunk) = self.chunk.take() else {
+            return Ok(());
+        };
+
+        let document = doc! {
+            Self::ID_KEY: chunk.timestamp,
+            Self::DATA_TYPE_KEY: 1,
+            Self::METRICS_CHUNK_KEY: Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: chunk.encode()?,
+            },
+        };
+
+        document.to_writer(&mut self.writer)?;
+        Ok(())
+    }
+
+    /// Writes the buffered samples and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, MetricWriteError> {
+        self.flush()?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    /// Builds the sample of the `chunk` at the `idx` measurement. The metrics are
+    /// grouped into sections by their first group, each with its own start and
+    /// end timestamps, as mongod does for every command it collects.
+    fn build_sample(chunk: &MetricsChunk, idx: usize) -> (DateTime<Utc>, Document) {
+        let timestamps = chunk
+            .metrics()
+            .iter()
+            .map(|m| m.measurements[idx].timestamp);
+        let start = timestamps.clone().min().unwrap_or(chunk.start);
+        let end = timestamps.max().unwrap_or(chunk.end);
+
+        let mut sample = doc! { Self::START_KEY: start };
+        let mut sections: Vec<&str> = Vec::new();
+        let mut metrics = chunk.metrics().iter().peekable();
+
+        while let Some(metric) = metrics.next() {
+            let measurement = metric.measurements[idx];
+
+            if let [section, _, ..] = metric.groups.as_slice()
+                && !sections.contains(&section.as_str())
+            {
+                sections.push(section);
+                sample.insert(
+                    section.to_owned(),
+                    doc! { Self::START_KEY: measurement.timestamp },
+                );
+            }
+
+            let (groups, value) = match measurement.value {
+                MetricValue::DateTime(time) if Self::is_timestamp(metric, metrics.peek()) => {
+                    let increment = metrics.next().map(|m| m.measurements[idx].value);
+                    let increment = match increment {
+                        Some(MetricValue::UInt32(increment)) => increment,
+                        _ => 0,
+                    };
+                    let timestamp = Timestamp {
+                        time: time.timestamp() as u32,
+                        increment,
+                    };
+
+                    let groups = &metric.groups[..metric.groups.len() - 1];
+                    (groups, Bson::Timestamp(timestamp))
+                }
+                value => (metric.groups.as_slice(), Self::to_bson(value)),
+            };
+
+            insert_path(&mut sample, groups, value);
+        }
+
+        for section in sections {
+            if let Ok(document) = sample.get_document_mut(section)
+                && let Ok(start) = document.get_datetime(Self::START_KEY)
+            {
+                let start = *start;
+                document.insert(Self::END_KEY, start);
+            }
+        }
+
+        let metadata = &chunk.metadata;
+        for (key, value) in [
+            (Self::HOST_KEY, &metadata.host),
+            (Self::PROCESS_KEY, &metadata.process),
+            (Self::VERSION_KEY, &metadata.version),
+        ] {
+            let groups = [String::from(Self::SERVER_STATUS_KEY), String::from(key)];
+            insert_path(&mut sample, &groups, Bson::String(value.clone()));
+        }
+
+        sample.insert(Self::END_KEY, end);
+
+        (start, sample)
+    }
+
+    /// A BSON timestamp is read as two metrics, its time and its increment.
+    fn is_timestamp(metric: &Metric, next: Option<&&Metric>) -> bool {
+        let (Some((time, parent)), Some(next)) = (metric.groups.split_last(), next) else {
+            return false;
+        };
+
+        time == Self::TIME_KEY
+            && next
+                .groups
+                .split_last()
+                .is_some_and(|(increment, p)| increment == Self::INCREMENT_KEY && p == parent)
+    }
+
+    fn to_bson(value: MetricValue) -> Bson {
+        match value {
+            MetricValue::UInt32(v) => Bson::Int64(i64::from(v)),
+            MetricValue::Int32(v) => Bson::Int32(v),
+            MetricValue::Int64(v) => Bson::Int64(v),
+            MetricValue::Float64(v) => Bson::Double(v),
+            MetricValue::Boolean(v) => Bson::Boolean(v),
+            MetricValue::DateTime(v) => Bson::DateTime(v.into()),
+        }
+    }
+}
+
+/// Inserts the `value` into the `document` at the path given by the `groups`,
+/// creating the nested documents that are missing.
+fn insert_path(document: &mut Document, groups: &[String], value: Bson) {
+    let Some((key, parents)) = groups.split_last() else {
+        return;
+    };
+
+    let mut document = document;
+    for parent in parents {
+        if !matches!(document.get(parent), Some(Bson::Document(_))) {
+            document.insert(parent.to_owned(), Document::new());
+        }
+
+        document = document
+            .get_document_mut(parent)
+            .expect("the parent to be a document");
+    }
+
+    document.insert(key.to_owned(), value);
+}
+
+/// The samples buffered for a metrics chunk.
+struct PendingChunk {
+    timestamp: DateTime<Utc>,
+    reference: Document,
+    metrics: Vec<MetricInitVal>,
+    last_values: Vec<u64>,
+    deltas: Vec<Vec<u64>>,
+}
+
+impl PendingChunk {
+    fn new(timestamp: DateTime<Utc>, reference: Document, metrics: Vec<MetricInitVal>) -> Self {
+        let last_values = metrics.iter().map(|m| m.value).collect();
+
+        Self {
+            timestamp,
+            reference,
+            metrics,
+            last_values,
+            deltas: Vec::new(),
+        }
+    }
+
+    fn samples_count(&self) -> usize {
+        self.deltas.len() + 1
+    }
+
+    /// Checks whether the `metrics` have the same names and types
+    /// as the ones of the reference document.
+    fn matches(&self, metrics: &[MetricInitVal]) -> bool {
+        self.metrics.len() == metrics.len()
+            && self
+                .metrics
+                .iter()
+                .zip(metrics)
+                .all(|(r, m)| r.vtype == m.vtype && r.groups == m.groups)
+    }
+
+    fn push(&mut self, metrics: Vec<MetricInitVal>) {
+        let deltas = metrics
+            .iter()
+            .zip(self.last_values.iter_mut())
+            .map(|(metric, last)| {
+                let delta = metric.value.wrapping_sub(*last);
+                *last = metric.value;
+                delta
+            })
+            .collect();
+
+        self.deltas.push(deltas);
+    }
+
+    /// Encodes the chunk: the reference document, the metrics and samples count,
+    /// followed by the deltas of every metric, in which the runs of zeros are
+    /// encoded as a zero followed by the count of the remaining zeros.
+    fn encode(&self) -> Result<Vec<u8>, MetricWriteError> {
+        let mut data = self.reference.to_vec()?;
+        bytes::write_le_u32(&mut data, self.metrics.len() as u32)?;
+        bytes::write_le_u32(&mut data, self.deltas.len() as u32)?;
+
+        let mut zeroes_count: u64 = 0;
+        for metric in 0..self.metrics.len() {
+            for deltas in &self.deltas {
+                let delta = deltas[metric];
+                if delta == 0 {
+                    zeroes_count += 1;
+                    continue;
+                }
+
+                if zeroes_count > 0 {
+                    bytes::write_var_u64(&mut data, 0)?;
+                    bytes::write_var_u64(&mut data, zeroes_count - 1)?;
+                    zeroes_count = 0;
+                }
+
+                bytes::write_var_u64(&mut data, delta)?;
+            }
+        }
+
+        if zeroes_count > 0 {
+            bytes::write_var_u64(&mut data, 0)?;
+            bytes::write_var_u64(&mut data, zeroes_count - 1)?;
+        }
+
+        let mut chunk = Vec::new();
+        compression::compress(&mut chunk, &data)?;
+
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    use crate::DiagnosticData;
+    use crate::testing;
+    use crate::testing::seconds;
+
+    fn sample(idx: i64, connections: i32) -> (DateTime<Utc>, Document) {
+        let ts = seconds(idx);
+
+        let sample = doc! {
+            "start": ts,
+            "serverStatus": {
+                "start": ts,
+                "host": "node-1",
+                "process": "mongod",
+                "version": "8.0.4",
+                "uptime": idx as f64 + 0.5,
+                "connections": { "current": connections, "available": 100 },
+                "balance": -2.5 * idx as f64,
+                "opTime": Timestamp { time: 1_730_800_800 + idx as u32, increment: 1 },
+                "end": ts,
+            },
+            "systemMetrics": {
+                "start": ts + Duration::milliseconds(5),
+                "cpu": { "user_ms": 1000i64 + idx, "idle_ms": 7i64 },
+                "end": ts + Duration::milliseconds(5),
+            },
+            "end": ts + Duration::milliseconds(5),
+        };
+
+        (ts, sample)
+    }
+
+    fn read(data: Vec<u8>) -> Vec<MetricsChunk> {
+        DiagnosticData::from_bytes(data)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    fn values(chunk: &MetricsChunk, name: &str) -> Vec<MetricValue> {
+        chunk
+            .metrics()
+            .iter()
+            .find(|m| &*m.name == name)
+            .unwrap_or_else(|| panic!("the {name} metric is missing"))
+            .measurements
+            .iter()
+            .map(|m| m.value)
+            .collect()
+    }
+
+    fn write_samples(writer: &mut FtdcWriter<Vec<u8>>, connections: &[i32]) {
+        for (idx, connections) in connections.iter().enumerate() {
+            let (ts, sample) = sample(idx as i64, *connections);
+            writer.write_sample(ts, &sample).unwrap();
+        }
+    }
+
+    #[test]
+    fn write_sample_round_trips_through_reader() {
+        let mut writer = FtdcWriter::new(Vec::new());
+        writer
+            .write_metadata(seconds(0), &testing::metadata("node-1"))
+            .unwrap();
+        write_samples(&mut writer, &[10, 12, 12, 9]);
+
+        let chunks = read(writer.finish().unwrap());
+
+        assert_eq!(chunks.len(), 1);
+        let chunk = &chunks[0];
+        assert_eq!(chunk.metadata.host, "node-1");
+        assert_eq!(chunk.start, seconds(0));
+        assert_eq!(chunk.end, seconds(3));
+        assert_eq!(
+            values(chunk, "serverStatus connections current"),
+            [10, 12, 12, 9].map(MetricValue::Int32)
+        );
+        assert_eq!(
+            values(chunk, "serverStatus connections available"),
+            [100; 4].map(MetricValue::Int32)
+        );
+        assert_eq!(
+            values(chunk, "serverStatus balance"),
+            [0.0, -2.0, -5.0, -7.0].map(MetricValue::Float64)
+        );
+        assert_eq!(
+            values(chunk, "serverStatus opTime increment"),
+            [1; 4].map(MetricValue::UInt32)
+        );
+        assert_eq!(
+            values(chunk, "systemMetrics cpu idle_ms"),
+            [7; 4].map(MetricValue::Int64)
+        );
+
+        let cpu = chunk
+            .metrics()
+            .iter()
+            .find(|m| &*m.name == "systemMetrics cpu user_ms")
+            .unwrap();
+        assert_eq!(cpu.start, seconds(0) + Duration::milliseconds(5));
+    }
+
+    #[test]
+    fn write_sample_starts_new_chunk_when_full_or_schema_changes() {
+        let mut writer = FtdcWriter::new(Vec::new()).with_max_samples(2);
+        write_samples(&mut writer, &[1, 2, 3]);
+
+        let (ts, mut changed) = sample(3, 4);
+        changed.insert("extra", 1);
+        writer.write_sample(ts, &changed).unwrap();
+
+        let chunks = read(writer.finish().unwrap());
+        let samples = chunks
+            .iter()
+            .map(|c| values(c, "serverStatus connections current").len())
+            .collect::<Vec<_>>();
+
+        assert_eq!(samples, vec![2, 1, 1]);
+        assert_eq!(values(&chunks[2], "extra"), vec![MetricValue::Int32(1)]);
+    }
+
+    #[test]
+    fn write_chunk_round_trips_metrics_chunk() {
+        let mut writer = FtdcWriter::new(Vec::new());
+        write_samples(&mut writer, &[5, 0, 0, 7]);
+        let expected = read(writer.finish().unwrap());
+
+        let mut writer = FtdcWriter::new(Vec::new());
+        writer.write_chunk(&expected[0]).unwrap();
+        let actual = read(writer.finish().unwrap());
+
+        assert_eq!(actual.len(), 1);
+        assert_eq!(actual[0].start, expected[0].start);
+        assert_eq!(actual[0].metadata.host, expected[0].metadata.host);
+
+        let metrics = |chunk: &MetricsChunk| {
+            chunk
+                .metrics()
+                .iter()
+                .map(|m| (m.name.clone(), m.measurements.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(metrics(&actual[0]), metrics(&expected[0]));
+
+        // The increment of a timestamp whose time is filtered out is widened.
+        let mut chunk = expected[0].clone();
+        chunk
+            .metrics_mut()
+            .retain(|m| &*m.name != "serverStatus opTime time");
+        let mut writer = FtdcWriter::new(Vec::new());
+        writer.write_chunk(&chunk).unwrap();
+        let actual = read(writer.finish().unwrap());
+
+        assert_eq!(
+            values(&actual[0], "serverStatus opTime increment"),
+            [1; 4].map(MetricValue::Int64)
+        );
+    }
+
+    #[test]
+    fn write_chunk_rejects_misaligned_measurements() {
+        let mut writer = FtdcWriter::new(Vec::new());
+        write_samples(&mut writer, &[1, 2]);
+        let mut chunk = read(writer.finish().unwrap()).remove(0);
+        chunk.metrics_mut()[1].measurements.pop();
+
+        let result = FtdcWriter::new(Vec::new()).write_chunk(&chunk);
+
+        assert!(matches!(
+            result,
+            Err(MetricWriteError::MeasurementCountMismatch { .. })
+        ));
+    }
+}
@@ -1,5 +1,4 @@
-use mprobe_diagnostics::metrics::Measurement;
-use mprobe_diagnostics::metrics::MetricValue;
+use mprobe_diagnostics::metrics::ValueType;
 
 use serde::Serialize;
 
@@ -16,18 +15,12 @@ pub enum AxisType {
 }
 
 impl AxisType {
-    pub fn yaxis(measurements: &[Measurement]) -> AxisType {
-        if let Some(first_measurement) = measurements.first() {
-            match first_measurement.value {
-                MetricValue::DateTime(_) => AxisType::Date,
-                MetricValue::UInt32(_)
-                | MetricValue::Int32(_)
-                | MetricValue::Int64(_)
-                | MetricValue::Float64(_)
-                | MetricValue::Boolean(_) => AxisType::Linear,
+    pub fn yaxis(value_type: ValueType) -> AxisType {
+        match value_type {
+            ValueType::UnixTime | ValueType::UnixTimeMillis => AxisType::Date,
+            ValueType::U32 | ValueType::I32 | ValueType::I64 | ValueType::F64 | ValueType::Bool => {
+                AxisType::Linear
             }
-        } else {
-            AxisType::Linear
         }
     }
 }
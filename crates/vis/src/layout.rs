@@ -2,7 +2,6 @@
 //! visualizing diagnostic metrics.
 
 mod data;
-mod iter;
 mod series;
 
 use std::fs;
@@ -10,10 +9,13 @@ use std::path::Path;
 use std::path::PathBuf;
 
 use mprobe_diagnostics::DiagnosticData;
+use mprobe_diagnostics::error::IndexSaveError;
+use mprobe_diagnostics::error::MetricParseError;
+use mprobe_diagnostics::source::SkippedFile;
+use mprobe_diagnostics::source::SkippedRegion;
 
 use crate::error::Result;
 use crate::layout::data::DataEngine;
-use crate::layout::iter::ErrorHandlingIter;
 use crate::template::TemplateEngine;
 
 /// Coordinates the creation of the data visualization.
@@ -68,12 +70,35 @@ impl VisLayout {
     }
 
     /// Generates a visualization report based on the provided diagnostic data.
-    pub fn generate_report(&self, diagnostic_data: DiagnosticData) -> Result<()> {
-        let mut data_engine = DataEngine::new(&self.data_path);
-        let metrics = ErrorHandlingIter::new(diagnostic_data.into_iter());
-        let charts = data_engine.render(metrics)?;
+    ///
+    /// The parts of the diagnostic data that cannot be read are left out
+    /// of the report and returned, so that the caller can report them.
+    pub fn generate_report(&self, diagnostic_data: DiagnosticData) -> Result<MissingData> {
+        let mut data_engine = DataEngine::new(&self.data_path)?;
+        diagnostic_data.visit(&mut data_engine)?;
+        let (charts, missing) = data_engine.finish()?;
 
         let template = TemplateEngine::new(&self.index_file_path, &self.views_path);
-        template.render(&charts)
+        template.render(&charts)?;
+
+        Ok(missing)
     }
 }
+
+/// The diagnostic data left out of a visualization report,
+/// since it could not be read.
+#[derive(Debug, Default)]
+pub struct MissingData {
+    /// The errors that occurred while reading the metrics chunks,
+    /// whose metrics are therefore missing in part or in full.
+    pub errors: Vec<MetricParseError>,
+
+    /// The files that do not hold diagnostic data.
+    pub skipped_files: Vec<SkippedFile>,
+
+    /// The corrupted regions of the files that were skipped in recovery mode.
+    pub skipped_regions: Vec<SkippedRegion>,
+
+    /// The indexes of the files that could not be saved, if they are indexed.
+    pub index_errors: Vec<IndexSaveError>,
+}
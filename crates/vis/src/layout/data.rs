@@ -1,5 +1,4 @@
 use std::collections::HashMap;
-use std::collections::hash_map::Entry;
 use std::fmt::Display;
 use std::fs;
 use std::fs::File;
@@ -9,82 +8,147 @@ use std::sync::Arc;
 
 use chrono::DateTime;
 use chrono::Utc;
-use mprobe_diagnostics::metrics::MetricsChunk;
+use mprobe_diagnostics::error::IndexSaveError;
+use mprobe_diagnostics::error::MetricParseError;
+use mprobe_diagnostics::metrics::MetricPath;
+use mprobe_diagnostics::metrics::MetricValue;
+use mprobe_diagnostics::metrics::ValueType;
+use mprobe_diagnostics::source::SkippedFile;
+use mprobe_diagnostics::source::SkippedRegion;
+use mprobe_diagnostics::visit::MetricsVisitor;
 
 use crate::chart::Chart;
 use crate::chart::Series;
 use crate::chart::axes::AxisType;
 use crate::id::Id;
+use crate::layout::MissingData;
 use crate::layout::series::SeriesWriter;
 
 const DATA_FILE_NAME: &str = "data";
+const METRIC_NAME_DELIMITER: &str = " ";
 
+/// Writes the measurements of the visited metrics into a data file
+/// for each metric, and creates the charts that plot them.
+///
+/// The diagnostic data that cannot be read is collected
+/// into [`MissingData`], rather than failing the visit.
 pub struct DataEngine<'a> {
     path: &'a Path,
+    writers: Vec<SeriesWriter<File, Timestamp, f64>>,
+    indexes: HashMap<MetricPath, usize>,
+    charts: Vec<Chart>,
+    current: Option<usize>,
+    missing: MissingData,
 }
 
 impl<'a> DataEngine<'a> {
-    pub fn new(path: &'a Path) -> DataEngine<'a> {
-        Self { path }
+    pub fn new(path: &'a Path) -> Result<DataEngine<'a>, std::io::Error> {
+        if !path.exists() {
+            fs::create_dir(path)?;
+        }
+
+        Ok(Self {
+            path,
+            writers: Vec::with_capacity(200),
+            indexes: HashMap::with_capacity(200),
+            charts: Vec::with_capacity(500),
+            current: None,
+            missing: MissingData::default(),
+        })
     }
 
-    pub fn render<I>(&mut self, metrics: I) -> Result<Vec<Chart>, std::io::Error>
-    where
-        I: Iterator<Item = MetricsChunk>,
-    {
-        if !self.path.exists() {
-            fs::create_dir(self.path)?;
+    /// Completes the data files and returns the charts of the visited metrics,
+    /// along with the diagnostic data that could not be read.
+    pub fn finish(self) -> Result<(Vec<Chart>, MissingData), std::io::Error> {
+        for writer in self.writers {
+            writer.end()?;
         }
 
-        let mut writers: HashMap<Arc<str>, SeriesWriter<File, Timestamp, f64>> =
-            HashMap::with_capacity(200);
-        let mut charts: Vec<Chart> = Vec::with_capacity(500);
-
-        for chunk in metrics {
-            for metric in chunk.metrics {
-                let writer = match writers.entry(Arc::clone(&metric.name)) {
-                    Entry::Occupied(entry) => entry.into_mut(),
-                    Entry::Vacant(vacant_entry) => {
-                        let id = Id::next();
-                        let file_name = format!("{DATA_FILE_NAME}{id}.js");
-                        let file_path: Arc<Path> = Arc::from(self.path.join(file_name));
-                        let series = Arc::new(Series::from(id));
-                        let writer = OpenOptions::new()
-                            .create(true)
-                            .write(true)
-                            .truncate(false)
-                            .open(&file_path)?;
-
-                        let chart = Chart::new(
-                            id,
-                            Arc::clone(&metric.name),
-                            metric.groups,
-                            AxisType::yaxis(&metric.measurements),
-                            Arc::clone(&series),
-                            file_path,
-                        );
-                        charts.push(chart);
-
-                        let mut writer = SeriesWriter::new(writer, Arc::clone(&series));
-                        writer.start()?;
-
-                        vacant_entry.insert(writer)
-                    }
-                };
-
-                for measurement in metric.measurements {
-                    let x = Timestamp(measurement.timestamp);
-                    let y = measurement.value.into();
-                    writer.write(x, y)?;
-                }
-            }
-        }
+        Ok((self.charts, self.missing))
+    }
 
-        for (_, writer) in writers {
-            writer.end()?;
+    fn create_writer(
+        &mut self,
+        path: &MetricPath,
+        value_type: ValueType,
+    ) -> Result<usize, std::io::Error> {
+        let id = Id::next();
+        let file_name = format!("{DATA_FILE_NAME}{id}.js");
+        let file_path: Arc<Path> = Arc::from(self.path.join(file_name));
+        let series = Arc::new(Series::from(id));
+        let writer = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&file_path)?;
+
+        let chart = Chart::new(
+            id,
+            Arc::from(path.segments().join(METRIC_NAME_DELIMITER)),
+            path.segments().to_vec(),
+            AxisType::yaxis(value_type),
+            Arc::clone(&series),
+            file_path,
+        );
+        self.charts.push(chart);
+
+        let mut writer = SeriesWriter::new(writer, Arc::clone(&series));
+        writer.start()?;
+
+        let index = self.writers.len();
+        self.writers.push(writer);
+        self.indexes.insert(path.clone(), index);
+
+        Ok(index)
+    }
+}
+
+impl MetricsVisitor for DataEngine<'_> {
+    type Error = std::io::Error;
+
+    fn metric(&mut self, path: &MetricPath, value_type: ValueType) -> Result<(), Self::Error> {
+        let index = match self.indexes.get(path) {
+            Some(index) => *index,
+            None => self.create_writer(path, value_type)?,
+        };
+        self.current = Some(index);
+
+        Ok(())
+    }
+
+    fn sample(&mut self, timestamp: DateTime<Utc>, value: MetricValue) -> Result<(), Self::Error> {
+        if let Some(index) = self.current {
+            self.writers[index].write(Timestamp(timestamp), value.into())?;
         }
 
-        Ok(charts)
+        Ok(())
+    }
+
+    fn chunk_end(&mut self) -> Result<(), Self::Error> {
+        self.current = None;
+        Ok(())
+    }
+
+    fn error(&mut self, error: MetricParseError) -> Result<(), Self::Error> {
+        // The measurements already written for the chunk are kept, as they are valid.
+        self.missing.errors.push(error);
+        self.current = None;
+        Ok(())
+    }
+
+    fn skipped_file(&mut self, file: &SkippedFile) -> Result<(), Self::Error> {
+        self.missing.skipped_files.push(file.clone());
+        Ok(())
+    }
+
+    fn skipped_region(&mut self, region: &SkippedRegion) -> Result<(), Self::Error> {
+        self.missing.skipped_regions.push(region.clone());
+        Ok(())
+    }
+
+    fn index_error(&mut self, error: &IndexSaveError) -> Result<(), Self::Error> {
+        self.missing.index_errors.push(error.clone());
+        Ok(())
     }
 }
 